@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use rolling_in_the_diff::decode::{decode_delta, decode_tree_delta, DecodeLimits};
+use rolling_in_the_diff::strong_hash::md5::Md5Sum;
+use rolling_in_the_diff::strong_hash::StrongHash;
+
+fuzz_target!(|data: &[u8]| {
+    let limits = DecodeLimits::default();
+    let _ = decode_delta::<<Md5Sum as StrongHash>::HashType>(data, &limits);
+    let _ = decode_tree_delta::<<Md5Sum as StrongHash>::HashType>(data, &limits);
+});
@@ -0,0 +1,29 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use rolling_in_the_diff::decode::{decode_signature, decode_tree_signature, DecodeLimits};
+use rolling_in_the_diff::delta_generation::try_generate_delta;
+use rolling_in_the_diff::mapped_signature::MappedSignature;
+use rolling_in_the_diff::rolling_checksum::rolling_adler32::RollingAdler32;
+use rolling_in_the_diff::signature_index::SignatureIndex;
+use rolling_in_the_diff::strong_hash::md5::Md5Sum;
+use rolling_in_the_diff::strong_hash::StrongHash;
+
+type Md5Hash = <Md5Sum as StrongHash>::HashType;
+
+const NEW_CONTENT: &[u8] = b"the quick brown fox jumps over the lazy dog";
+
+fuzz_target!(|data: &[u8]| {
+    let limits = DecodeLimits::default();
+
+    // whatever gets decoded has to be usable for a delta
+    if let Ok(signature) = decode_signature::<u32, Md5Hash>(data, &limits) {
+        let _ = try_generate_delta::<RollingAdler32, Md5Sum>(&signature, NEW_CONTENT);
+    }
+    if let Ok(signature) = MappedSignature::<u32, Md5Hash>::from_bytes(data) {
+        let _ = signature.strong_hashes_by_chunk();
+        let _ = try_generate_delta::<RollingAdler32, Md5Sum>(&signature, NEW_CONTENT);
+    }
+    let _ = decode_tree_signature::<u32, Md5Hash>(data, &limits);
+});
@@ -0,0 +1,28 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use rolling_in_the_diff::decode::{decode_delta, DecodeLimits};
+use rolling_in_the_diff::delta_generation::Delta;
+use rolling_in_the_diff::patch::patch;
+use rolling_in_the_diff::strong_hash::md5::Md5Sum;
+use rolling_in_the_diff::strong_hash::StrongHash;
+
+// the first two bytes tell where the old content ends and the encoded delta starts
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let split = u16::from_le_bytes([data[0], data[1]]) as usize;
+    let (old_content, delta) = data[2..].split_at(split.min(data.len() - 2));
+
+    let limits = DecodeLimits {
+        max_output_len: 1 << 24,
+        ..Default::default()
+    };
+    let delta: Delta<<Md5Sum as StrongHash>::HashType> = match decode_delta(delta, &limits) {
+        Ok(delta) => delta,
+        Err(_) => return,
+    };
+    let _ = patch::<Md5Sum, _>(old_content, delta, &mut std::io::sink());
+});
@@ -0,0 +1,195 @@
+#ifndef ROLLING_IN_THE_DIFF_H
+#define ROLLING_IN_THE_DIFF_H
+
+/* Generated with cbindgen from src/ffi.rs - run `make header` instead of editing by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum RitdStatus {
+  RITD_STATUS_OK = 0,
+  /**
+   * A null pointer where there must be something
+   */
+  RITD_STATUS_INVALID_ARGUMENT,
+  /**
+   * Reading or writing a file descriptor failed
+   */
+  RITD_STATUS_IO,
+  /**
+   * The signature or delta is malformed or over the decoding limits
+   */
+  RITD_STATUS_MALFORMED,
+  /**
+   * The signature refers to chunks it doesn't have
+   */
+  RITD_STATUS_INVALID_SIGNATURE,
+  /**
+   * The delta refers to a chunk past the end of the old content
+   */
+  RITD_STATUS_CHUNK_OUT_OF_BOUND,
+  /**
+   * The delta copies a range past the end of the old content
+   */
+  RITD_STATUS_RANGE_OUT_OF_BOUND,
+  /**
+   * The delta refers back past the output written so far
+   */
+  RITD_STATUS_BACK_REFERENCE_OUT_OF_BOUND,
+  /**
+   * The delta has a diff that doesn't fit its chunk
+   */
+  RITD_STATUS_DIFF_LENGTH_MISMATCH,
+  /**
+   * The old content isn't the one the delta was generated against
+   */
+  RITD_STATUS_CHUNK_HASH_MISMATCH,
+  RITD_STATUS_CANCELLED,
+  /**
+   * A bug in the library
+   */
+  RITD_STATUS_INTERNAL,
+} RitdStatus;
+
+/**
+ * The signature of some content
+ */
+typedef struct RitdSignature RitdSignature;
+
+/**
+ * Bytes owned by the library, to be released with [ritd_buffer_free]
+ */
+typedef struct RitdBuffer {
+  uint8_t *data;
+  size_t len;
+} RitdBuffer;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Generates the signature of `len` bytes at `content`
+ *
+ * # Safety
+ *
+ * `content` must point to `len` readable bytes (or may be null when `len` is 0) and `out` to a
+ * writable pointer that receives a signature to release with [ritd_signature_free].
+ */
+RitdStatus ritd_signature_new(const uint8_t *content, size_t len, RitdSignature **out);
+
+/**
+ * Generates the signature of what's left to read from `fd`, which is left open
+ *
+ * # Safety
+ *
+ * `fd` must be an open file descriptor and `out` must point to a writable pointer that receives a
+ * signature to release with [ritd_signature_free].
+ */
+RitdStatus ritd_signature_new_from_fd(int fd, RitdSignature **out);
+
+/**
+ * Decodes a signature written by [ritd_signature_encode] or the command line tool
+ *
+ * # Safety
+ *
+ * `bytes` must point to `len` readable bytes and `out` to a writable pointer that receives a
+ * signature to release with [ritd_signature_free].
+ */
+RitdStatus ritd_signature_decode(const uint8_t *bytes, size_t len, RitdSignature **out);
+
+/**
+ * Encodes `signature` into a buffer to release with [ritd_buffer_free]
+ *
+ * # Safety
+ *
+ * `signature` must come from this library and not be freed yet, `out` must point to a writable
+ * [RitdBuffer].
+ */
+RitdStatus ritd_signature_encode(const RitdSignature *signature, RitdBuffer *out);
+
+/**
+ * Releases a signature, null is ignored
+ *
+ * # Safety
+ *
+ * `signature` must come from this library and not be freed yet.
+ */
+void ritd_signature_free(RitdSignature *signature);
+
+/**
+ * Generates the delta between `signature` and the `len` bytes at `new_content` into a buffer to
+ * release with [ritd_buffer_free]
+ *
+ * # Safety
+ *
+ * `signature` must come from this library and not be freed yet, `new_content` must point to `len`
+ * readable bytes and `out` to a writable [RitdBuffer].
+ */
+RitdStatus ritd_delta_new(const RitdSignature *signature,
+                          const uint8_t *new_content,
+                          size_t len,
+                          RitdBuffer *out);
+
+/**
+ * Generates the delta between `signature` and what's left to read from `new_fd` into `delta_fd`,
+ * both are left open
+ *
+ * # Safety
+ *
+ * `signature` must come from this library and not be freed yet, the file descriptors must be
+ * open.
+ */
+RitdStatus ritd_delta_new_from_fd(const RitdSignature *signature, int new_fd, int delta_fd);
+
+/**
+ * Applies the `delta_len` bytes of delta at `delta` on top of the `old_len` bytes at
+ * `old_content` into a buffer to release with [ritd_buffer_free]
+ *
+ * # Safety
+ *
+ * `old_content` and `delta` must point to `old_len` and `delta_len` readable bytes, `out` to a
+ * writable [RitdBuffer].
+ */
+RitdStatus ritd_patch(const uint8_t *old_content,
+                      size_t old_len,
+                      const uint8_t *delta,
+                      size_t delta_len,
+                      RitdBuffer *out);
+
+/**
+ * Applies the delta left to read from `delta_fd` on top of what's left to read from `old_fd` into
+ * `out_fd`, all of them are left open
+ *
+ * Part of the output may have been written when it fails.
+ *
+ * # Safety
+ *
+ * The file descriptors must be open.
+ */
+RitdStatus ritd_patch_from_fd(int old_fd, int delta_fd, int out_fd);
+
+/**
+ * Releases the bytes of a buffer and empties it, an empty buffer is ignored
+ *
+ * # Safety
+ *
+ * `buffer` must be null or point to a buffer filled by this library.
+ */
+void ritd_buffer_free(RitdBuffer *buffer);
+
+/**
+ * The message of the last error on the calling thread, null after a success
+ *
+ * The message stays valid until the next call on the same thread.
+ */
+const char *ritd_last_error_message(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ROLLING_IN_THE_DIFF_H */
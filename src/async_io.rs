@@ -0,0 +1,571 @@
+//!
+//! Async versions of the signature, delta and patch APIs on top of tokio's `AsyncRead`/`AsyncWrite`
+//!
+//! The hashing runs on tokio's blocking thread pool, so none of it stalls the runtime. Outputs are
+//! streamed: the blocking side hands blocks over a bounded channel to the async side, which writes
+//! them as they come. The delta is streamed into patching the same way, only the signature and the
+//! delta generation read their whole input first - both need all of it before they can start.
+//!
+//! Dropping a returned future cancels a child of the given [CancellationToken], so the blocking
+//! work stops at its next cancellation check rather than running to completion in the background.
+//!
+
+use std::hash::Hash;
+use std::io::{Cursor, Read, Write};
+use std::panic::resume_unwind;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::join;
+use tokio::sync::mpsc;
+use tokio::task::{spawn_blocking, JoinHandle};
+
+use crate::cancel::{CancellationToken, Cancelled};
+use crate::decode::{DecodeError, DecodeLimits};
+use crate::delta_generation::{generate_delta_into, DeltaError, DeltaOptions, GenerateIntoError};
+use crate::format::FormatError;
+use crate::patch::{patch_from_reader, PatchError, PatchOptions};
+use crate::progress::ProgressObserver;
+use crate::rolling_checksum::RollingChecksum;
+use crate::signature_generation::{
+    cancellable_signature, ChunkSizePolicy, SignatureError, READ_STEP,
+};
+use crate::strong_hash::StrongHash;
+use crate::token_stream::{DeltaReader, DeltaWriter};
+use crate::Signature;
+
+/// Size of the blocks handed over from the blocking side to the async writer
+const BLOCK_SIZE: usize = 1 << 16;
+/// Blocks waiting for the async writer before the blocking side has to wait too
+const BLOCKS_IN_FLIGHT: usize = 4;
+
+///
+/// Generates the signature of everything `content` yields
+///
+/// Like [crate::signature_generation::try_generate_signature], the whole content is read into
+/// memory first - the chunk size depends on its length.
+///
+pub async fn generate_signature_async<R, S, Rd>(
+    content: &mut Rd,
+    progress: Arc<dyn ProgressObserver + Send>,
+    cancel: &CancellationToken,
+) -> Result<Signature<R::ChecksumType, S::HashType>, SignatureError>
+where
+    R: RollingChecksum + 'static,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash + Send + Copy + 'static,
+    S: StrongHash + 'static,
+    <S as StrongHash>::HashType: Send + 'static,
+    Rd: AsyncRead + Unpin,
+{
+    let guard = CancelOnDrop(cancel.child_token());
+    let content = read_all(content, cancel).await?;
+    let cancel = guard.0.clone();
+    let signature = offload(move || {
+        cancellable_signature::<R, S>(
+            &content,
+            &ChunkSizePolicy::default(),
+            progress.as_ref(),
+            &cancel,
+        )
+    })
+    .await?;
+    Ok(signature)
+}
+
+///
+/// Generates the delta between `old_signature` and everything `new_content` yields and writes it
+/// into `out`, encoded in bincode with a header like [crate::format::encode] does
+///
+/// The whole new content is read into memory first, as it's scanned for the old chunks. The tokens
+/// are encoded and written as they are generated, the delta is never held in memory.
+///
+pub async fn generate_delta_async<R, S, Rd, Wr>(
+    old_signature: Arc<Signature<R::ChecksumType, S::HashType>>,
+    new_content: &mut Rd,
+    out: &mut Wr,
+    options: DeltaOptions,
+    progress: Arc<dyn ProgressObserver + Send>,
+    cancel: &CancellationToken,
+) -> Result<(), AsyncError>
+where
+    R: RollingChecksum + 'static,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash + Send + Sync + 'static,
+    S: StrongHash + 'static,
+    <S as StrongHash>::HashType: Eq + Send + Sync + Serialize + 'static,
+    Rd: AsyncRead + Unpin,
+    Wr: AsyncWrite + Unpin,
+{
+    let guard = CancelOnDrop(cancel.child_token());
+    let new_content = read_all(new_content, cancel).await?;
+    let blocking_cancel = guard.0.clone();
+    write_from_blocking(out, cancel, move |writer| {
+        generate_delta_into::<R, S, _>(
+            old_signature.as_ref(),
+            &new_content,
+            &options,
+            progress.as_ref(),
+            &blocking_cancel,
+            &mut DeltaWriter::new(writer),
+        )
+        .map_err(|e| match e {
+            GenerateIntoError::Delta(e) => AsyncError::Delta(e),
+            GenerateIntoError::Visitor(e) => AsyncError::Encoding(e.into()),
+        })
+    })
+    .await
+}
+
+///
+/// Applies the delta `delta` yields on top of `old_content` into `out`
+///
+/// The delta is read a block at a time and patched as it comes, like [patch_from_reader] does -
+/// it has to be in bincode, which is what [generate_delta_async] writes. The old content has to be
+/// in memory, it's only handed over to the blocking side. As with [patch_with_progress], part of
+/// the output may have been written on errors.
+///
+pub async fn patch_async<S, O, Rd, Wr>(
+    old_content: O,
+    delta: &mut Rd,
+    out: &mut Wr,
+    progress: Arc<dyn ProgressObserver + Send>,
+    cancel: &CancellationToken,
+) -> Result<(), AsyncError>
+where
+    S: StrongHash + 'static,
+    <S as StrongHash>::HashType: Serialize + DeserializeOwned + 'static,
+    O: AsRef<[u8]> + Send + 'static,
+    Rd: AsyncRead + Unpin,
+    Wr: AsyncWrite + Unpin,
+{
+    let guard = CancelOnDrop(cancel.child_token());
+    let blocking_cancel = guard.0.clone();
+    pipe_through_blocking(delta, out, cancel, move |input, writer| {
+        let delta = DeltaReader::new(input, &DecodeLimits::default())?;
+        patch_from_reader::<S, _, _>(
+            old_content.as_ref(),
+            delta,
+            writer,
+            &PatchOptions::default(),
+            progress.as_ref(),
+            &blocking_cancel,
+        )?;
+        Ok(())
+    })
+    .await
+}
+
+async fn read_all<Rd: AsyncRead + Unpin>(
+    input: &mut Rd,
+    cancel: &CancellationToken,
+) -> Result<Vec<u8>, ReadError> {
+    let mut buffer = Vec::new();
+    loop {
+        cancel.check()?;
+        if (&mut *input)
+            .take(READ_STEP)
+            .read_to_end(&mut buffer)
+            .await?
+            == 0
+        {
+            return Ok(buffer);
+        }
+    }
+}
+
+/// Cancels the blocking work started by a future once the future is gone, completed or dropped
+struct CancelOnDrop(CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Runs CPU-heavy work on the blocking thread pool
+async fn offload<T, F>(work: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    join(spawn_blocking(work)).await
+}
+
+async fn join<T>(task: JoinHandle<T>) -> T {
+    match task.await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => resume_unwind(e.into_panic()),
+        Err(e) => panic!("the blocking work didn't complete: {}", e),
+    }
+}
+
+/// Runs `produce` on the blocking thread pool and writes what it writes into `out`
+async fn write_from_blocking<Wr, F>(
+    out: &mut Wr,
+    cancel: &CancellationToken,
+    produce: F,
+) -> Result<(), AsyncError>
+where
+    Wr: AsyncWrite + Unpin,
+    F: FnOnce(&mut ChannelWriter) -> Result<(), AsyncError> + Send + 'static,
+{
+    pipe_through_blocking(&mut tokio::io::empty(), out, cancel, |_, writer| {
+        produce(writer)
+    })
+    .await
+}
+
+///
+/// Runs `produce` on the blocking thread pool, feeding it what `input` yields and writing what it
+/// writes into `out`
+///
+/// An error reading `input` or writing `out` takes precedence over the error of `produce` it
+/// causes.
+///
+async fn pipe_through_blocking<Rd, Wr, F>(
+    input: &mut Rd,
+    out: &mut Wr,
+    cancel: &CancellationToken,
+    produce: F,
+) -> Result<(), AsyncError>
+where
+    Rd: AsyncRead + Unpin,
+    Wr: AsyncWrite + Unpin,
+    F: FnOnce(ChannelReader, &mut ChannelWriter) -> Result<(), AsyncError> + Send + 'static,
+{
+    let (input_sender, input_receiver) = mpsc::channel(BLOCKS_IN_FLIGHT);
+    let (output_sender, mut output_receiver) = mpsc::channel(BLOCKS_IN_FLIGHT);
+    let produced = spawn_blocking(move || {
+        let reader = ChannelReader {
+            block: Cursor::new(Vec::new()),
+            receiver: input_receiver,
+        };
+        let mut writer = ChannelWriter {
+            buffer: Vec::with_capacity(BLOCK_SIZE),
+            sender: output_sender,
+        };
+        produce(reader, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    });
+
+    // each side lets go of its channel as soon as it's done, so that the blocking side never waits
+    // on one that gave up
+    let read = async move {
+        let read = async {
+            loop {
+                cancel.check()?;
+                let mut block = Vec::with_capacity(BLOCK_SIZE);
+                if (&mut *input)
+                    .take(BLOCK_SIZE as u64)
+                    .read_to_end(&mut block)
+                    .await?
+                    == 0
+                {
+                    return Ok(());
+                }
+                if input_sender.send(block).await.is_err() {
+                    // the blocking side is done with the input
+                    return Ok(());
+                }
+            }
+        }
+        .await;
+        drop(input_sender);
+        read
+    };
+    let written = async move {
+        let written = async {
+            while let Some(block) = output_receiver.recv().await {
+                out.write_all(&block).await?;
+            }
+            out.flush().await
+        }
+        .await;
+        drop(output_receiver);
+        written
+    };
+    let (read, written): (Result<(), ReadError>, std::io::Result<()>) = join!(read, written);
+    read?;
+    written?;
+    join(produced).await
+}
+
+/// Reads the blocks sent by the async reader, the input ends once it stops sending
+struct ChannelReader {
+    block: Cursor<Vec<u8>>,
+    receiver: mpsc::Receiver<Vec<u8>>,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.block.position() == self.block.get_ref().len() as u64 {
+            match self.receiver.blocking_recv() {
+                Some(block) => self.block = Cursor::new(block),
+                None => return Ok(0),
+            }
+        }
+        Read::read(&mut self.block, buf)
+    }
+}
+
+/// Buffers what is written into blocks sent to the async writer
+struct ChannelWriter {
+    buffer: Vec<u8>,
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+impl ChannelWriter {
+    fn send_buffer(&mut self) -> std::io::Result<()> {
+        let block = std::mem::replace(&mut self.buffer, Vec::with_capacity(BLOCK_SIZE));
+        self.sender.blocking_send(block).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "the output was closed")
+        })
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= BLOCK_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.send_buffer()?;
+        }
+        Ok(())
+    }
+}
+
+/// Reading the input of an operation failed or was cancelled
+#[derive(Error, Debug)]
+enum ReadError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+}
+
+impl From<ReadError> for SignatureError {
+    fn from(e: ReadError) -> Self {
+        match e {
+            ReadError::Io(e) => SignatureError::Io(e),
+            ReadError::Cancelled(e) => SignatureError::Cancelled(e),
+        }
+    }
+}
+
+impl From<ReadError> for AsyncError {
+    fn from(e: ReadError) -> Self {
+        match e {
+            ReadError::Io(e) => AsyncError::Io(e),
+            ReadError::Cancelled(e) => AsyncError::Cancelled(e),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AsyncError {
+    #[error("failed to generate the delta")]
+    Delta(#[from] DeltaError),
+    #[error("invalid delta")]
+    Decode(#[from] DecodeError),
+    #[error("failed to apply the delta")]
+    Patch(#[from] PatchError),
+    #[error("failed to encode the delta")]
+    Encoding(#[from] FormatError),
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+    #[error("input or output error")]
+    Io(#[from] std::io::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc as std_mpsc, Mutex};
+    use std::task::{Context, Waker};
+    use std::thread;
+    use std::time::Duration;
+
+    use tokio::runtime::Runtime;
+
+    use crate::progress::Progress;
+
+    use crate::decode::decode_delta;
+    use crate::delta_generation::{try_generate_delta, Delta};
+    use crate::format::{encode, Format, Kind};
+    use crate::patch::patch;
+    use crate::progress::NoProgress;
+    use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+    use crate::signature_generation::generate_signature;
+    use crate::strong_hash::md5::Md5Sum;
+
+    use super::*;
+
+    fn runtime() -> Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+    }
+
+    fn contents() -> (Vec<u8>, Vec<u8>) {
+        let old_content: Vec<u8> = (0..1 << 18).map(|x| (x * 31 % 251) as u8).collect();
+        let mut new_content = old_content.clone();
+        new_content.splice(1000..1010, [42; 30]);
+        (old_content, new_content)
+    }
+
+    #[test]
+    fn test_async_round_trip() {
+        let (old_content, new_content) = contents();
+        let cancel = CancellationToken::new();
+
+        runtime().block_on(async {
+            let signature = generate_signature_async::<RollingAdler32, Md5Sum, _>(
+                &mut old_content.as_slice(),
+                Arc::new(NoProgress),
+                &cancel,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                bincode2::serialize(&signature).unwrap(),
+                bincode2::serialize(&generate_signature::<RollingAdler32, Md5Sum>(&old_content))
+                    .unwrap()
+            );
+
+            let mut delta = Vec::new();
+            generate_delta_async::<RollingAdler32, Md5Sum, _, _>(
+                Arc::new(signature),
+                &mut new_content.as_slice(),
+                &mut delta,
+                DeltaOptions::default(),
+                Arc::new(NoProgress),
+                &cancel,
+            )
+            .await
+            .unwrap();
+
+            // the streamed delta is a regular one
+            let decoded: Delta<[u8; 16]> = decode_delta(&delta, &DecodeLimits::default()).unwrap();
+            let mut patched_content = Vec::new();
+            patch::<Md5Sum, _>(&old_content, decoded, &mut patched_content).unwrap();
+            assert_eq!(patched_content, new_content);
+
+            let mut patched_content = Vec::new();
+            patch_async::<Md5Sum, _, _, _>(
+                old_content.clone(),
+                &mut delta.as_slice(),
+                &mut patched_content,
+                Arc::new(NoProgress),
+                &cancel,
+            )
+            .await
+            .unwrap();
+            assert_eq!(patched_content, new_content);
+        });
+    }
+
+    #[test]
+    fn test_async_delta_into_closed_output() {
+        let (old_content, new_content) = contents();
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&old_content);
+        let (mut out, reader) = tokio::io::duplex(16);
+        drop(reader);
+
+        let result = runtime().block_on(generate_delta_async::<RollingAdler32, Md5Sum, _, _>(
+            Arc::new(signature),
+            &mut new_content.as_slice(),
+            &mut out,
+            DeltaOptions::default(),
+            Arc::new(NoProgress),
+            &CancellationToken::new(),
+        ));
+        assert!(matches!(result, Err(AsyncError::Io(_))));
+    }
+
+    #[test]
+    fn test_async_patch_streams_the_delta() {
+        let (old_content, new_content) = contents();
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&old_content);
+        let delta = try_generate_delta::<RollingAdler32, Md5Sum>(&signature, &new_content).unwrap();
+        let mut encoded = Vec::new();
+        encode(&delta, Kind::Delta, Format::Bincode, &mut encoded).unwrap();
+        // without its end, the delta is only found to be truncated once the rest is patched
+        encoded.pop();
+
+        let mut patched_content = Vec::new();
+        let result = runtime().block_on(patch_async::<Md5Sum, _, _, _>(
+            old_content,
+            &mut encoded.as_slice(),
+            &mut patched_content,
+            Arc::new(NoProgress),
+            &CancellationToken::new(),
+        ));
+        assert!(matches!(result, Err(AsyncError::Patch(_))));
+        assert!(patched_content.len() >= new_content.len() - BLOCK_SIZE);
+        assert!(new_content.starts_with(&patched_content));
+    }
+
+    /// Holds the blocking work on its first progress report until released
+    struct Gate {
+        started: Mutex<Option<std_mpsc::Sender<()>>>,
+        released: AtomicBool,
+        finished: AtomicBool,
+    }
+
+    impl ProgressObserver for Gate {
+        fn on_progress(&self, _: &Progress) {
+            if let Some(started) = self.started.lock().unwrap().take() {
+                started.send(()).unwrap();
+            }
+            while !self.released.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        fn on_finish(&self, _: &Progress) {
+            self.finished.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_dropped_future_cancels_the_blocking_work() {
+        let (old_content, _) = contents();
+        let (started, wait_started) = std_mpsc::channel();
+        let gate = Arc::new(Gate {
+            started: Mutex::new(Some(started)),
+            released: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+        });
+        let cancel = CancellationToken::new();
+
+        let runtime = runtime();
+        let _context = runtime.enter();
+        let mut input = old_content.as_slice();
+        let mut future = Box::pin(generate_signature_async::<RollingAdler32, Md5Sum, _>(
+            &mut input,
+            gate.clone(),
+            &cancel,
+        ));
+        assert!(future
+            .as_mut()
+            .poll(&mut Context::from_waker(Waker::noop()))
+            .is_pending());
+        wait_started.recv().unwrap();
+        drop(future);
+
+        gate.released.store(true, Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(200));
+        assert!(!gate.finished.load(Ordering::Relaxed));
+        assert!(!cancel.is_cancelled());
+    }
+}
@@ -0,0 +1,139 @@
+//!
+//! Cooperative cancellation of the long-running operations
+//!
+//! The operations check a [CancellationToken] between chunks and scan steps and give up with
+//! [Cancelled] once it's cancelled or its deadline has passed.
+//!
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+///
+/// Tells an operation to stop, either on request or once a deadline has passed
+///
+/// Clones share the cancellation, so one can be handed to the operation and another one kept to
+/// cancel it from elsewhere (e.g. when the client disconnects).
+///
+/// ```
+/// use std::time::Duration;
+/// use rolling_in_the_diff::cancel::CancellationToken;
+///
+/// let token = CancellationToken::new();
+/// let operation_token = token.clone();
+/// token.cancel();
+/// assert!(operation_token.is_cancelled());
+///
+/// assert!(CancellationToken::with_timeout(Duration::ZERO).is_cancelled());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+    /// Cancelling the parent cancels this token as well, but not the other way around
+    parent: Option<Arc<CancellationToken>>,
+}
+
+impl CancellationToken {
+    /// A token that is only cancelled by [CancellationToken::cancel]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A token that is also cancelled once `deadline` has passed
+    pub fn with_deadline(deadline: Instant) -> Self {
+        CancellationToken {
+            cancelled: Arc::default(),
+            deadline: Some(deadline),
+            parent: None,
+        }
+    }
+
+    /// A token that is also cancelled once `timeout` has elapsed from now
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self::with_deadline(Instant::now() + timeout)
+    }
+
+    /// A token that is cancelled along with this one, and that can also be cancelled on its own
+    pub fn child_token(&self) -> Self {
+        CancellationToken {
+            cancelled: Arc::default(),
+            deadline: None,
+            parent: Some(Arc::new(self.clone())),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            || self
+                .parent
+                .as_ref()
+                .is_some_and(|parent| parent.is_cancelled())
+    }
+
+    pub(crate) fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("the operation was cancelled")]
+pub struct Cancelled;
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_deadline() {
+        let token = CancellationToken::with_timeout(Duration::from_millis(50));
+        assert!(!token.is_cancelled());
+        assert_eq!(token.check(), Ok(()));
+
+        thread::sleep(Duration::from_millis(60));
+        assert!(token.is_cancelled());
+        assert_eq!(token.check(), Err(Cancelled));
+        assert!(CancellationToken::with_deadline(Instant::now()).is_cancelled());
+    }
+
+    #[test]
+    fn test_clones_share_the_cancellation() {
+        let token = CancellationToken::with_timeout(Duration::from_secs(3600));
+        let clone = token.clone();
+        let other = CancellationToken::new();
+
+        thread::spawn(move || clone.cancel()).join().unwrap();
+        assert!(token.is_cancelled());
+        assert!(!other.is_cancelled());
+    }
+
+    #[test]
+    fn test_child_token() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        child.cancel();
+        assert!(!parent.is_cancelled());
+
+        let child = parent.child_token();
+        parent.cancel();
+        assert!(child.is_cancelled());
+        assert!(CancellationToken::with_timeout(Duration::ZERO)
+            .child_token()
+            .is_cancelled());
+    }
+}
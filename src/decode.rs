@@ -0,0 +1,456 @@
+//!
+//! Decoding of signatures and deltas that come from untrusted sources
+//!
+//! The input is in one of the formats of [crate::format], headerless signatures and deltas are read
+//! in the layouts of the first release.
+//! Besides being well-formed, the decoded values are checked for fields that would make
+//! the delta generation or the patching misbehave: a chunk size that can't describe the chunks
+//! referred to, or a delta that would produce more output than allowed.
+//!
+
+use std::fmt::Debug;
+use std::hash::Hash;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::delta_generation::{Delta, DeltaToken, BACK_REFERENCE_WINDOW};
+use crate::format::{self, legacy, FormatError, Header, Kind};
+use crate::tree::{TreeDelta, TreeDeltaEntry, TreeSignature};
+use crate::Signature;
+
+#[derive(Debug, Clone)]
+pub struct DecodeLimits {
+    ///
+    /// The biggest encoded signature or delta accepted
+    ///
+    /// CBOR input goes through an intermediate value first (see [crate::format]), which holds a
+    /// copy of every byte string and a node per item, so decoding it can take a few times this.
+    ///
+    pub max_input_len: u64,
+    /// The most bytes a delta may produce when applied - a few bytes of `Fill` can ask for terabytes
+    pub max_output_len: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_input_len: 1 << 32,
+            max_output_len: 1 << 40,
+        }
+    }
+}
+
+pub fn decode_signature<'de, W, S>(
+    bytes: &'de [u8],
+    limits: &DecodeLimits,
+) -> Result<Signature<W, S>, DecodeError>
+where
+    W: Eq + Hash + PartialEq + Copy + Serialize + Deserialize<'de>,
+    S: PartialEq + Copy + Serialize + Deserialize<'de>,
+{
+    bounded_deserialize(
+        bytes,
+        Kind::Signature,
+        limits,
+        Some(legacy::deserialize_signature),
+    )
+}
+
+pub fn decode_delta<'de, S>(
+    bytes: &'de [u8],
+    limits: &DecodeLimits,
+) -> Result<Delta<'de, S>, DecodeError>
+where
+    S: Eq + PartialEq + Debug + Serialize + Deserialize<'de>,
+{
+    let delta: Delta<S> =
+        bounded_deserialize(bytes, Kind::Delta, limits, Some(legacy::deserialize_delta))?;
+    validate_delta(&delta, limits)?;
+    Ok(delta)
+}
+
+pub fn decode_tree_signature<'de, W, S>(
+    bytes: &'de [u8],
+    limits: &DecodeLimits,
+) -> Result<TreeSignature<W, S>, DecodeError>
+where
+    W: Eq + Hash + PartialEq + Serialize + Deserialize<'de>,
+    S: PartialEq + Copy + Serialize + Deserialize<'de>,
+{
+    bounded_deserialize(bytes, Kind::TreeSignature, limits, None)
+}
+
+pub fn decode_tree_delta<'de, S>(
+    bytes: &'de [u8],
+    limits: &DecodeLimits,
+) -> Result<TreeDelta<'de, S>, DecodeError>
+where
+    S: Eq + PartialEq + Debug + Serialize + Deserialize<'de>,
+{
+    let delta: TreeDelta<S> = bounded_deserialize(bytes, Kind::TreeDelta, limits, None)?;
+    for entry in &delta.entries {
+        if let TreeDeltaEntry::Modified { path, delta } = entry {
+            validate_delta(delta, limits).map_err(|e| e.in_file(path))?;
+        }
+    }
+    Ok(delta)
+}
+
+/// Decodes input without a header, in the layout of the first release
+type Headerless<'de, T> = fn(&'de [u8]) -> Result<T, DecodeError>;
+
+/// Decodes a value of `kind`, with `headerless` when there's no header
+fn bounded_deserialize<'de, T: Serialize + Deserialize<'de>>(
+    bytes: &'de [u8],
+    kind: Kind,
+    limits: &DecodeLimits,
+    headerless: Option<Headerless<'de, T>>,
+) -> Result<T, DecodeError> {
+    let len = bytes.len() as u64;
+    if len > limits.max_input_len {
+        return Err(DecodeError::InputTooLarge {
+            len,
+            limit: limits.max_input_len,
+        });
+    }
+    let (format, body) = match Header::parse(bytes).map_err(DecodeError::InvalidHeader)? {
+        Some((header, _)) if header.kind != kind => {
+            return Err(DecodeError::WrongKind {
+                expected: kind,
+                found: header.kind,
+            })
+        }
+        Some((header, _)) if !header.format.is_supported() => {
+            return Err(DecodeError::InvalidHeader(FormatError::Unsupported(
+                header.format,
+            )))
+        }
+        Some((header, body)) => (header.format, body),
+        None => {
+            let headerless =
+                headerless.ok_or(DecodeError::InvalidHeader(FormatError::MissingHeader(kind)))?;
+            return headerless(bytes);
+        }
+    };
+    // nothing can claim more bytes than there are, whatever the length prefixes say
+    format::deserialize(body, format).map_err(DecodeError::Malformed)
+}
+
+fn validate_delta<S>(delta: &Delta<S>, limits: &DecodeLimits) -> Result<(), DecodeError>
+where
+    S: Eq + PartialEq + Debug,
+{
+    let mut validator = TokenValidator::new(delta.chunk_size, limits);
+    delta
+        .tokens
+        .iter()
+        .try_for_each(|token| validator.validate(token))
+}
+
+/// Checks the tokens of a delta one by one, keeping count of the output they produce
+pub(crate) struct TokenValidator {
+    chunk_size: u64,
+    output_len: u64,
+    max_output_len: u64,
+}
+
+impl TokenValidator {
+    pub(crate) fn new(chunk_size: u64, limits: &DecodeLimits) -> Self {
+        TokenValidator {
+            chunk_size,
+            output_len: 0,
+            max_output_len: limits.max_output_len,
+        }
+    }
+
+    pub(crate) fn validate<S>(&mut self, token: &DeltaToken<S>) -> Result<(), DecodeError>
+    where
+        S: PartialEq + Debug,
+    {
+        let chunk_size = self.chunk_size;
+        let token_len = match token {
+            DeltaToken::Reused(..) | DeltaToken::Removed(_) if chunk_size == 0 => {
+                return Err(DecodeError::MissingChunkSize)
+            }
+            DeltaToken::Reused(..) => chunk_size,
+            DeltaToken::Removed(_) => 0,
+            DeltaToken::Added(bytes) => bytes.len() as u64,
+            DeltaToken::Copied(_, len, _) => *len,
+            DeltaToken::BackReference(offset, len) => {
+                if *offset >= self.output_len || self.output_len - offset > BACK_REFERENCE_WINDOW {
+                    return Err(DecodeError::BackReferenceOutOfWindow {
+                        offset: *offset,
+                        written: self.output_len,
+                    });
+                }
+                *len
+            }
+            DeltaToken::Fill(_, len) => *len,
+            DeltaToken::Diff(chunk_num, _, runs) => {
+                for (offset, bytes) in runs {
+                    let end = offset.saturating_add(bytes.len() as u64);
+                    if end > chunk_size {
+                        return Err(DecodeError::DiffTooLong {
+                            chunk_num: *chunk_num,
+                            diff_len: end,
+                            chunk_size,
+                        });
+                    }
+                }
+                chunk_size
+            }
+        };
+        self.output_len = self
+            .output_len
+            .checked_add(token_len)
+            .filter(|len| *len <= self.max_output_len)
+            .ok_or(DecodeError::OutputTooLarge {
+                limit: self.max_output_len,
+            })?;
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("input of {len} bytes is over the limit of {limit}")]
+    InputTooLarge { len: u64, limit: u64 },
+    #[error("invalid header")]
+    InvalidHeader(#[source] FormatError),
+    #[error("expected a {expected}, found a {found}")]
+    WrongKind { expected: Kind, found: Kind },
+    #[error("malformed input")]
+    Malformed(#[source] FormatError),
+    #[error("signature claims {expected} chunks but has {found}")]
+    ChunkCountMismatch { expected: u64, found: u64 },
+    #[error("delta refers to chunks but has no chunk size")]
+    MissingChunkSize,
+    #[error("diff of chunk {chunk_num} goes past the chunk size: {diff_len} {chunk_size}")]
+    DiffTooLong {
+        chunk_num: u64,
+        diff_len: u64,
+        chunk_size: u64,
+    },
+    #[error(
+        "back-reference to {offset} is not within the last {} bytes written: {written}",
+        BACK_REFERENCE_WINDOW
+    )]
+    BackReferenceOutOfWindow { offset: u64, written: u64 },
+    #[error("delta produces more than {limit} bytes")]
+    OutputTooLarge { limit: u64 },
+    #[error("invalid entry for {path}")]
+    InvalidFile {
+        path: String,
+        #[source]
+        source: Box<DecodeError>,
+    },
+}
+
+impl DecodeError {
+    fn in_file(self, path: &str) -> Self {
+        DecodeError::InvalidFile {
+            path: path.to_string(),
+            source: Box::new(self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+
+    use test_case::test_case;
+
+    use crate::delta_generation::DeltaToken::{Added, BackReference, Diff, Fill, Reused};
+    use crate::format::{Format, HEADER_LEN};
+    use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+    use crate::signature_generation::generate_signature;
+    use crate::strong_hash::md5::Md5Sum;
+    use crate::strong_hash::StrongHash;
+    use crate::DEFAULT_VERSION;
+
+    use super::*;
+
+    type Md5Hash = <Md5Sum as StrongHash>::HashType;
+
+    fn encoded<T: Serialize>(value: &T, kind: Kind) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        format::encode(value, kind, Format::Bincode, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn encoded_delta(tokens: Vec<DeltaToken<Md5Hash>>, chunk_size: u64) -> Vec<u8> {
+        let delta = Delta {
+            tokens,
+            chunk_size,
+            version: DEFAULT_VERSION.to_string(),
+        };
+        encoded(&delta, Kind::Delta)
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        let content: Vec<u8> = (0..1 << 12).map(|x| (x * 13 % 251) as u8).collect();
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&content);
+        let bytes = encoded(&signature, Kind::Signature);
+        let limits = DecodeLimits::default();
+
+        let decoded: Signature<u32, Md5Hash> = decode_signature(&bytes, &limits).unwrap();
+        assert_eq!(decoded.records, signature.records);
+
+        let bytes = encoded_delta(vec![Added(Cow::Borrowed(&[1, 2, 3])), Fill(0, 10)], 0);
+        let decoded: Delta<Md5Hash> = decode_delta(&bytes, &limits).unwrap();
+        assert_eq!(
+            decoded.tokens,
+            vec![Added(Cow::Borrowed(&[1, 2, 3])), Fill(0, 10)]
+        );
+    }
+
+    #[test]
+    fn test_decode_formats() {
+        let content: Vec<u8> = (0..1 << 14).map(|x| (x * 13 % 251) as u8).collect();
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&content);
+        // longer than what some decoders keep in their scratch buffers
+        let tokens = vec![Added(Cow::Owned(content.clone())), Fill(7, 10)];
+        let delta = Delta {
+            tokens: tokens.clone(),
+            chunk_size: 0,
+            version: DEFAULT_VERSION.to_string(),
+        };
+        let limits = DecodeLimits::default();
+
+        let formats = [
+            Format::Bincode,
+            Format::Cbor,
+            Format::MessagePack,
+            Format::Json,
+        ];
+        for format in formats.into_iter().filter(|format| format.is_supported()) {
+            let mut bytes = Vec::new();
+            format::encode(&signature, Kind::Signature, format, &mut bytes).unwrap();
+            let decoded: Signature<u32, Md5Hash> = decode_signature(&bytes, &limits).unwrap();
+            assert_eq!(decoded.records, signature.records, "{}", format);
+            assert!(matches!(
+                decode_delta::<Md5Hash>(&bytes, &limits),
+                Err(DecodeError::WrongKind {
+                    expected: Kind::Delta,
+                    found: Kind::Signature
+                })
+            ));
+
+            let mut bytes = Vec::new();
+            format::encode(&delta, Kind::Delta, format, &mut bytes).unwrap();
+            let decoded: Delta<Md5Hash> = decode_delta(&bytes, &limits).unwrap();
+            assert_eq!(decoded.tokens, tokens, "{}", format);
+        }
+    }
+
+    #[test]
+    fn test_decode_unsupported_format() {
+        let formats = [Format::Cbor, Format::MessagePack, Format::Json];
+        for format in formats.into_iter().filter(|format| !format.is_supported()) {
+            let mut bytes = Vec::new();
+            Header {
+                format,
+                kind: Kind::Delta,
+            }
+            .write(&mut bytes)
+            .unwrap();
+            bytes.extend(encoded_delta(vec![], 0));
+            assert!(matches!(
+                decode_delta::<Md5Hash>(&bytes, &DecodeLimits::default()),
+                Err(DecodeError::InvalidHeader(FormatError::Unsupported(_)))
+            ));
+        }
+    }
+
+    #[test_case(vec ! [Reused(0, [0; 16])], 0 => matches DecodeError::MissingChunkSize; "chunk token without chunk size")]
+    #[test_case(vec ! [Diff(0, [0; 16], vec ! [(2, vec ! [0; 2])])], 3 => matches DecodeError::DiffTooLong{..}; "diff longer than a chunk")]
+    #[test_case(vec ! [Fill(0, 1 << 40), Added(Cow::Borrowed(&[1]))], 0 => matches DecodeError::OutputTooLarge{..}; "fill over the output limit")]
+    #[test_case(vec ! [Added(Cow::Borrowed(&[1])), BackReference(0, u64::MAX), BackReference(0, 1)], 0 => matches DecodeError::OutputTooLarge{..}; "output length overflows")]
+    #[test_case(vec ! [BackReference(0, 1)], 0 => matches DecodeError::BackReferenceOutOfWindow{..}; "back-reference to nothing written")]
+    #[test_case(vec ! [Fill(0, BACK_REFERENCE_WINDOW + 1), BackReference(0, 1)], 0 => matches DecodeError::BackReferenceOutOfWindow{..}; "back-reference beyond the window")]
+    fn test_decode_invalid_delta(tokens: Vec<DeltaToken<Md5Hash>>, chunk_size: u64) -> DecodeError {
+        let bytes = encoded_delta(tokens, chunk_size);
+        decode_delta::<Md5Hash>(&bytes, &DecodeLimits::default()).unwrap_err()
+    }
+
+    #[test]
+    fn test_decode_malformed_input() {
+        let bytes = encoded_delta(vec![Added(Cow::Borrowed(&[1, 2, 3]))], 0);
+
+        assert!(matches!(
+            decode_delta::<Md5Hash>(&bytes[..bytes.len() - 1], &DecodeLimits::default()),
+            Err(DecodeError::Malformed(_))
+        ));
+        // a length prefix claiming way more than there is - the version's, after the chunk size
+        let mut huge_prefix = bytes.clone();
+        huge_prefix[HEADER_LEN + 8..HEADER_LEN + 16].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            decode_delta::<Md5Hash>(&huge_prefix, &DecodeLimits::default()),
+            Err(DecodeError::Malformed(_))
+        ));
+        assert!(matches!(
+            decode_delta::<Md5Hash>(
+                &bytes,
+                &DecodeLimits {
+                    max_input_len: 4,
+                    ..Default::default()
+                }
+            ),
+            Err(DecodeError::InputTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_signature_without_chunk_size() {
+        let bytes = encoded(&(vec![(1u32, [0u8; 16])], 0usize, "none"), Kind::Signature);
+
+        assert!(matches!(
+            decode_signature::<u32, Md5Hash>(&bytes, &DecodeLimits::default()),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+
+    #[test_case(Format::Bincode)]
+    #[test_case(Format::Cbor)]
+    #[test_case(Format::MessagePack)]
+    #[test_case(Format::Json)]
+    fn test_decode_trailing_bytes(format: Format) {
+        if !format.is_supported() {
+            return;
+        }
+        let delta = Delta::<Md5Hash> {
+            tokens: vec![Fill(7, 10)],
+            chunk_size: 0,
+            version: DEFAULT_VERSION.to_string(),
+        };
+        let mut bytes = Vec::new();
+        format::encode(&delta, Kind::Delta, format, &mut bytes).unwrap();
+        // a space would be skipped by a lenient JSON reader
+        bytes.extend(b" 0");
+
+        assert!(matches!(
+            decode_delta::<Md5Hash>(&bytes, &DecodeLimits::default()),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_headerless() {
+        // the first release wrote the tokens first: none, then the chunk size and the version
+        let bytes = bincode2::serialize(&(Vec::<()>::new(), 16u64, "v1")).unwrap();
+        let decoded: Delta<Md5Hash> = decode_delta(&bytes, &DecodeLimits::default()).unwrap();
+        assert!(decoded.tokens.is_empty());
+        assert_eq!(decoded.chunk_size, 16);
+
+        // tree deltas came with headers
+        assert!(matches!(
+            decode_tree_delta::<Md5Hash>(&bytes, &DecodeLimits::default()),
+            Err(DecodeError::InvalidHeader(FormatError::MissingHeader(
+                Kind::TreeDelta
+            )))
+        ));
+    }
+}
@@ -1,18 +1,36 @@
+use std::borrow::Cow;
 use std::cmp::min;
-use std::fmt::Debug;
+use std::fmt::{self, Debug, Formatter};
 use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::str::FromStr;
 
 use bitvec::bitvec;
-use indicatif::{ProgressBar, ProgressStyle};
 use log::{error, info};
-use serde::{Deserialize, Serialize};
+use serde::de::{self, SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
 
-use crate::delta_generation::DeltaToken::{Added, Removed, Reused};
+use crate::cancel::{CancellationToken, Cancelled};
+use crate::delta_generation::literal_dedup::LiteralIndex;
+use crate::delta_generation::DeltaToken::{Added, Fill, Removed, Reused};
+use crate::progress::{NoProgress, Phase, Progress, ProgressObserver};
 use crate::rolling_checksum::RollingChecksum;
+use crate::signature_index::SignatureIndex;
 use crate::strong_hash::StrongHash;
 use crate::{ChunkNumber, DEFAULT_VERSION};
 
-#[derive(PartialEq, Eq, Serialize, Deserialize, Debug)]
+mod literal_dedup;
+
+///
+/// A piece of a [Delta]
+///
+/// The added data borrows from the new content when the delta is generated or decoded from bytes,
+/// and is owned once the token went through [DeltaToken::into_owned].
+///
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
 pub enum DeltaToken<'a, S>
 where
     S: PartialEq + Debug,
@@ -21,24 +39,285 @@ where
         ChunkNumber, /* chunk number in old file */
         S,           /* strong hash over the content for the patch operation to use*/
     ),
-    Added(&'a [u8] /* new data */),
+    Added(
+        #[serde(borrow, with = "crate::format::bytes")] Cow<'a, [u8]>, /* new data */
+    ),
     Removed(ChunkNumber),
+    Copied(
+        u64, /* offset in old file */
+        u64, /* length */
+        S,   /* strong hash over the copied range for the patch operation to use */
+    ),
+    BackReference(
+        u64, /* offset in the already patched output */
+        u64, /* length */
+    ),
+    Fill(u8 /* the repeated byte */, u64 /* length */),
+    Diff(
+        ChunkNumber,         /* chunk number in old file */
+        S,                   /* strong hash over the chunk for the patch operation to use */
+        Vec<(u64, Vec<u8>)>, /* the new bytes of each run that differs, by offset in the chunk */
+    ),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// The tokens that turn the old content into the new one
+///
+/// It's serialized header first - the chunk size and the version - followed by each token wrapped
+/// in `Some` and a final `None`, so that it can be written and read a token at a time (see
+/// [crate::token_stream]) without knowing the token count up front.
+///
+#[derive(Debug, Clone)]
 pub struct Delta<'a, S>
 where
     S: Eq + PartialEq + Debug,
 {
-    #[serde(borrow)]
     pub tokens: Vec<DeltaToken<'a, S>>,
     pub chunk_size: u64,
     pub version: String,
 }
 
+impl<S> Serialize for Delta<'_, S>
+where
+    S: Eq + PartialEq + Debug + Serialize,
+{
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        let mut layout = serializer.serialize_tuple(self.tokens.len() + 3)?;
+        layout.serialize_element(&self.chunk_size)?;
+        layout.serialize_element(&self.version)?;
+        for token in &self.tokens {
+            layout.serialize_element(&Some(token))?;
+        }
+        layout.serialize_element(&None::<&DeltaToken<S>>)?;
+        layout.end()
+    }
+}
+
+impl<'de: 'a, 'a, S> Deserialize<'de> for Delta<'a, S>
+where
+    S: Eq + PartialEq + Debug + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // the real length is only known once the final `None` is read
+        deserializer.deserialize_tuple(usize::MAX, DeltaVisitor(PhantomData))
+    }
+}
+
+struct DeltaVisitor<'a, S>(PhantomData<Delta<'a, S>>)
+where
+    S: Eq + PartialEq + Debug;
+
+impl<'de: 'a, 'a, S> Visitor<'de> for DeltaVisitor<'a, S>
+where
+    S: Eq + PartialEq + Debug + Deserialize<'de>,
+{
+    type Value = Delta<'a, S>;
+
+    fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter.write_str("a delta")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let chunk_size = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let version = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        let mut tokens = Vec::new();
+        loop {
+            match seq.next_element::<Option<DeltaToken<S>>>()? {
+                Some(Some(token)) => tokens.push(token),
+                Some(None) => break,
+                None => return Err(de::Error::invalid_length(tokens.len() + 2, &self)),
+            }
+        }
+        Ok(Delta {
+            tokens,
+            chunk_size,
+            version,
+        })
+    }
+}
+
+///
+/// A delta that doesn't borrow from the new content, so it can be kept around, sent to another
+/// thread or built up from several sources
+///
+/// Decoding borrows from the encoded bytes, [Delta::into_owned] detaches the result:
+///
+/// ```
+/// use rolling_in_the_diff::decode::{decode_delta, DecodeLimits};
+/// use rolling_in_the_diff::delta_generation::OwnedDelta;
+/// use rolling_in_the_diff::diff::diff;
+/// use rolling_in_the_diff::format::{encode, Format, Kind};
+/// use rolling_in_the_diff::rolling_checksum::rolling_adler32::RollingAdler32;
+/// use rolling_in_the_diff::strong_hash::md5::Md5Sum;
+///
+/// let encoded = {
+///     let new_content = b"the quick brown cat".to_vec();
+///     let delta = diff::<RollingAdler32, Md5Sum>(b"the quick brown fox", &new_content);
+///     let mut encoded = Vec::new();
+///     encode(&delta, Kind::Delta, Format::Bincode, &mut encoded).unwrap();
+///     encoded
+/// };
+/// let delta: OwnedDelta<[u8; 16]> = decode_delta(&encoded, &DecodeLimits::default())
+///     .unwrap()
+///     .into_owned();
+/// drop(encoded);
+/// std::thread::spawn(move || assert!(!delta.tokens.is_empty())).join().unwrap();
+/// ```
+///
+pub type OwnedDelta<S> = Delta<'static, S>;
+
+impl<S> DeltaToken<'_, S>
+where
+    S: PartialEq + Debug,
+{
+    /// Copies borrowed added data
+    pub fn into_owned(self) -> DeltaToken<'static, S> {
+        match self {
+            Reused(chunk_number, hash) => Reused(chunk_number, hash),
+            Added(bytes) => Added(Cow::Owned(bytes.into_owned())),
+            Removed(chunk_number) => Removed(chunk_number),
+            DeltaToken::Copied(offset, len, hash) => DeltaToken::Copied(offset, len, hash),
+            DeltaToken::BackReference(offset, len) => DeltaToken::BackReference(offset, len),
+            Fill(byte, len) => Fill(byte, len),
+            DeltaToken::Diff(chunk_number, hash, runs) => {
+                DeltaToken::Diff(chunk_number, hash, runs)
+            }
+        }
+    }
+}
+
+impl<S> Delta<'_, S>
+where
+    S: Eq + PartialEq + Debug,
+{
+    /// Copies all borrowed added data
+    pub fn into_owned(self) -> OwnedDelta<S> {
+        Delta {
+            tokens: self
+                .tokens
+                .into_iter()
+                .map(DeltaToken::into_owned)
+                .collect(),
+            chunk_size: self.chunk_size,
+            version: self.version,
+        }
+    }
+}
+
+/// `bytes[range]`, still borrowing from the same buffer if `bytes` does
+pub(crate) fn sub_slice<'a>(bytes: &Cow<'a, [u8]>, range: Range<usize>) -> Cow<'a, [u8]> {
+    match bytes {
+        Cow::Borrowed(bytes) => Cow::Borrowed(&bytes[range]),
+        Cow::Owned(bytes) => Cow::Owned(bytes[range].to_vec()),
+    }
+}
+
+/// How a match is picked when several old chunks could be reused
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MatchPolicy {
+    /// The first chunk with matching checksums wins
+    #[default]
+    FirstMatch,
+    /// The chunk right after the previously reused one is tried first - with a single strong hash
+    /// and no rolling - and is preferred among the matching chunks otherwise
+    PreferSequential,
+}
+
+impl FromStr for MatchPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(MatchPolicy::FirstMatch),
+            "sequential" => Ok(MatchPolicy::PreferSequential),
+            _ => Err(format!("unknown match policy {} (first or sequential)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct DeltaOptions {
+    pub match_policy: MatchPolicy,
+    /// Literal data that repeats data emitted earlier is encoded as a `BackReference`
+    pub dedup_literals: bool,
+    /// Runs of a single repeated byte (e.g. zeros in sparse files) are encoded as a `Fill`
+    pub fill_runs: bool,
+    /// The `Removed` tokens listing the old chunks that aren't reused are left out - patching
+    /// doesn't need them, they only tell what changed
+    pub omit_removed: bool,
+}
+
+///
+/// How far back in the output a `BackReference` can point
+///
+/// Patching keeps only that much of what it wrote, so the memory needed to apply a delta doesn't
+/// grow with the output.
+///
+pub const BACK_REFERENCE_WINDOW: u64 = 1 << 24;
+
+/// Shorter runs of a repeated byte are cheaper to keep as literal data
+const MIN_FILL_LEN: usize = 32;
+
+/// The `[start, end)` ranges of at least [MIN_FILL_LEN] repetitions of a byte in `content[from..]`
+fn repeated_byte_runs(content: &[u8], from: usize) -> Vec<(usize, usize)> {
+    let mut runs = Vec::new();
+    let mut run_start = from;
+    while run_start < content.len() {
+        let byte = content[run_start];
+        let run_len = content[run_start..]
+            .iter()
+            .take_while(|&&b| b == byte)
+            .count();
+        if run_len >= MIN_FILL_LEN {
+            runs.push((run_start, run_start + run_len));
+        }
+        run_start += run_len;
+    }
+    runs
+}
+
+///
+/// Generates the delta between the old content described by `old_signature` and `new_content`
+///
+/// A corrupt signature gives a delta that doesn't reuse anything, [try_generate_delta] reports it
+/// instead.
+///
+#[deprecated(note = "a corrupt signature only shows in the log, use try_generate_delta")]
 pub fn generate_delta<'a, R, S>(
-    old_signature: &crate::Signature<R::ChecksumType, S::HashType>,
+    old_signature: &impl SignatureIndex<R::ChecksumType, S::HashType>,
+    new_content: &'a [u8],
+) -> Delta<'a, S::HashType>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash,
+    S: StrongHash,
+    <S as StrongHash>::HashType: Eq,
+{
+    #[allow(deprecated)]
+    generate_delta_with_options::<R, S>(
+        old_signature,
+        new_content,
+        &DeltaOptions::default(),
+        &NoProgress,
+    )
+}
+
+///
+/// Like [try_generate_delta_with_options], but a corrupt signature results in a delta that
+/// doesn't reuse anything instead of an error
+///
+#[deprecated(
+    note = "a corrupt signature only shows in the log, use try_generate_delta_with_options"
+)]
+pub fn generate_delta_with_options<'a, R, S>(
+    old_signature: &impl SignatureIndex<R::ChecksumType, S::HashType>,
     new_content: &'a [u8],
+    options: &DeltaOptions,
+    progress: &dyn ProgressObserver,
 ) -> Delta<'a, S::HashType>
 where
     R: RollingChecksum,
@@ -46,82 +325,294 @@ where
     S: StrongHash,
     <S as StrongHash>::HashType: Eq,
 {
-    let version = crate::VERSION.unwrap_or(DEFAULT_VERSION).to_string();
+    let cancel = CancellationToken::new();
+    try_generate_delta_with_options::<R, S>(old_signature, new_content, options, progress, &cancel)
+        .unwrap_or_else(|e| {
+            error!("{}, the delta won't reuse any chunk", e);
+            let mut tokens = Vec::new();
+            if !new_content.is_empty() {
+                tokens.push(Added(Cow::Borrowed(new_content)));
+            }
+            Delta {
+                tokens,
+                chunk_size: old_signature.chunk_size() as u64,
+                version: crate::VERSION.unwrap_or(DEFAULT_VERSION).to_string(),
+            }
+        })
+}
+
+pub fn try_generate_delta<'a, R, S>(
+    old_signature: &impl SignatureIndex<R::ChecksumType, S::HashType>,
+    new_content: &'a [u8],
+) -> Result<Delta<'a, S::HashType>, DeltaError>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash,
+    S: StrongHash,
+    <S as StrongHash>::HashType: Eq,
+{
+    try_generate_delta_with_options::<R, S>(
+        old_signature,
+        new_content,
+        &DeltaOptions::default(),
+        &NoProgress,
+        &CancellationToken::new(),
+    )
+}
 
-    let mut reused_chunks = bitvec![0; old_signature.chunk_count];
+///
+/// Generates the delta between the old content described by `old_signature` and `new_content`
+///
+/// `cancel` is checked between chunks and every [SCAN_STEP] bytes of scanning for a match.
+///
+pub fn try_generate_delta_with_options<'a, R, S>(
+    old_signature: &impl SignatureIndex<R::ChecksumType, S::HashType>,
+    new_content: &'a [u8],
+    options: &DeltaOptions,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+) -> Result<Delta<'a, S::HashType>, DeltaError>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash,
+    S: StrongHash,
+    <S as StrongHash>::HashType: Eq,
+{
+    check_chunk_size(old_signature)?;
+    let mut tokens = Vec::with_capacity(old_signature.chunk_count());
+    generate_tokens::<R, S, DeltaError>(
+        old_signature,
+        new_content,
+        options,
+        progress,
+        cancel,
+        |token| {
+            tokens.push(token);
+            Ok(())
+        },
+    )?;
+    Ok(Delta {
+        tokens,
+        chunk_size: old_signature.chunk_size() as u64,
+        version: crate::VERSION.unwrap_or(DEFAULT_VERSION).to_string(),
+    })
+}
 
-    let mut delta = Delta {
-        tokens: Vec::with_capacity(old_signature.chunk_count),
-        chunk_size: old_signature.chunk_size as u64,
-        version,
-    };
+///
+/// Like [try_generate_delta_with_options], but the tokens go to `visitor` as soon as they are
+/// generated instead of being collected into a [Delta]
+///
+/// With a [DeltaWriter](crate::token_stream::DeltaWriter) the delta is encoded while it's being
+/// generated.
+///
+pub fn generate_delta_into<R, S, V>(
+    old_signature: &impl SignatureIndex<R::ChecksumType, S::HashType>,
+    new_content: &[u8],
+    options: &DeltaOptions,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+    visitor: &mut V,
+) -> Result<(), GenerateIntoError<V::Error>>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash,
+    S: StrongHash,
+    <S as StrongHash>::HashType: Eq,
+    V: crate::token_stream::DeltaVisitor<S::HashType>,
+{
+    check_chunk_size(old_signature)?;
+    visitor
+        .visit_header(
+            old_signature.chunk_size() as u64,
+            crate::VERSION.unwrap_or(DEFAULT_VERSION),
+        )
+        .map_err(GenerateIntoError::Visitor)?;
+    generate_tokens::<R, S, GenerateIntoError<V::Error>>(
+        old_signature,
+        new_content,
+        options,
+        progress,
+        cancel,
+        |token| {
+            visitor
+                .visit_token(token)
+                .map_err(GenerateIntoError::Visitor)
+        },
+    )?;
+    visitor.finish().map_err(GenerateIntoError::Visitor)
+}
+
+fn check_chunk_size<W, S>(old_signature: &impl SignatureIndex<W, S>) -> Result<(), DeltaError> {
+    if old_signature.chunk_size() == 0 && old_signature.chunk_count() > 0 {
+        return Err(DeltaError::InvalidChunkSize {
+            chunk_count: old_signature.chunk_count() as u64,
+        });
+    }
+    Ok(())
+}
+
+/// Hands the tokens of the delta to `emit` in order, the chunk size must have been checked
+fn generate_tokens<'a, R, S, E>(
+    old_signature: &impl SignatureIndex<R::ChecksumType, S::HashType>,
+    new_content: &'a [u8],
+    options: &DeltaOptions,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+    mut emit: impl FnMut(DeltaToken<'a, S::HashType>) -> Result<(), E>,
+) -> Result<(), E>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash,
+    S: StrongHash,
+    <S as StrongHash>::HashType: Eq,
+    E: From<DeltaError>,
+{
+    let mut reused_chunks = bitvec![0; old_signature.chunk_count()];
+
+    // the tokens of a step, emitted at the end of it
+    let mut tokens = Vec::new();
     let mut left = 0;
 
-    let progress = ProgressBar::new(new_content.len() as u64);
-    progress.set_style(
-        ProgressStyle::default_bar()
-            .template("{msg} {bar} {bytes}/{total_bytes}")
-            .unwrap(),
-    );
-    progress.set_message("Going through new content:");
+    let sequential = options.match_policy == MatchPolicy::PreferSequential;
+    let chunk_hashes = if sequential {
+        old_signature.strong_hashes_by_chunk()
+    } else {
+        Vec::new()
+    };
+    let mut last_reused: Option<ChunkNumber> = None;
+    let mut literal_index = options
+        .dedup_literals
+        .then(|| LiteralIndex::<R, S>::new(old_signature.chunk_size()));
+    let mut push_literal = |tokens: &mut Vec<DeltaToken<'a, S::HashType>>, start, end| {
+        let runs = if options.fill_runs {
+            repeated_byte_runs(&new_content[..end], start)
+        } else {
+            Vec::new()
+        };
+        let mut segment_start = start;
+        for (run_start, run_end) in runs.into_iter().chain([(end, end)]) {
+            if run_start > segment_start {
+                match literal_index.as_mut() {
+                    Some(literal_index) => {
+                        literal_index.emit(new_content, segment_start, run_start, tokens)
+                    }
+                    None => {
+                        tokens.push(Added(Cow::Borrowed(&new_content[segment_start..run_start])))
+                    }
+                }
+            }
+            if run_end > run_start {
+                tokens.push(Fill(new_content[run_start], (run_end - run_start) as u64));
+            }
+            segment_start = run_end;
+        }
+    };
+
+    let mut report = Progress::new(Phase::Delta, Some(new_content.len() as u64));
     let mut reused_count = 0;
     loop {
-        match find_reused_chunk::<R, S>(old_signature, &new_content[left..]) {
+        for token in tokens.drain(..) {
+            emit(token)?;
+        }
+        cancel.check().map_err(DeltaError::from)?;
+        let next_chunk = last_reused.map(|chunk_number| chunk_number + 1);
+        let next_hash = next_chunk.and_then(|c| chunk_hashes.get(c as usize).copied().flatten());
+        if let (Some(next_chunk), Some(next_hash)) = (next_chunk, next_hash) {
+            let chunk_after_end = min(left + old_signature.chunk_size(), new_content.len());
+            if chunk_after_end > left && S::hash(&new_content[left..chunk_after_end]) == next_hash {
+                tokens.push(Reused(next_chunk, next_hash));
+                left = chunk_after_end;
+                reused_chunks.set(next_chunk as usize, true);
+                reused_count += 1;
+                last_reused = Some(next_chunk);
+                report.processed_bytes = left as u64;
+                report.reused_chunks = reused_count;
+                progress.on_progress(&report);
+                continue;
+            }
+        }
+
+        let preferred_chunk = if sequential { next_chunk } else { None };
+        let found =
+            find_reused_chunk::<R, S>(old_signature, &new_content[left..], preferred_chunk, cancel);
+        match found {
             Some(reused_chunk) => {
                 if reused_chunk.bytes_until_reused > 0 {
-                    delta.tokens.push(Added(
-                        &new_content[left..left + reused_chunk.bytes_until_reused],
-                    ));
+                    push_literal(&mut tokens, left, left + reused_chunk.bytes_until_reused);
                     left += reused_chunk.bytes_until_reused;
                 }
-                delta.tokens.push(Reused(
+                if reused_chunk.chunk_number >= old_signature.chunk_count() as ChunkNumber {
+                    // that might very well mean an invalid signature file
+                    return Err(DeltaError::ChunkOutOfRange {
+                        chunk_num: reused_chunk.chunk_number,
+                        chunk_count: old_signature.chunk_count() as u64,
+                    }
+                    .into());
+                }
+                tokens.push(Reused(
                     reused_chunk.chunk_number,
                     reused_chunk.chunk_strong_hash,
                 ));
                 left += reused_chunk.reused_chunk_size;
-                if reused_chunk.chunk_number >= old_signature.chunk_count as ChunkNumber {
-                    // that might very well mean an invalid signature file
-                    error!(
-                        "signature contains a chunk number out of the chunk count: {} >= {}",
-                        reused_chunk.chunk_number, old_signature.chunk_count
-                    );
-                    continue;
-                }
                 reused_chunks.set(reused_chunk.chunk_number as usize, true);
                 reused_count += 1;
-                progress.set_position(left as u64);
+                last_reused = Some(reused_chunk.chunk_number);
+                report.processed_bytes = left as u64;
+                report.reused_chunks = reused_count;
+                progress.on_progress(&report);
             }
             None => {
+                // the scan stops early when cancelled
+                cancel.check().map_err(DeltaError::from)?;
                 // couldn't find a single match until the end of the new content - finish up the delta
                 // note: empty new_content with [0..] is a valid usage
                 if !new_content[left..].is_empty() {
-                    delta.tokens.push(Added(&new_content[left..]));
+                    push_literal(&mut tokens, left, new_content.len());
                 }
                 // fill up all the removed chunks at the end
-                for i in 0..old_signature.chunk_count {
+                let removed_count = if options.omit_removed {
+                    0
+                } else {
+                    old_signature.chunk_count()
+                };
+                for i in 0..removed_count {
                     if let Some(&true) = reused_chunks.get(i).as_deref() {
                         continue;
                     }
-                    delta.tokens.push(Removed(i as ChunkNumber));
+                    tokens.push(Removed(i as ChunkNumber));
+                }
+                for token in tokens.drain(..) {
+                    emit(token)?;
                 }
-                progress.finish();
+                report.processed_bytes = new_content.len() as u64;
+                report.reused_chunks = reused_count;
+                progress.on_finish(&report);
                 info!("reused chunks: {}", reused_count);
-                return delta;
+                return Ok(());
             }
         }
     }
 }
 
-struct ReusedChunkDescriptor<T> {
-    bytes_until_reused: usize,
-    reused_chunk_size: usize,
-    chunk_number: ChunkNumber,
-    chunk_strong_hash: T,
+pub(crate) struct ReusedChunkDescriptor<T> {
+    pub(crate) bytes_until_reused: usize,
+    pub(crate) reused_chunk_size: usize,
+    pub(crate) chunk_number: ChunkNumber,
+    pub(crate) chunk_strong_hash: T,
 }
 
-fn find_reused_chunk<R, S>(
-    old_signature: &crate::Signature<R::ChecksumType, S::HashType>,
+/// How many bytes are scanned for a match between two checks of the cancellation
+pub const SCAN_STEP: usize = 1 << 16;
+
+///
+/// Scans `new_content` for the first chunk of the signature, `None` if there's none until the end
+/// or if `cancel` gets cancelled in the meantime
+///
+pub(crate) fn find_reused_chunk<R, S>(
+    old_signature: &impl SignatureIndex<R::ChecksumType, S::HashType>,
     new_content: &[u8],
+    preferred_chunk: Option<ChunkNumber>,
+    cancel: &CancellationToken,
 ) -> Option<ReusedChunkDescriptor<S::HashType>>
 where
     R: RollingChecksum,
@@ -129,31 +620,42 @@ where
     S: StrongHash,
 {
     // it's possible that the original file includes a non-chunk-aligned chunk at the end
-    // and it can be matched by a less-than-old_signature.chunk_size from the new content
-    let mut chunk_after_end = min(old_signature.chunk_size, new_content.len());
+    // and it can be matched by a less-than-old_signature.chunk_size() from the new content
+    let mut chunk_after_end = min(old_signature.chunk_size(), new_content.len());
 
     let mut rolling_checksum = R::new(&new_content[..chunk_after_end]);
     let mut chunk_start = 0;
 
     loop {
-        if chunk_after_end - chunk_start == 0 {
+        if chunk_after_end - chunk_start == 0
+            || (chunk_start % SCAN_STEP == SCAN_STEP - 1 && cancel.is_cancelled())
+        {
             return None;
         }
         let checksum = rolling_checksum.checksum();
 
-        if let Some(strong_hashes) = old_signature.quick_query(&checksum) {
+        let mut candidates = old_signature.quick_query(&checksum).peekable();
+        if candidates.peek().is_some() {
             let hash = S::hash(&new_content[chunk_start..chunk_after_end]);
 
-            for (signature_hash, chunk_number) in strong_hashes {
-                let signature_hash = *signature_hash;
-                if signature_hash == hash {
-                    return Some(ReusedChunkDescriptor {
-                        bytes_until_reused: chunk_start,
-                        reused_chunk_size: chunk_after_end - chunk_start,
-                        chunk_number: *chunk_number,
-                        chunk_strong_hash: signature_hash,
-                    });
+            let mut reused = None;
+            for (_, chunk_number) in
+                candidates.filter(|(signature_hash, _)| *signature_hash == hash)
+            {
+                if Some(chunk_number) == preferred_chunk {
+                    reused = Some(chunk_number);
+                    break;
                 }
+                reused.get_or_insert(chunk_number);
+            }
+
+            if let Some(chunk_number) = reused {
+                return Some(ReusedChunkDescriptor {
+                    bytes_until_reused: chunk_start,
+                    reused_chunk_size: chunk_after_end - chunk_start,
+                    chunk_number,
+                    chunk_strong_hash: hash,
+                });
             }
         }
         rolling_checksum.pop_byte(new_content[chunk_start], chunk_after_end - chunk_start);
@@ -165,16 +667,39 @@ where
     }
 }
 
+#[derive(Error, Debug)]
+pub enum DeltaError {
+    #[error("signature refers to chunk {chunk_num} out of {chunk_count}")]
+    ChunkOutOfRange { chunk_num: u64, chunk_count: u64 },
+    #[error("signature has {chunk_count} chunks but no chunk size")]
+    InvalidChunkSize { chunk_count: u64 },
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+}
+
+/// Why [generate_delta_into] failed
+#[derive(Error, Debug)]
+pub enum GenerateIntoError<E> {
+    #[error("failed to generate the delta")]
+    Delta(#[from] DeltaError),
+    #[error("the visitor of the delta failed")]
+    Visitor(#[source] E),
+}
+
 #[cfg(test)]
+#[allow(clippy::needless_return)]
 mod test {
-    use std::collections::HashMap;
     use std::iter::zip;
 
     use adler32::RollingAdler32 as actual_adler32;
     use test_case::test_case;
 
+    use crate::delta_generation::DeltaToken::BackReference;
+    use crate::format::{encode, Format, Kind};
     use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+    use crate::signature_generation::generate_signature;
     use crate::strong_hash::md5::Md5Sum;
+    use crate::token_stream::DeltaWriter;
     use crate::{Signature, VERSION};
 
     use super::*;
@@ -182,14 +707,14 @@ mod test {
     #[test_case(
     3, & [1, 2, 3, 4, 5, 6], & [0, 1, 2, 4, 5, 6] =>
     vec ! [
-    Added(& [0, 1, 2]),
+    Added(Cow::Borrowed(&[0, 1, 2])),
     Reused(1, Md5Sum::hash(& [4, 5, 6])),
     Removed(0),
     ]; "chunks are perfectly aligned")]
     #[test_case(
     3, & [1, 2, 3, 4, 5], & [0, 1, 2, 4, 5] =>
     vec ! [
-    Added(& [0, 1, 2]),
+    Added(Cow::Borrowed(&[0, 1, 2])),
     Reused(1, Md5Sum::hash(& [4, 5])),
     Removed(0),
     ];
@@ -204,7 +729,7 @@ mod test {
     #[test_case(
     3, & [1, 2, 3, 4, 5], & [4, 5, 1, 2, 3] =>
     vec ! [
-    Added(& [4, 5]),
+    Added(Cow::Borrowed(&[4, 5])),
     Reused(0, Md5Sum::hash(& [1, 2, 3])),
     Removed(1),
     ];
@@ -220,46 +745,243 @@ mod test {
         old_content: &'static [u8],
         new_content: &'static [u8],
     ) -> Vec<DeltaToken<'static, <Md5Sum as StrongHash>::HashType>> {
-        let mut signature_map: HashMap<u32, Vec<(<Md5Sum as StrongHash>::HashType, ChunkNumber)>> =
-            HashMap::new();
-
-        for (chunk_num, chunk) in old_content.chunks(chunk_size).enumerate() {
-            assert_eq!(
-                signature_map.insert(
+        let chunks: Vec<_> = old_content
+            .chunks(chunk_size)
+            .map(|chunk| {
+                (
                     actual_adler32::from_buffer(chunk).hash(),
-                    vec![(Md5Sum::hash(chunk), chunk_num as ChunkNumber)],
-                ),
-                None
-            );
-        }
+                    Md5Sum::hash(chunk),
+                )
+            })
+            .collect();
 
-        let signature = Signature {
-            checksum_to_hashes: signature_map,
-            chunk_count: old_content.chunks(chunk_size).len(),
+        let signature = Signature::from_ordered_chunks(
+            chunks,
             chunk_size,
-            version: VERSION.unwrap_or(DEFAULT_VERSION).to_string(),
+            VERSION.unwrap_or(DEFAULT_VERSION).to_string(),
+        );
+        return try_generate_delta::<RollingAdler32, Md5Sum>(&signature, new_content)
+            .unwrap()
+            .tokens;
+    }
+
+    #[test_case(MatchPolicy::FirstMatch =>
+    vec ! [
+    Reused(0, Md5Sum::hash(& [1, 2, 3])),
+    Reused(0, Md5Sum::hash(& [1, 2, 3])),
+    Reused(0, Md5Sum::hash(& [1, 2, 3])),
+    Removed(1),
+    Removed(2),
+    ]; "first match reuses the same chunk")]
+    #[test_case(MatchPolicy::PreferSequential =>
+    vec ! [
+    Reused(0, Md5Sum::hash(& [1, 2, 3])),
+    Reused(1, Md5Sum::hash(& [1, 2, 3])),
+    Reused(2, Md5Sum::hash(& [1, 2, 3])),
+    ]; "sequential match continues with the next chunk")]
+    fn test_generate_delta_with_repeating_chunks(
+        match_policy: MatchPolicy,
+    ) -> Vec<DeltaToken<'static, <Md5Sum as StrongHash>::HashType>> {
+        let chunk = [1, 2, 3];
+        let checksum = actual_adler32::from_buffer(&chunk).hash();
+        let signature = Signature::from_ordered_chunks(
+            vec![(checksum, Md5Sum::hash(&chunk)); 3],
+            chunk.len(),
+            VERSION.unwrap_or(DEFAULT_VERSION).to_string(),
+        );
+
+        try_generate_delta_with_options::<RollingAdler32, Md5Sum>(
+            &signature,
+            &[1, 2, 3, 1, 2, 3, 1, 2, 3],
+            &DeltaOptions {
+                match_policy,
+                ..Default::default()
+            },
+            &NoProgress,
+            &CancellationToken::new(),
+        )
+        .unwrap()
+        .tokens
+    }
+
+    #[test]
+    fn test_generate_delta_with_dedup_literals() {
+        let block: Vec<u8> = (0..100).collect();
+        let new_content = [block.as_slice(), &[0], block.as_slice()].concat();
+        let signature = Signature::<u32, <Md5Sum as StrongHash>::HashType>::from_ordered_chunks(
+            [],
+            0,
+            VERSION.unwrap_or(DEFAULT_VERSION).to_string(),
+        );
+
+        let delta = try_generate_delta_with_options::<RollingAdler32, Md5Sum>(
+            &signature,
+            &new_content,
+            &DeltaOptions {
+                dedup_literals: true,
+                ..Default::default()
+            },
+            &NoProgress,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert!(delta
+            .tokens
+            .iter()
+            .any(|token| matches!(token, BackReference(0, _))));
+
+        let mut patched_content = Vec::new();
+        crate::patch::patch::<Md5Sum, _>(&[], delta, &mut patched_content).unwrap();
+        assert_eq!(patched_content, new_content);
+    }
+
+    #[test]
+    fn test_generate_delta_with_fill_runs() {
+        let new_content = [&[1, 2][..], &[0; 100], &[3], &[7; MIN_FILL_LEN - 1]].concat();
+        let signature = Signature::<u32, <Md5Sum as StrongHash>::HashType>::from_ordered_chunks(
+            [],
+            0,
+            VERSION.unwrap_or(DEFAULT_VERSION).to_string(),
+        );
+
+        let delta = try_generate_delta_with_options::<RollingAdler32, Md5Sum>(
+            &signature,
+            &new_content,
+            &DeltaOptions {
+                fill_runs: true,
+                ..Default::default()
+            },
+            &NoProgress,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            delta.tokens,
+            vec![
+                Added(Cow::Borrowed(&[1, 2])),
+                Fill(0, 100),
+                Added(Cow::Borrowed(&new_content[102..]))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cancelled_delta() {
+        let content: Vec<u8> = (0..SCAN_STEP * 4).map(|x| (x * 7 % 251) as u8).collect();
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&content[..SCAN_STEP]);
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        assert!(matches!(
+            try_generate_delta_with_options::<RollingAdler32, Md5Sum>(
+                &signature,
+                &content,
+                &DeltaOptions::default(),
+                &NoProgress,
+                &cancel,
+            ),
+            Err(DeltaError::Cancelled(_))
+        ));
+    }
+
+    #[test]
+    fn test_generate_delta_into() {
+        let old_content: Vec<u8> = (0..1 << 14).map(|x| (x % 251) as u8).collect();
+        let mut new_content = old_content.clone();
+        new_content.splice(100..200, [7; 10]);
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&old_content);
+        let options = DeltaOptions {
+            dedup_literals: true,
+            ..Default::default()
         };
-        return generate_delta::<RollingAdler32, Md5Sum>(&signature, new_content).tokens;
+        let cancel = CancellationToken::new();
+
+        let mut writer = DeltaWriter::new(Vec::new());
+        generate_delta_into::<RollingAdler32, Md5Sum, _>(
+            &signature,
+            &new_content,
+            &options,
+            &NoProgress,
+            &cancel,
+            &mut writer,
+        )
+        .unwrap();
+
+        let delta = try_generate_delta_with_options::<RollingAdler32, Md5Sum>(
+            &signature,
+            &new_content,
+            &options,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        let mut expected = Vec::new();
+        encode(&delta, Kind::Delta, Format::Bincode, &mut expected).unwrap();
+        assert_eq!(writer.into_inner(), expected);
     }
 
     #[test]
+    #[allow(clippy::useless_vec)]
     fn test_generate_delta_with_empty_old_signature() {
-        let signature = Signature {
-            checksum_to_hashes:
-                HashMap::<u32, Vec<(<Md5Sum as StrongHash>::HashType, ChunkNumber)>>::new(),
-            chunk_count: 0,
-            chunk_size: 0,
-            version: VERSION.unwrap_or(DEFAULT_VERSION).to_string(),
-        };
+        let signature = Signature::<u32, <Md5Sum as StrongHash>::HashType>::from_ordered_chunks(
+            [],
+            0,
+            VERSION.unwrap_or(DEFAULT_VERSION).to_string(),
+        );
 
         let new_content = [1, 2, 3];
 
-        let delta = generate_delta::<RollingAdler32, Md5Sum>(&signature, &new_content);
+        let delta = try_generate_delta::<RollingAdler32, Md5Sum>(&signature, &new_content).unwrap();
 
-        let expected_tokens = vec![Added(&[1, 2, 3])];
+        let expected_tokens = vec![Added(Cow::Borrowed(&[1, 2, 3]))];
 
         assert_eq!(delta.tokens.len(), expected_tokens.len());
         zip(delta.tokens.iter(), expected_tokens.iter())
             .for_each(|(actual, expected)| assert_eq!(actual, expected));
     }
+
+    /// Claims that every chunk of the content is chunk 5 of a single-chunk signature
+    struct CorruptSignature;
+
+    impl SignatureIndex<u32, <Md5Sum as StrongHash>::HashType> for CorruptSignature {
+        fn chunk_size(&self) -> usize {
+            3
+        }
+
+        fn chunk_count(&self) -> usize {
+            1
+        }
+
+        fn version(&self) -> &str {
+            DEFAULT_VERSION
+        }
+
+        fn quick_query(
+            &self,
+            _: &u32,
+        ) -> impl Iterator<Item = (<Md5Sum as StrongHash>::HashType, ChunkNumber)> {
+            std::iter::once((Md5Sum::hash(&[1, 2, 3]), 5))
+        }
+
+        fn strong_hashes_by_chunk(&self) -> Vec<Option<<Md5Sum as StrongHash>::HashType>> {
+            vec![None]
+        }
+    }
+
+    #[test]
+    fn test_generate_delta_with_corrupt_signature() {
+        let new_content = [0, 1, 2, 3];
+
+        assert!(matches!(
+            try_generate_delta::<RollingAdler32, Md5Sum>(&CorruptSignature, &new_content),
+            Err(DeltaError::ChunkOutOfRange {
+                chunk_num: 5,
+                chunk_count: 1
+            })
+        ));
+        #[allow(deprecated)]
+        let delta = generate_delta::<RollingAdler32, Md5Sum>(&CorruptSignature, &new_content);
+        assert_eq!(delta.tokens, vec![Added(Cow::Borrowed(&new_content))]);
+    }
 }
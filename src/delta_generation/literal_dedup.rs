@@ -0,0 +1,237 @@
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+use crate::delta_generation::DeltaToken::{Added, BackReference};
+use crate::delta_generation::{DeltaToken, BACK_REFERENCE_WINDOW};
+use crate::rolling_checksum::RollingChecksum;
+use crate::strong_hash::StrongHash;
+
+/// Used when the old content has no chunks to take the block size from
+pub(crate) const MIN_BLOCK_SIZE: usize = 32;
+
+///
+/// Index over the literal data that has already been emitted in a delta
+///
+/// Literal data is indexed in blocks, aligned to the start of each literal run. A later literal
+/// run that contains an indexed block is emitted as a back-reference to it (extended for as long
+/// as the bytes keep matching) instead of repeating the bytes. Blocks that fall further back than
+/// [BACK_REFERENCE_WINDOW] can't be referenced anymore and are dropped, so the index never holds
+/// more than a window's worth of blocks.
+///
+pub(crate) struct LiteralIndex<R, S>
+where
+    R: RollingChecksum,
+    S: StrongHash,
+{
+    block_size: usize,
+    /// Oldest first for each checksum
+    blocks: HashMap<R::ChecksumType, VecDeque<(S::HashType, usize)>>,
+    /// The offsets of the indexed blocks, oldest first
+    offsets: VecDeque<usize>,
+}
+
+impl<R, S> LiteralIndex<R, S>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash,
+    S: StrongHash,
+{
+    pub(crate) fn new(block_size: usize) -> Self {
+        LiteralIndex {
+            block_size: block_size.max(MIN_BLOCK_SIZE),
+            blocks: HashMap::new(),
+            offsets: VecDeque::new(),
+        }
+    }
+
+    ///
+    /// Emits `content[start..end]` as a mix of `Added` and `BackReference` tokens
+    ///
+    /// Offsets are positions in `content`, which is also where the data ends up in the patched output
+    ///
+    pub(crate) fn emit<'a>(
+        &mut self,
+        content: &'a [u8],
+        start: usize,
+        end: usize,
+        tokens: &mut Vec<DeltaToken<'a, S::HashType>>,
+    ) {
+        let block_size = self.block_size;
+        let mut literal_start = start;
+        let mut next_block = start;
+        let mut position = start;
+        let mut rolling_checksum: Option<R> = None;
+
+        while position + block_size <= end {
+            // only blocks that are fully behind the window can be referenced
+            while next_block + block_size <= position {
+                self.insert(content, next_block);
+                next_block += block_size;
+            }
+
+            self.evict(content, position);
+
+            let window = &content[position..position + block_size];
+            let checksum = rolling_checksum.get_or_insert_with(|| R::new(window));
+            match self.lookup(checksum.checksum(), window) {
+                Some(source) => {
+                    let mut len = block_size;
+                    while position + len < end && content[source + len] == content[position + len] {
+                        len += 1;
+                    }
+                    if position > literal_start {
+                        tokens.push(Added(Cow::Borrowed(&content[literal_start..position])));
+                    }
+                    tokens.push(BackReference(source as u64, len as u64));
+
+                    position += len;
+                    literal_start = position;
+                    next_block = position;
+                    rolling_checksum = None;
+                }
+                None => {
+                    checksum.pop_byte(content[position], block_size);
+                    if position + block_size < end {
+                        checksum.push_byte(content[position + block_size]);
+                    }
+                    position += 1;
+                }
+            }
+        }
+
+        if end > literal_start {
+            tokens.push(Added(Cow::Borrowed(&content[literal_start..end])));
+        }
+        while next_block + block_size <= end {
+            self.insert(content, next_block);
+            next_block += block_size;
+        }
+    }
+
+    fn insert(&mut self, content: &[u8], offset: usize) {
+        let block = &content[offset..offset + self.block_size];
+        self.blocks
+            .entry(R::new(block).checksum())
+            .or_insert_with(|| VecDeque::with_capacity(1))
+            .push_back((S::hash(block), offset));
+        self.offsets.push_back(offset);
+    }
+
+    /// Drops the blocks a back-reference at `position` can't reach
+    fn evict(&mut self, content: &[u8], position: usize) {
+        while let Some(&offset) = self.offsets.front() {
+            if (position - offset) as u64 <= BACK_REFERENCE_WINDOW {
+                break;
+            }
+            self.offsets.pop_front();
+            // blocks are indexed in offset order, so this one is the oldest with its checksum
+            let block = &content[offset..offset + self.block_size];
+            if let Entry::Occupied(mut candidates) = self.blocks.entry(R::new(block).checksum()) {
+                candidates.get_mut().pop_front();
+                if candidates.get().is_empty() {
+                    candidates.remove();
+                }
+            }
+        }
+    }
+
+    fn lookup(&self, checksum: R::ChecksumType, window: &[u8]) -> Option<usize> {
+        let candidates = self.blocks.get(&checksum)?;
+        let hash = S::hash(window);
+        candidates
+            .iter()
+            .find(|(block_hash, _)| *block_hash == hash)
+            .map(|(_, offset)| *offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+    use crate::strong_hash::md5::Md5Sum;
+
+    use super::*;
+
+    #[test]
+    fn test_emit_repeated_block() {
+        let block: Vec<u8> = (0..MIN_BLOCK_SIZE as u8).collect();
+        let content = [block.as_slice(), &[1], block.as_slice(), &[2]].concat();
+
+        let mut index = LiteralIndex::<RollingAdler32, Md5Sum>::new(0);
+        let mut tokens = Vec::new();
+        index.emit(&content, 0, content.len(), &mut tokens);
+
+        assert_eq!(
+            tokens,
+            vec![
+                Added(Cow::Borrowed(&content[..MIN_BLOCK_SIZE + 1])),
+                BackReference(0, MIN_BLOCK_SIZE as u64),
+                Added(Cow::Borrowed(&[2])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_emit_across_literal_runs() {
+        let content: Vec<u8> = (0..MIN_BLOCK_SIZE as u8)
+            .cycle()
+            .take(MIN_BLOCK_SIZE * 4)
+            .collect();
+
+        let mut index = LiteralIndex::<RollingAdler32, Md5Sum>::new(0);
+        let mut tokens = Vec::new();
+        index.emit(&content, 0, MIN_BLOCK_SIZE, &mut tokens);
+        index.emit(&content, MIN_BLOCK_SIZE * 2, content.len(), &mut tokens);
+
+        assert_eq!(
+            tokens,
+            vec![
+                Added(Cow::Borrowed(&content[..MIN_BLOCK_SIZE])),
+                BackReference(0, (MIN_BLOCK_SIZE * 2) as u64),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_emit_beyond_the_window() {
+        let block: Vec<u8> = (0..MIN_BLOCK_SIZE as u8).collect();
+        let gap = BACK_REFERENCE_WINDOW as usize;
+        let content = [block.as_slice(), &vec![0; gap], block.as_slice()].concat();
+
+        let mut index = LiteralIndex::<RollingAdler32, Md5Sum>::new(0);
+        let mut tokens = Vec::new();
+        index.emit(&content, 0, MIN_BLOCK_SIZE, &mut tokens);
+        index.emit(&content, MIN_BLOCK_SIZE + gap, content.len(), &mut tokens);
+
+        assert_eq!(
+            tokens,
+            vec![
+                Added(Cow::Borrowed(&content[..MIN_BLOCK_SIZE])),
+                Added(Cow::Borrowed(&content[MIN_BLOCK_SIZE + gap..])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_blocks_behind_the_window_are_dropped() {
+        let block_count = 4;
+        let len = MIN_BLOCK_SIZE * block_count;
+        let far = BACK_REFERENCE_WINDOW as usize + len;
+        let content: Vec<u8> = (0..far + len).map(|x| (x % 251) as u8).collect();
+
+        let mut index = LiteralIndex::<RollingAdler32, Md5Sum>::new(0);
+        let mut tokens = Vec::new();
+        index.emit(&content, 0, len, &mut tokens);
+        assert_eq!(index.offsets.len(), block_count);
+
+        index.emit(&content, far, far + len, &mut tokens);
+        let expected: Vec<_> = (0..block_count)
+            .map(|block| far + block * MIN_BLOCK_SIZE)
+            .collect();
+        assert!(index.offsets.iter().eq(expected.iter()));
+        let indexed: usize = index.blocks.values().map(VecDeque::len).sum();
+        assert_eq!(indexed, block_count);
+    }
+}
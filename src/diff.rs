@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::cancel::CancellationToken;
+use crate::delta_generation::DeltaToken::{Added, Copied, Diff, Reused};
+use crate::delta_generation::{
+    sub_slice, try_generate_delta_with_options, Delta, DeltaError, DeltaOptions,
+};
+use crate::progress::{NoProgress, ProgressObserver};
+use crate::rolling_checksum::RollingChecksum;
+use crate::signature_generation::{cancellable_signature, ChunkSizePolicy};
+use crate::strong_hash::StrongHash;
+use crate::ChunkNumber;
+
+///
+/// Generates the delta between two local contents without persisting a signature in between
+///
+/// Having the old content at hand, the matched chunks are also extended byte by byte (see [extend_matches])
+///
+/// ```
+/// use rolling_in_the_diff::diff::diff;
+/// use rolling_in_the_diff::patch::patch;
+/// use rolling_in_the_diff::rolling_checksum::rolling_adler32::RollingAdler32;
+/// use rolling_in_the_diff::strong_hash::md5::Md5Sum;
+///
+/// let old_content = b"the quick brown fox jumps over the lazy dog";
+/// let new_content = b"the quick brown cat jumps over the lazy dog";
+///
+/// let delta = diff::<RollingAdler32, Md5Sum>(old_content, new_content);
+///
+/// let mut updated_content = Vec::new();
+/// patch::<Md5Sum, _>(old_content, delta, &mut updated_content).unwrap();
+/// assert_eq!(updated_content, new_content);
+/// ```
+pub fn diff<'a, R, S>(old_content: &[u8], new_content: &'a [u8]) -> Delta<'a, S::HashType>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash + Send + Copy,
+    S: StrongHash,
+    <S as StrongHash>::HashType: Eq + Send,
+{
+    diff_with_options::<R, S>(
+        old_content,
+        new_content,
+        &DiffOptions::default(),
+        &NoProgress,
+        &CancellationToken::new(),
+    )
+    .expect("nothing cancels a new token and the signature is generated right here")
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct DiffOptions {
+    pub delta: DeltaOptions,
+    /// Literal data similar to an old chunk is encoded as a `Diff` against it (see
+    /// [encode_near_matches])
+    pub near_matches: bool,
+}
+
+///
+/// Like [diff], with the encoding picked by `options`
+///
+/// `cancel` is checked while generating the signature of the old content and then the delta.
+///
+pub fn diff_with_options<'a, R, S>(
+    old_content: &[u8],
+    new_content: &'a [u8],
+    options: &DiffOptions,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+) -> Result<Delta<'a, S::HashType>, DeltaError>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash + Send + Copy,
+    S: StrongHash,
+    <S as StrongHash>::HashType: Eq + Send,
+{
+    let signature =
+        cancellable_signature::<R, S>(old_content, &ChunkSizePolicy::default(), progress, cancel)?;
+    let mut delta = try_generate_delta_with_options::<R, S>(
+        &signature,
+        new_content,
+        &options.delta,
+        progress,
+        cancel,
+    )?;
+    if options.near_matches {
+        delta = encode_near_matches::<R, S>(delta, old_content);
+    }
+    Ok(extend_matches::<S>(delta, old_content))
+}
+
+/// Sub-blocks sampled per chunk when looking for a similar one
+const NEAR_MATCH_SAMPLES: usize = 4;
+/// Similar chunks sharing a sampled sub-block that are compared, at most, for each position
+const NEAR_MATCH_CANDIDATES: usize = 4;
+/// At most 1/N of a chunk can be taken by the changed runs for it to be considered similar
+const NEAR_MATCH_MAX_CHANGED_RATIO: usize = 4;
+/// What a run costs on top of its bytes - its offset and length
+const NEAR_MATCH_RUN_OVERHEAD: usize = 16;
+/// Changed bytes that are closer than that are kept in a single run
+const NEAR_MATCH_RUN_GAP: usize = NEAR_MATCH_RUN_OVERHEAD;
+
+///
+/// Encodes chunk-sized blocks of added data as the runs of bytes that differ from a similar old chunk
+///
+/// Similar chunks are found by rolling over the added data looking for any of a few sub-blocks
+/// sampled from each old chunk, so a block is found wherever it starts in the added data. Only the
+/// changed runs are kept, and the old chunk is checked against its strong hash when patching - the
+/// same idea as bsdiff, useful for executables and database pages with a few changes per chunk.
+///
+pub fn encode_near_matches<'a, R, S>(
+    delta: Delta<'a, S::HashType>,
+    old_content: &[u8],
+) -> Delta<'a, S::HashType>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash,
+    S: StrongHash,
+{
+    let chunk_size = delta.chunk_size as usize;
+    let sub_block_size = chunk_size / NEAR_MATCH_SAMPLES;
+    if sub_block_size == 0 {
+        return delta;
+    }
+
+    let mut sub_blocks: HashMap<R::ChecksumType, Vec<(ChunkNumber, usize)>> = HashMap::new();
+    for (chunk_number, chunk) in old_content.chunks_exact(chunk_size).enumerate() {
+        for (sample, sub_block) in chunk.chunks_exact(sub_block_size).enumerate() {
+            sub_blocks
+                .entry(R::new(sub_block).checksum())
+                .or_default()
+                .push((chunk_number as ChunkNumber, sample));
+        }
+    }
+
+    let mut tokens = Vec::with_capacity(delta.tokens.len());
+    for token in delta.tokens {
+        let bytes = match token {
+            Added(bytes) => bytes,
+            _ => {
+                tokens.push(token);
+                continue;
+            }
+        };
+
+        let mut literal_start = 0;
+        let mut position = 0;
+        let mut rolling_checksum: Option<R> = None;
+        while position + sub_block_size <= bytes.len() {
+            let window = &bytes[position..position + sub_block_size];
+            let checksum = rolling_checksum.get_or_insert_with(|| R::new(window));
+            let similar = sub_blocks
+                .get(&checksum.checksum())
+                .into_iter()
+                .flatten()
+                .take(NEAR_MATCH_CANDIDATES)
+                .find_map(|&(chunk_number, sample)| {
+                    let block_start = position
+                        .checked_sub(sample * sub_block_size)
+                        .filter(|start| *start >= literal_start)
+                        .filter(|start| start + chunk_size <= bytes.len())?;
+                    let old_start = chunk_number as usize * chunk_size;
+                    let old_chunk = &old_content[old_start..old_start + chunk_size];
+                    let block = &bytes[block_start..block_start + chunk_size];
+                    changed_runs(block, old_chunk)
+                        .map(|runs| (block_start, chunk_number, S::hash(old_chunk), runs))
+                });
+
+            match similar {
+                Some((block_start, chunk_number, hash, runs)) => {
+                    if block_start > literal_start {
+                        tokens.push(Added(sub_slice(&bytes, literal_start..block_start)));
+                    }
+                    tokens.push(Diff(chunk_number, hash, runs));
+                    literal_start = block_start + chunk_size;
+                    position = literal_start;
+                    rolling_checksum = None;
+                }
+                None => {
+                    checksum.pop_byte(bytes[position], sub_block_size);
+                    if position + sub_block_size < bytes.len() {
+                        checksum.push_byte(bytes[position + sub_block_size]);
+                    }
+                    position += 1;
+                }
+            }
+        }
+        if bytes.len() > literal_start {
+            tokens.push(Added(sub_slice(&bytes, literal_start..bytes.len())));
+        }
+    }
+
+    Delta { tokens, ..delta }
+}
+
+/// The runs of `new` that differ from `old`, `None` if they don't save enough over `new` itself
+fn changed_runs(new: &[u8], old: &[u8]) -> Option<Vec<(u64, Vec<u8>)>> {
+    let max_cost = new.len() / NEAR_MATCH_MAX_CHANGED_RATIO;
+    let mut runs: Vec<(u64, Vec<u8>)> = Vec::new();
+    let mut cost = 0;
+    let mut run_end = 0;
+    for (i, (new_byte, old_byte)) in new.iter().zip(old).enumerate() {
+        if new_byte == old_byte {
+            continue;
+        }
+        match runs.last_mut() {
+            Some((_, bytes)) if i - run_end < NEAR_MATCH_RUN_GAP => {
+                bytes.extend_from_slice(&new[run_end..=i]);
+                cost += i + 1 - run_end;
+            }
+            _ => {
+                runs.push((i as u64, vec![*new_byte]));
+                cost += 1 + NEAR_MATCH_RUN_OVERHEAD;
+            }
+        }
+        run_end = i + 1;
+        if cost > max_cost {
+            return None;
+        }
+    }
+    Some(runs)
+}
+
+///
+/// Turns the added bytes around each reused chunk into copies from the old content where they match
+///
+/// The signature only allows matching whole chunks, so an insert in the middle of a chunk makes
+/// the whole chunk (minus the part the rolling search aligns with) look new. Extending each match
+/// backward and forward leaves only the actually different bytes as `Added`. Each copy carries the
+/// strong hash of its range so that patching checks it like a reused chunk.
+///
+pub fn extend_matches<'a, S>(
+    delta: Delta<'a, S::HashType>,
+    old_content: &[u8],
+) -> Delta<'a, S::HashType>
+where
+    S: StrongHash,
+{
+    let chunk_size = delta.chunk_size as usize;
+    let chunk_range = |chunk_number: u64| {
+        let start = (chunk_number as usize)
+            .saturating_mul(chunk_size)
+            .min(old_content.len());
+        (
+            start,
+            start.saturating_add(chunk_size).min(old_content.len()),
+        )
+    };
+
+    let mut tokens = Vec::with_capacity(delta.tokens.len());
+    // where in the old content the previous token's copy ended
+    let mut old_cursor: Option<usize> = None;
+    let mut input = delta.tokens.into_iter().peekable();
+
+    while let Some(token) = input.next() {
+        let bytes = match token {
+            Added(bytes) => bytes,
+            Reused(chunk_number, _) => {
+                old_cursor = Some(chunk_range(chunk_number).1);
+                tokens.push(token);
+                continue;
+            }
+            _ => {
+                tokens.push(token);
+                continue;
+            }
+        };
+
+        // the part of the added bytes that is left after the copies
+        let (mut added_start, mut added_end) = (0, bytes.len());
+        if let Some(cursor) = old_cursor {
+            let forward = common_prefix_len(&bytes, &old_content[cursor..]);
+            if forward > 0 {
+                let range = &old_content[cursor..cursor + forward];
+                tokens.push(Copied(cursor as u64, forward as u64, S::hash(range)));
+                added_start = forward;
+            }
+        }
+
+        let mut backward_copy = None;
+        if let Some(Reused(chunk_number, _)) = input.peek() {
+            let (start, _) = chunk_range(*chunk_number);
+            let backward = common_suffix_len(&bytes[added_start..], &old_content[..start]);
+            if backward > 0 {
+                let range = &old_content[start - backward..start];
+                backward_copy = Some(Copied(
+                    (start - backward) as u64,
+                    backward as u64,
+                    S::hash(range),
+                ));
+                added_end -= backward;
+            }
+        }
+
+        if added_end > added_start {
+            tokens.push(Added(sub_slice(&bytes, added_start..added_end)));
+        }
+        tokens.extend(backward_copy);
+        old_cursor = None;
+    }
+
+    Delta { tokens, ..delta }
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+fn common_suffix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count()
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+
+    use test_case::test_case;
+
+    use crate::delta_generation::DeltaToken;
+    use crate::patch::patch;
+    use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+    use crate::strong_hash::md5::Md5Sum;
+    use crate::DEFAULT_VERSION;
+
+    use super::*;
+
+    #[test_case(& [], & []; "both contents are empty")]
+    #[test_case(& [1, 2, 3], & []; "new content is empty")]
+    #[test_case(& [], & [1, 2, 3]; "old content is empty")]
+    #[test_case(& [1, 2, 3, 4, 5, 6, 7, 8, 9], & [7, 8, 9, 4, 5, 6, 0]; "content is moved around")]
+    fn test_diff_round_trip(old_content: &[u8], new_content: &[u8]) {
+        let delta = diff::<RollingAdler32, Md5Sum>(old_content, new_content);
+
+        let mut updated_content = Vec::new();
+        patch::<Md5Sum, _>(old_content, delta, &mut updated_content).unwrap();
+        assert_eq!(updated_content, new_content);
+    }
+
+    #[test_case(false; "when the added data is borrowed")]
+    #[test_case(true; "when the added data is owned")]
+    fn test_extend_matches(owned: bool) {
+        let old_content = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+        // chunk 1 ([4, 5, 6]) is found, the rest is only partially reused
+        let new_content = [0, 2, 3, 4, 5, 6, 7, 0, 9];
+        let hash = Md5Sum::hash(&[4, 5, 6]);
+        let delta = Delta {
+            tokens: vec![
+                Added(Cow::Borrowed(&new_content[..3])),
+                Reused(1, hash),
+                Added(Cow::Borrowed(&new_content[6..])),
+            ],
+            chunk_size: 3,
+            version: DEFAULT_VERSION.to_string(),
+        };
+        let delta = if owned { delta.into_owned() } else { delta };
+
+        let expected: Vec<DeltaToken<_>> = vec![
+            Added(Cow::Borrowed(&[0])),
+            Copied(1, 2, Md5Sum::hash(&[2, 3])),
+            Reused(1, hash),
+            Copied(6, 1, Md5Sum::hash(&[7])),
+            Added(Cow::Borrowed(&[0, 9])),
+        ];
+        assert_eq!(
+            extend_matches::<Md5Sum>(delta, &old_content).tokens,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_diff_extends_matches_around_inserts() {
+        let old_content: Vec<u8> = (0..1 << 12).map(|x| (x * 13 % 251) as u8).collect();
+        let mut new_content = old_content.clone();
+        new_content.insert(1001, 42);
+
+        let delta = diff::<RollingAdler32, Md5Sum>(&old_content, &new_content);
+
+        let added_len: usize = delta
+            .tokens
+            .iter()
+            .map(|token| match token {
+                Added(bytes) => bytes.len(),
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(added_len, 1);
+    }
+
+    fn pseudo_random(len: usize) -> Vec<u8> {
+        let mut seed: u32 = 1;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_diff_with_near_matches() {
+        // big enough for chunks that have room for a run of changes
+        let old_content = pseudo_random(1 << 20);
+        let mut new_content = old_content.clone();
+        // scattered changes within a single chunk
+        new_content[1000] ^= 0xff;
+        new_content[1010] ^= 0xff;
+
+        let options = DiffOptions {
+            near_matches: true,
+            ..Default::default()
+        };
+        let delta = diff_with_options::<RollingAdler32, Md5Sum>(
+            &old_content,
+            &new_content,
+            &options,
+            &NoProgress,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+
+        let runs: Vec<&Vec<(u64, Vec<u8>)>> = delta
+            .tokens
+            .iter()
+            .filter_map(|token| match token {
+                Diff(_, _, runs) => Some(runs),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].len(), 1);
+        assert_eq!(runs[0][0].1, new_content[1000..=1010]);
+        assert!(!delta.tokens.iter().any(|token| matches!(token, Added(_))));
+
+        let mut updated_content = Vec::new();
+        patch::<Md5Sum, _>(&old_content, delta, &mut updated_content).unwrap();
+        assert_eq!(updated_content, new_content);
+    }
+
+    #[test]
+    fn test_encode_near_matches_anywhere_in_added_data() {
+        let old_content = pseudo_random(4 * 256);
+        let mut block = old_content[256..512].to_vec();
+        block[10] ^= 0xff;
+        let added = [&[0xaa; 3][..], &block].concat();
+        let delta = Delta {
+            tokens: vec![Added(Cow::Borrowed(&added))],
+            chunk_size: 256,
+            version: DEFAULT_VERSION.to_string(),
+        };
+
+        let expected: Vec<DeltaToken<_>> = vec![
+            Added(Cow::Borrowed(&[0xaa; 3])),
+            Diff(
+                1,
+                Md5Sum::hash(&old_content[256..512]),
+                vec![(10, vec![block[10]])],
+            ),
+        ];
+        assert_eq!(
+            encode_near_matches::<RollingAdler32, Md5Sum>(delta, &old_content).tokens,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_cancelled_diff() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let result = diff_with_options::<RollingAdler32, Md5Sum>(
+            &[1, 2, 3],
+            &[1, 2, 4],
+            &DiffOptions::default(),
+            &NoProgress,
+            &cancel,
+        );
+        assert!(matches!(result, Err(DeltaError::Cancelled(_))));
+    }
+}
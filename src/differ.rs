@@ -0,0 +1,303 @@
+//!
+//! A [Differ] generates signatures and deltas and applies patches with one set of algorithms and
+//! policies, picked once with a [DiffConfig] instead of repeated at every call
+//!
+//! ```
+//! use rolling_in_the_diff::differ::Differ;
+//! use rolling_in_the_diff::signature_generation::ChunkSizePolicy;
+//!
+//! let old_content = b"the quick brown fox jumps over the lazy dog";
+//! let new_content = b"the quick brown cat jumps over the lazy dog";
+//!
+//! let differ = Differ::builder()
+//!     .chunk_size(ChunkSizePolicy::Fixed(8))
+//!     .omit_removed(true)
+//!     .build();
+//! let signature = differ.signature(old_content).unwrap();
+//! let delta = differ.delta(&signature, new_content).unwrap();
+//!
+//! let mut updated_content = Vec::new();
+//! differ.patch(old_content, delta, &mut updated_content).unwrap();
+//! assert_eq!(updated_content, new_content);
+//! ```
+//!
+
+use std::hash::Hash;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cancel::CancellationToken;
+use crate::decode::{decode_delta, decode_signature, DecodeError, DecodeLimits};
+use crate::delta_generation::{try_generate_delta_with_options, Delta, DeltaError, DeltaOptions};
+use crate::patch::{patch_with_options, PatchError, PatchOptions, Verification};
+use crate::progress::{NoProgress, ProgressObserver};
+use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+use crate::rolling_checksum::RollingChecksum;
+use crate::signature_generation::{
+    cancellable_signature, read_to_end, ChunkSizePolicy, SignatureError,
+};
+use crate::signature_index::SignatureIndex;
+use crate::strong_hash::md5::Md5Sum;
+use crate::strong_hash::StrongHash;
+use crate::Signature;
+
+///
+/// Builds a [Differ], see [Differ::builder]
+///
+/// `R` and `S` are the rolling checksum and the strong hash, the ones of the command line tool
+/// unless changed with [DiffConfig::algorithms].
+///
+pub struct DiffConfig<R = RollingAdler32, S = Md5Sum> {
+    chunk_size_policy: ChunkSizePolicy,
+    delta_options: DeltaOptions,
+    patch_options: PatchOptions,
+    limits: DecodeLimits,
+    progress: Arc<dyn ProgressObserver + Send>,
+    cancel: CancellationToken,
+    algorithms: PhantomData<fn() -> (R, S)>,
+}
+
+impl<R, S> DiffConfig<R, S> {
+    /// Switches to another rolling checksum and strong hash, keeping everything else
+    pub fn algorithms<R2, S2>(self) -> DiffConfig<R2, S2> {
+        DiffConfig {
+            chunk_size_policy: self.chunk_size_policy,
+            delta_options: self.delta_options,
+            patch_options: self.patch_options,
+            limits: self.limits,
+            progress: self.progress,
+            cancel: self.cancel,
+            algorithms: PhantomData,
+        }
+    }
+
+    pub fn chunk_size(mut self, chunk_size_policy: ChunkSizePolicy) -> Self {
+        self.chunk_size_policy = chunk_size_policy;
+        self
+    }
+
+    pub fn delta_options(mut self, delta_options: DeltaOptions) -> Self {
+        self.delta_options = delta_options;
+        self
+    }
+
+    /// See [DeltaOptions::omit_removed]
+    pub fn omit_removed(mut self, omit_removed: bool) -> Self {
+        self.delta_options.omit_removed = omit_removed;
+        self
+    }
+
+    /// The limits signatures and deltas are decoded with
+    pub fn limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn verification(mut self, verification: Verification) -> Self {
+        self.patch_options.verification = verification;
+        self
+    }
+
+    /// Reports the progress of every operation to `progress`
+    pub fn progress(mut self, progress: Arc<dyn ProgressObserver + Send>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Every operation stops with a cancellation error once `cancel` is cancelled
+    pub fn cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn build(self) -> Differ<R, S> {
+        Differ { config: self }
+    }
+}
+
+pub struct Differ<R = RollingAdler32, S = Md5Sum> {
+    config: DiffConfig<R, S>,
+}
+
+impl Differ {
+    /// A configuration starting from the defaults of the command line tool
+    pub fn builder() -> DiffConfig {
+        DiffConfig {
+            chunk_size_policy: ChunkSizePolicy::default(),
+            delta_options: DeltaOptions::default(),
+            patch_options: PatchOptions::default(),
+            limits: DecodeLimits::default(),
+            progress: Arc::new(NoProgress),
+            cancel: CancellationToken::new(),
+            algorithms: PhantomData,
+        }
+    }
+}
+
+impl Default for Differ {
+    fn default() -> Self {
+        Differ::builder().build()
+    }
+}
+
+impl<R, S> Differ<R, S>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash + Send + Copy,
+    S: StrongHash,
+    <S as StrongHash>::HashType: Send,
+{
+    pub fn signature(
+        &self,
+        content: &[u8],
+    ) -> Result<Signature<R::ChecksumType, S::HashType>, SignatureError> {
+        let config = &self.config;
+        Ok(cancellable_signature::<R, S>(
+            content,
+            &config.chunk_size_policy,
+            config.progress.as_ref(),
+            &config.cancel,
+        )?)
+    }
+
+    /// The signature of everything `content` yields, which is read into memory first
+    pub fn signature_from_reader(
+        &self,
+        content: &mut impl Read,
+    ) -> Result<Signature<R::ChecksumType, S::HashType>, SignatureError> {
+        let content = read_to_end(content, &self.config.cancel)?;
+        self.signature(&content)
+    }
+
+    pub fn delta<'a>(
+        &self,
+        old_signature: &impl SignatureIndex<R::ChecksumType, S::HashType>,
+        new_content: &'a [u8],
+    ) -> Result<Delta<'a, S::HashType>, DeltaError> {
+        let config = &self.config;
+        try_generate_delta_with_options::<R, S>(
+            old_signature,
+            new_content,
+            &config.delta_options,
+            config.progress.as_ref(),
+            &config.cancel,
+        )
+    }
+
+    /// Applies `delta` on top of `old_content` into `out`, see [patch_with_options]
+    pub fn patch<W: Write>(
+        &self,
+        old_content: &[u8],
+        delta: Delta<S::HashType>,
+        out: &mut W,
+    ) -> Result<(), PatchError> {
+        let config = &self.config;
+        patch_with_options::<S, W>(
+            old_content,
+            delta,
+            out,
+            &config.patch_options,
+            config.progress.as_ref(),
+            &config.cancel,
+        )
+    }
+
+    pub fn decode_signature<'de>(
+        &self,
+        bytes: &'de [u8],
+    ) -> Result<Signature<R::ChecksumType, S::HashType>, DecodeError>
+    where
+        <R as RollingChecksum>::ChecksumType: Serialize + Deserialize<'de>,
+        <S as StrongHash>::HashType: Serialize + Deserialize<'de>,
+    {
+        decode_signature(bytes, &self.config.limits)
+    }
+
+    pub fn decode_delta<'de>(
+        &self,
+        bytes: &'de [u8],
+    ) -> Result<Delta<'de, S::HashType>, DecodeError>
+    where
+        <S as StrongHash>::HashType: Serialize + Deserialize<'de>,
+    {
+        decode_delta(bytes, &self.config.limits)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use test_case::test_case;
+
+    use crate::delta_generation::DeltaToken;
+    use crate::format::{encode, Format, Kind};
+
+    use super::*;
+
+    fn contents() -> (Vec<u8>, Vec<u8>) {
+        let old_content: Vec<u8> = (0..1 << 14).map(|x: u32| (x * 31 % 251) as u8).collect();
+        let mut new_content = old_content.clone();
+        new_content[1000..1100].fill(42);
+        (old_content, new_content)
+    }
+
+    #[test_case(ChunkSizePolicy::default(), false; "with the default chunk size")]
+    #[test_case(ChunkSizePolicy::MaxChunkCount(4), false; "with few chunks")]
+    #[test_case(ChunkSizePolicy::Fixed(100), true; "with a fixed chunk size and no removed chunks")]
+    fn test_round_trip(chunk_size_policy: ChunkSizePolicy, omit_removed: bool) {
+        let (old_content, new_content) = contents();
+        let differ = Differ::builder()
+            .chunk_size(chunk_size_policy)
+            .omit_removed(omit_removed)
+            .build();
+
+        let signature = differ.signature(&old_content).unwrap();
+        let mut signature_bytes = Vec::new();
+        encode(
+            &signature,
+            Kind::Signature,
+            Format::Bincode,
+            &mut signature_bytes,
+        )
+        .unwrap();
+        let signature = differ.decode_signature(&signature_bytes).unwrap();
+        assert_eq!(
+            signature.chunk_size(),
+            chunk_size_policy.chunk_size::<u32, [u8; 16]>(old_content.len())
+        );
+
+        let delta = differ.delta(&signature, &new_content).unwrap();
+        let has_removed = delta
+            .tokens
+            .iter()
+            .any(|token| matches!(token, DeltaToken::Removed(_)));
+        assert_eq!(has_removed, !omit_removed);
+        let mut delta_bytes = Vec::new();
+        encode(&delta, Kind::Delta, Format::Bincode, &mut delta_bytes).unwrap();
+
+        let mut patched_content = Vec::new();
+        differ
+            .patch(
+                &old_content,
+                differ.decode_delta(&delta_bytes).unwrap(),
+                &mut patched_content,
+            )
+            .unwrap();
+        assert_eq!(patched_content, new_content);
+    }
+
+    #[test_case(Verification::ReusedChunks, true; "when verifying the reused chunks")]
+    #[test_case(Verification::None, false; "when trusting the old content")]
+    fn test_patching_other_content(verification: Verification, expect_error: bool) {
+        let (old_content, new_content) = contents();
+        let differ = Differ::builder().verification(verification).build();
+        let signature = differ.signature(&old_content).unwrap();
+        let delta = differ.delta(&signature, &new_content).unwrap();
+
+        let other_content = vec![0; old_content.len()];
+        let result = differ.patch(&other_content, delta, &mut Vec::new());
+        assert_eq!(result.is_err(), expect_error);
+    }
+}
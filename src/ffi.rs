@@ -0,0 +1,454 @@
+//!
+//! C API for embedding the library, with the rolling Adler-32 and MD5 the command line tool uses
+//!
+//! Signatures are opaque handles; deltas and patched content are handed out as [RitdBuffer]s in the
+//! same encoding the command line tool reads and writes. Every function returns a [RitdStatus] and
+//! the message of the last error on the calling thread is available through
+//! [ritd_last_error_message].
+//!
+//! The header in `include/` is generated with cbindgen (see `cbindgen.toml` and `make header`).
+//!
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CString};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::fd::FromRawFd;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::{ptr, slice};
+
+use thiserror::Error;
+
+use crate::cancel::CancellationToken;
+use crate::decode::{decode_delta, decode_signature, DecodeLimits};
+use crate::delta_generation::{try_generate_delta, DeltaError};
+use crate::format::{encode, Format, FormatError, Kind};
+use crate::patch::{patch, PatchError};
+use crate::progress::NoProgress;
+use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+use crate::signature_generation::{generate_signature, try_generate_signature, SignatureError};
+use crate::strong_hash::md5::Md5Sum;
+use crate::strong_hash::StrongHash;
+use crate::Signature;
+
+type Md5Hash = <Md5Sum as StrongHash>::HashType;
+
+/// The signature of some content
+pub struct RitdSignature(Signature<u32, Md5Hash>);
+
+/// Bytes owned by the library, to be released with [ritd_buffer_free]
+#[repr(C)]
+pub struct RitdBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RitdStatus {
+    Ok = 0,
+    /// A null pointer where there must be something
+    InvalidArgument,
+    /// Reading or writing a file descriptor failed
+    Io,
+    /// The signature or delta is malformed or over the decoding limits
+    Malformed,
+    /// The signature refers to chunks it doesn't have
+    InvalidSignature,
+    /// The delta refers to a chunk past the end of the old content
+    ChunkOutOfBound,
+    /// The delta copies a range past the end of the old content
+    RangeOutOfBound,
+    /// The delta refers back past the output written so far
+    BackReferenceOutOfBound,
+    /// The delta has a diff that doesn't fit its chunk
+    DiffLengthMismatch,
+    /// The old content isn't the one the delta was generated against
+    ChunkHashMismatch,
+    Cancelled,
+    /// A bug in the library
+    Internal,
+}
+
+#[derive(Error, Debug)]
+enum FfiError {
+    #[error("{0} must not be null")]
+    NullArgument(&'static str),
+    #[error(transparent)]
+    Library(#[from] crate::Error),
+    #[error("failed to read or write a file descriptor")]
+    Io(#[from] std::io::Error),
+    #[error("failed to encode")]
+    Encoding(#[from] FormatError),
+    #[error("panicked: {0}")]
+    Panic(String),
+}
+
+impl FfiError {
+    fn status(&self) -> RitdStatus {
+        match self {
+            FfiError::NullArgument(_) => RitdStatus::InvalidArgument,
+            FfiError::Io(_) | FfiError::Encoding(_) => RitdStatus::Io,
+            FfiError::Panic(_) => RitdStatus::Internal,
+            FfiError::Library(error) => match error {
+                crate::Error::Signature(SignatureError::Io(_)) => RitdStatus::Io,
+                crate::Error::Signature(SignatureError::Cancelled(_)) => RitdStatus::Cancelled,
+                crate::Error::Decode(_) => RitdStatus::Malformed,
+                crate::Error::Delta(DeltaError::Cancelled(_)) => RitdStatus::Cancelled,
+                crate::Error::Delta(_) => RitdStatus::InvalidSignature,
+                crate::Error::Patch(error) => match error {
+                    PatchError::ChunkOutOfBound { .. } => RitdStatus::ChunkOutOfBound,
+                    PatchError::RangeOutOfBound { .. } => RitdStatus::RangeOutOfBound,
+                    PatchError::BackReferenceOutOfBound { .. } => {
+                        RitdStatus::BackReferenceOutOfBound
+                    }
+                    PatchError::DiffLengthMismatch { .. } => RitdStatus::DiffLengthMismatch,
+                    PatchError::ChunkHashMismatch { .. } | PatchError::RangeHashMismatch { .. } => {
+                        RitdStatus::ChunkHashMismatch
+                    }
+                    PatchError::OutputFailure(_) => RitdStatus::Io,
+                    PatchError::Cancelled(_) => RitdStatus::Cancelled,
+                    PatchError::Decode(_) => RitdStatus::Malformed,
+                },
+                // the other operations aren't part of the C API
+                _ => RitdStatus::Internal,
+            },
+        }
+    }
+
+    /// The error followed by its sources
+    fn message(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source {
+            message.push_str(": ");
+            message.push_str(&error.to_string());
+            source = error.source();
+        }
+        message
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn run(operation: impl FnOnce() -> Result<(), FfiError>) -> RitdStatus {
+    let result = catch_unwind(AssertUnwindSafe(operation)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(FfiError::Panic(message))
+    });
+    let (status, message) = match result {
+        Ok(()) => (RitdStatus::Ok, None),
+        Err(error) => (
+            error.status(),
+            // messages don't have nul bytes in the middle, unless some path or input sneaks one in
+            Some(CString::new(error.message().replace('\0', " ")).unwrap_or_default()),
+        ),
+    };
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+    status
+}
+
+/// A null `data` is fine for empty content
+unsafe fn input<'a>(data: *const u8, len: usize, name: &'static str) -> Result<&'a [u8], FfiError> {
+    match (data.is_null(), len) {
+        (true, 0) => Ok(&[]),
+        (true, _) => Err(FfiError::NullArgument(name)),
+        (false, _) => Ok(slice::from_raw_parts(data, len)),
+    }
+}
+
+unsafe fn output<'a, T>(out: *mut T, name: &'static str) -> Result<&'a mut T, FfiError> {
+    out.as_mut().ok_or(FfiError::NullArgument(name))
+}
+
+unsafe fn signature<'a>(signature: *const RitdSignature) -> Result<&'a RitdSignature, FfiError> {
+    signature
+        .as_ref()
+        .ok_or(FfiError::NullArgument("signature"))
+}
+
+/// A file over `fd` that leaves it open when dropped
+unsafe fn borrow_fd(fd: c_int) -> ManuallyDrop<File> {
+    ManuallyDrop::new(File::from_raw_fd(fd))
+}
+
+fn read_fd(fd: c_int) -> Result<Vec<u8>, FfiError> {
+    let mut content = Vec::new();
+    unsafe { borrow_fd(fd) }.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+fn into_buffer(bytes: Vec<u8>) -> RitdBuffer {
+    let bytes = Box::into_raw(bytes.into_boxed_slice());
+    RitdBuffer {
+        data: bytes as *mut u8,
+        len: bytes.len(),
+    }
+}
+
+///
+/// Generates the signature of `len` bytes at `content`
+///
+/// # Safety
+///
+/// `content` must point to `len` readable bytes (or may be null when `len` is 0) and `out` to a
+/// writable pointer that receives a signature to release with [ritd_signature_free].
+///
+#[no_mangle]
+pub unsafe extern "C" fn ritd_signature_new(
+    content: *const u8,
+    len: usize,
+    out: *mut *mut RitdSignature,
+) -> RitdStatus {
+    run(|| {
+        let content = input(content, len, "content")?;
+        let out = output(out, "out")?;
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(content);
+        *out = Box::into_raw(Box::new(RitdSignature(signature)));
+        Ok(())
+    })
+}
+
+///
+/// Generates the signature of what's left to read from `fd`, which is left open
+///
+/// # Safety
+///
+/// `fd` must be an open file descriptor and `out` must point to a writable pointer that receives a
+/// signature to release with [ritd_signature_free].
+///
+#[no_mangle]
+pub unsafe extern "C" fn ritd_signature_new_from_fd(
+    fd: c_int,
+    out: *mut *mut RitdSignature,
+) -> RitdStatus {
+    run(|| {
+        let out = output(out, "out")?;
+        let signature = try_generate_signature::<RollingAdler32, Md5Sum, _>(
+            &mut *borrow_fd(fd),
+            &NoProgress,
+            &CancellationToken::new(),
+        )
+        .map_err(crate::Error::from)?;
+        *out = Box::into_raw(Box::new(RitdSignature(signature)));
+        Ok(())
+    })
+}
+
+///
+/// Decodes a signature written by [ritd_signature_encode] or the command line tool
+///
+/// # Safety
+///
+/// `bytes` must point to `len` readable bytes and `out` to a writable pointer that receives a
+/// signature to release with [ritd_signature_free].
+///
+#[no_mangle]
+pub unsafe extern "C" fn ritd_signature_decode(
+    bytes: *const u8,
+    len: usize,
+    out: *mut *mut RitdSignature,
+) -> RitdStatus {
+    run(|| {
+        let bytes = input(bytes, len, "bytes")?;
+        let out = output(out, "out")?;
+        let signature =
+            decode_signature(bytes, &DecodeLimits::default()).map_err(crate::Error::from)?;
+        *out = Box::into_raw(Box::new(RitdSignature(signature)));
+        Ok(())
+    })
+}
+
+///
+/// Encodes `signature` into a buffer to release with [ritd_buffer_free]
+///
+/// # Safety
+///
+/// `signature` must come from this library and not be freed yet, `out` must point to a writable
+/// [RitdBuffer].
+///
+#[no_mangle]
+pub unsafe extern "C" fn ritd_signature_encode(
+    signature: *const RitdSignature,
+    out: *mut RitdBuffer,
+) -> RitdStatus {
+    run(|| {
+        let signature = self::signature(signature)?;
+        let out = output(out, "out")?;
+        let mut encoded = Vec::new();
+        encode(&signature.0, Kind::Signature, Format::Bincode, &mut encoded)?;
+        *out = into_buffer(encoded);
+        Ok(())
+    })
+}
+
+///
+/// Releases a signature, null is ignored
+///
+/// # Safety
+///
+/// `signature` must come from this library and not be freed yet.
+///
+#[no_mangle]
+pub unsafe extern "C" fn ritd_signature_free(signature: *mut RitdSignature) {
+    if !signature.is_null() {
+        drop(Box::from_raw(signature));
+    }
+}
+
+///
+/// Generates the delta between `signature` and the `len` bytes at `new_content` into a buffer to
+/// release with [ritd_buffer_free]
+///
+/// # Safety
+///
+/// `signature` must come from this library and not be freed yet, `new_content` must point to `len`
+/// readable bytes and `out` to a writable [RitdBuffer].
+///
+#[no_mangle]
+pub unsafe extern "C" fn ritd_delta_new(
+    signature: *const RitdSignature,
+    new_content: *const u8,
+    len: usize,
+    out: *mut RitdBuffer,
+) -> RitdStatus {
+    run(|| {
+        let signature = self::signature(signature)?;
+        let new_content = input(new_content, len, "new_content")?;
+        let out = output(out, "out")?;
+        let delta = try_generate_delta::<RollingAdler32, Md5Sum>(&signature.0, new_content)
+            .map_err(crate::Error::from)?;
+        let mut encoded = Vec::new();
+        encode(&delta, Kind::Delta, Format::Bincode, &mut encoded)?;
+        *out = into_buffer(encoded);
+        Ok(())
+    })
+}
+
+///
+/// Generates the delta between `signature` and what's left to read from `new_fd` into `delta_fd`,
+/// both are left open
+///
+/// # Safety
+///
+/// `signature` must come from this library and not be freed yet, the file descriptors must be
+/// open.
+///
+#[no_mangle]
+pub unsafe extern "C" fn ritd_delta_new_from_fd(
+    signature: *const RitdSignature,
+    new_fd: c_int,
+    delta_fd: c_int,
+) -> RitdStatus {
+    run(|| {
+        let signature = self::signature(signature)?;
+        let new_content = read_fd(new_fd)?;
+        let delta = try_generate_delta::<RollingAdler32, Md5Sum>(&signature.0, &new_content)
+            .map_err(crate::Error::from)?;
+        let delta_file = borrow_fd(delta_fd);
+        let mut delta_file = BufWriter::new(&*delta_file);
+        encode(&delta, Kind::Delta, Format::Bincode, &mut delta_file)?;
+        delta_file.flush()?;
+        Ok(())
+    })
+}
+
+///
+/// Applies the `delta_len` bytes of delta at `delta` on top of the `old_len` bytes at
+/// `old_content` into a buffer to release with [ritd_buffer_free]
+///
+/// # Safety
+///
+/// `old_content` and `delta` must point to `old_len` and `delta_len` readable bytes, `out` to a
+/// writable [RitdBuffer].
+///
+#[no_mangle]
+pub unsafe extern "C" fn ritd_patch(
+    old_content: *const u8,
+    old_len: usize,
+    delta: *const u8,
+    delta_len: usize,
+    out: *mut RitdBuffer,
+) -> RitdStatus {
+    run(|| {
+        let old_content = input(old_content, old_len, "old_content")?;
+        let delta = input(delta, delta_len, "delta")?;
+        let out = output(out, "out")?;
+        let delta = decode_delta(delta, &DecodeLimits::default()).map_err(crate::Error::from)?;
+        let mut patched_content = Vec::new();
+        patch::<Md5Sum, _>(old_content, delta, &mut patched_content).map_err(crate::Error::from)?;
+        *out = into_buffer(patched_content);
+        Ok(())
+    })
+}
+
+///
+/// Applies the delta left to read from `delta_fd` on top of what's left to read from `old_fd` into
+/// `out_fd`, all of them are left open
+///
+/// Part of the output may have been written when it fails.
+///
+/// # Safety
+///
+/// The file descriptors must be open.
+///
+#[no_mangle]
+pub unsafe extern "C" fn ritd_patch_from_fd(
+    old_fd: c_int,
+    delta_fd: c_int,
+    out_fd: c_int,
+) -> RitdStatus {
+    run(|| {
+        let old_content = read_fd(old_fd)?;
+        let delta = read_fd(delta_fd)?;
+        let delta = decode_delta(&delta, &DecodeLimits::default()).map_err(crate::Error::from)?;
+        let out_file = borrow_fd(out_fd);
+        let mut out = BufWriter::new(&*out_file);
+        patch::<Md5Sum, _>(&old_content, delta, &mut out).map_err(crate::Error::from)?;
+        out.flush()?;
+        Ok(())
+    })
+}
+
+///
+/// Releases the bytes of a buffer and empties it, an empty buffer is ignored
+///
+/// # Safety
+///
+/// `buffer` must be null or point to a buffer filled by this library.
+///
+#[no_mangle]
+pub unsafe extern "C" fn ritd_buffer_free(buffer: *mut RitdBuffer) {
+    if let Some(buffer) = buffer.as_mut() {
+        if !buffer.data.is_null() {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+                buffer.data,
+                buffer.len,
+            )));
+        }
+        buffer.data = ptr::null_mut();
+        buffer.len = 0;
+    }
+}
+
+///
+/// The message of the last error on the calling thread, null after a success
+///
+/// The message stays valid until the next call on the same thread.
+///
+#[no_mangle]
+pub extern "C" fn ritd_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
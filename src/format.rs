@@ -0,0 +1,422 @@
+//!
+//! The serialization formats signatures and deltas can be encoded with
+//!
+//! Bincode and JSON are always available - the command line tool needs serde_json anyway - CBOR
+//! and MessagePack come with the `cbor` and `msgpack` features. [encode] starts the output with a
+//! [Header] recording the format and what kind of value follows, which is how the decoding
+//! functions of [crate::decode] pick the format back.
+//! Everything this version writes has a header. Signatures and deltas without one are read in the
+//! bincode layouts of the first release, see [legacy]. Tree signatures and deltas didn't exist
+//! back then and always need a header.
+//!
+//! ```
+//! use rolling_in_the_diff::decode::{decode_signature, DecodeLimits};
+//! use rolling_in_the_diff::format::{encode, Format, Kind};
+//! use rolling_in_the_diff::rolling_checksum::rolling_adler32::RollingAdler32;
+//! use rolling_in_the_diff::signature_generation::generate_signature;
+//! use rolling_in_the_diff::strong_hash::md5::Md5Sum;
+//! use rolling_in_the_diff::Signature;
+//!
+//! let signature = generate_signature::<RollingAdler32, Md5Sum>(b"the quick brown fox");
+//! let mut encoded = Vec::new();
+//! encode(&signature, Kind::Signature, Format::Bincode, &mut encoded).unwrap();
+//!
+//! let decoded: Signature<u32, [u8; 16]> =
+//!     decode_signature(&encoded, &DecodeLimits::default()).unwrap();
+//! assert_eq!(decoded.chunk_count(), signature.chunk_count());
+//! ```
+//!
+
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+#[cfg(feature = "msgpack")]
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::SignatureChunksError;
+
+pub(crate) mod legacy;
+
+/// The start of every encoded value with a [Header]
+pub const MAGIC: &[u8; 8] = b"RITDENCD";
+
+/// The length of an encoded [Header]
+pub const HEADER_LEN: usize = MAGIC.len() + 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Bincode = 0,
+    Cbor = 1,
+    MessagePack = 2,
+    Json = 3,
+}
+
+impl Format {
+    /// Whether this build can encode and decode the format, see the features of the crate
+    pub fn is_supported(self) -> bool {
+        match self {
+            Format::Bincode => true,
+            Format::Cbor => cfg!(feature = "cbor"),
+            Format::MessagePack => cfg!(feature = "msgpack"),
+            Format::Json => true,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, FormatError> {
+        match byte {
+            0 => Ok(Format::Bincode),
+            1 => Ok(Format::Cbor),
+            2 => Ok(Format::MessagePack),
+            3 => Ok(Format::Json),
+            _ => Err(FormatError::UnknownFormat(byte)),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(Format::Bincode),
+            "cbor" => Ok(Format::Cbor),
+            "msgpack" => Ok(Format::MessagePack),
+            "json" => Ok(Format::Json),
+            _ => Err(format!(
+                "unknown format {}, expected bincode, cbor, msgpack or json",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Bincode => "bincode",
+            Format::Cbor => "cbor",
+            Format::MessagePack => "msgpack",
+            Format::Json => "json",
+        })
+    }
+}
+
+/// What an encoded value is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Signature = 0,
+    Delta = 1,
+    TreeSignature = 2,
+    TreeDelta = 3,
+}
+
+impl Kind {
+    fn from_byte(byte: u8) -> Result<Self, FormatError> {
+        match byte {
+            0 => Ok(Kind::Signature),
+            1 => Ok(Kind::Delta),
+            2 => Ok(Kind::TreeSignature),
+            3 => Ok(Kind::TreeDelta),
+            _ => Err(FormatError::UnknownKind(byte)),
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "signature" => Ok(Kind::Signature),
+            "delta" => Ok(Kind::Delta),
+            "tree-signature" => Ok(Kind::TreeSignature),
+            "tree-delta" => Ok(Kind::TreeDelta),
+            _ => Err(format!(
+                "unknown kind {}, expected signature, delta, tree-signature or tree-delta",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Signature => "signature",
+            Kind::Delta => "delta",
+            Kind::TreeSignature => "tree signature",
+            Kind::TreeDelta => "tree delta",
+        })
+    }
+}
+
+///
+/// The start of an encoded value: [MAGIC], the format byte and the kind byte
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub format: Format,
+    pub kind: Kind,
+}
+
+impl Header {
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[self.format as u8, self.kind as u8])
+    }
+
+    ///
+    /// The header `bytes` start with and the bytes after it, `None` when there's no header
+    ///
+    /// The format and kind are only known to this version when they parse, whether the format
+    /// is supported by this build is up to the caller.
+    ///
+    pub fn parse(bytes: &[u8]) -> Result<Option<(Header, &[u8])>, FormatError> {
+        if !bytes.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        match bytes[MAGIC.len()..] {
+            [format, kind, ..] => Ok(Some((
+                Header {
+                    format: Format::from_byte(format)?,
+                    kind: Kind::from_byte(kind)?,
+                },
+                &bytes[HEADER_LEN..],
+            ))),
+            _ => Err(FormatError::TruncatedHeader),
+        }
+    }
+}
+
+///
+/// Writes the [Header] and then `value` encoded with `format` into `out`
+///
+pub fn encode<T, W>(value: &T, kind: Kind, format: Format, out: &mut W) -> Result<(), FormatError>
+where
+    T: Serialize + ?Sized,
+    W: Write,
+{
+    if !format.is_supported() {
+        return Err(FormatError::Unsupported(format));
+    }
+    Header { format, kind }.write(out)?;
+    match format {
+        Format::Bincode => bincode2::serialize_into(out, value)?,
+        #[cfg(feature = "cbor")]
+        Format::Cbor => ciborium::into_writer(value, out)?,
+        #[cfg(feature = "msgpack")]
+        // with the field names, so that the output reads like the JSON one
+        Format::MessagePack => rmp_serde::encode::write_named(out, value)?,
+        Format::Json => serde_json::to_writer(out, value)?,
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("unsupported formats are rejected above"),
+    }
+    Ok(())
+}
+
+///
+/// Decodes `bytes` that hold nothing but a value encoded with `format`
+///
+/// Bincode length prefixes can't claim more than `bytes.len()`. Bytes left after the value are
+/// an error.
+///
+pub(crate) fn deserialize<'de, T: Serialize + Deserialize<'de>>(
+    bytes: &'de [u8],
+    format: Format,
+) -> Result<T, FormatError> {
+    if !format.is_supported() {
+        return Err(FormatError::Unsupported(format));
+    }
+    Ok(match format {
+        Format::Bincode => deserialize_bincode(bytes)?,
+        // ciborium only deserializes owned values, going through its value keeps the borrowing
+        // types (the added data of the deltas) decodable - at the cost of holding the value tree
+        // next to the result, see crate::decode::DecodeLimits
+        #[cfg(feature = "cbor")]
+        Format::Cbor => {
+            let mut rest = bytes;
+            let value = ciborium::from_reader::<ciborium::Value, _>(&mut rest)?;
+            trailing_bytes(rest)?;
+            value.deserialized()?
+        }
+        // the deserializer borrowing from `bytes` doesn't tell where it stopped, skipping the value
+        // with one that reads from `rest` does
+        #[cfg(feature = "msgpack")]
+        Format::MessagePack => {
+            let mut rest = bytes;
+            IgnoredAny::deserialize(&mut rmp_serde::Deserializer::new(&mut rest))?;
+            trailing_bytes(rest)?;
+            rmp_serde::from_slice(bytes)?
+        }
+        Format::Json => {
+            let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+            let value = T::deserialize(&mut deserializer)?;
+            deserializer.end()?;
+            value
+        }
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("unsupported formats are rejected above"),
+    })
+}
+
+///
+/// Decodes `bytes` that hold nothing but a bincode value
+///
+/// Bincode doesn't tell where it stopped reading, but it has a single encoding for each value, so
+/// encoding the value again gives the length that was read.
+///
+pub(crate) fn deserialize_bincode<'de, T: Serialize + Deserialize<'de>>(
+    bytes: &'de [u8],
+) -> Result<T, FormatError> {
+    let value: T = bincode2::config()
+        .limit(bytes.len() as u64)
+        .deserialize(bytes)?;
+    let read = bincode2::serialized_size(&value)?;
+    match bytes.len() as u64 - read.min(bytes.len() as u64) {
+        0 => Ok(value),
+        trailing => Err(FormatError::TrailingBytes(trailing)),
+    }
+}
+
+/// Fails when there's something left of the input after the value
+#[cfg(any(feature = "cbor", feature = "msgpack"))]
+fn trailing_bytes(rest: &[u8]) -> Result<(), FormatError> {
+    match rest.len() {
+        0 => Ok(()),
+        trailing => Err(FormatError::TrailingBytes(trailing as u64)),
+    }
+}
+
+///
+/// (De)serialization of byte fields that borrow from the input when the format allows it
+///
+/// Bincode writes them like any other list of bytes, CBOR and MessagePack as byte strings and
+/// JSON as arrays of numbers. Use with `#[serde(borrow, with = "crate::format::bytes")]`.
+///
+pub(crate) mod bytes {
+    use std::borrow::Cow;
+    use std::fmt::{self, Formatter};
+
+    use serde::de::{SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub(crate) fn serialize<Ser: Serializer>(
+        bytes: &[u8],
+        serializer: Ser,
+    ) -> Result<Ser::Ok, Ser::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub(crate) fn deserialize<'de: 'a, 'a, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Cow<'a, [u8]>, D::Error> {
+        deserializer.deserialize_bytes(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Cow<'de, [u8]>;
+
+        fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+            formatter.write_str("bytes")
+        }
+
+        fn visit_borrowed_bytes<E>(self, bytes: &'de [u8]) -> Result<Self::Value, E> {
+            Ok(Cow::Borrowed(bytes))
+        }
+
+        fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+            Ok(Cow::Owned(bytes.to_vec()))
+        }
+
+        fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(Cow::Owned(bytes))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(1 << 16));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(Cow::Owned(bytes))
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FormatError {
+    #[error("the {0} format is not built in, see the features of the crate")]
+    Unsupported(Format),
+    #[error("unknown format {0} in the header")]
+    UnknownFormat(u8),
+    #[error("unknown kind {0} in the header")]
+    UnknownKind(u8),
+    #[error("the header is cut off")]
+    TruncatedHeader,
+    #[error("a {0} needs a header")]
+    MissingHeader(Kind),
+    #[error("only bincode can be read a token at a time, not {0}")]
+    NotStreamable(Format),
+    #[error("{0} bytes left after the encoded value")]
+    TrailingBytes(u64),
+    #[error("invalid signature in the layout of the first release")]
+    LegacySignature(#[from] SignatureChunksError),
+    #[error("bincode error")]
+    Bincode(#[from] bincode2::Error),
+    #[cfg(feature = "cbor")]
+    #[error("CBOR encoding error")]
+    CborEncode(#[from] ciborium::ser::Error<io::Error>),
+    #[cfg(feature = "cbor")]
+    #[error("CBOR decoding error")]
+    CborDecode(#[from] ciborium::de::Error<io::Error>),
+    #[cfg(feature = "cbor")]
+    #[error("unexpected CBOR value")]
+    CborValue(#[from] ciborium::value::Error),
+    #[cfg(feature = "msgpack")]
+    #[error("MessagePack encoding error")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+    #[cfg(feature = "msgpack")]
+    #[error("MessagePack decoding error")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+    #[error("JSON error")]
+    Json(#[from] serde_json::Error),
+    #[error("IO error")]
+    Io(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case(b"" => None; "when empty")]
+    #[test_case(b"\x08\0\0\0\0\0\0\0" => None; "when headerless")]
+    #[test_case(b"RITDENCD\x02\x01rest" => Some((Header { format: Format::MessagePack, kind: Kind::Delta }, b"rest".to_vec())); "when there's a header")]
+    fn test_parse_header(bytes: &[u8]) -> Option<(Header, Vec<u8>)> {
+        Header::parse(bytes)
+            .unwrap()
+            .map(|(header, rest)| (header, rest.to_vec()))
+    }
+
+    #[test_case(b"RITDENCD\x00" => matches FormatError::TruncatedHeader; "when cut off")]
+    #[test_case(b"RITDENCD\x09\x00" => matches FormatError::UnknownFormat(9); "when the format is unknown")]
+    #[test_case(b"RITDENCD\x00\x09" => matches FormatError::UnknownKind(9); "when the kind is unknown")]
+    fn test_parse_invalid_header(bytes: &[u8]) -> FormatError {
+        Header::parse(bytes).unwrap_err()
+    }
+
+    #[test_case("bincode", Format::Bincode)]
+    #[test_case("cbor", Format::Cbor)]
+    #[test_case("msgpack", Format::MessagePack)]
+    #[test_case("json", Format::Json)]
+    fn test_format_names(name: &str, format: Format) {
+        assert_eq!(name.parse::<Format>().unwrap(), format);
+        assert_eq!(format.to_string(), name);
+    }
+}
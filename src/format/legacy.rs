@@ -0,0 +1,231 @@
+//!
+//! The layouts the first release wrote signatures and deltas in, bincode without a header
+//!
+//! Signatures were a map from weak checksum to the strong hashes and chunk numbers sharing it,
+//! deltas a list of tokens followed by the chunk size and the version. Only the tokens that
+//! existed back then can appear.
+//!
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+use serde::{Deserialize, Serialize};
+
+use crate::decode::DecodeError;
+use crate::delta_generation::{Delta, DeltaToken};
+use crate::format::deserialize_bincode;
+use crate::{ChunkNumber, ChunkRecord, Signature};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LegacySignature<W, S>
+where
+    W: Eq + Hash,
+{
+    pub(crate) checksum_to_hashes: HashMap<W, Vec<(S, ChunkNumber)>>,
+    pub(crate) chunk_size: usize,
+    pub(crate) chunk_count: usize,
+    pub(crate) version: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) enum LegacyDeltaToken<'a, S> {
+    Reused(ChunkNumber, S),
+    Added(#[serde(borrow, with = "crate::format::bytes")] Cow<'a, [u8]>),
+    Removed(ChunkNumber),
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LegacyDelta<'a, S> {
+    #[serde(borrow)]
+    pub(crate) tokens: Vec<LegacyDeltaToken<'a, S>>,
+    pub(crate) chunk_size: u64,
+    pub(crate) version: String,
+}
+
+/// Decodes a headerless signature, the chunk numbers must be `0..n` like [Signature::from_chunks]
+/// wants them and there must be as many as the chunk count says
+pub(crate) fn deserialize_signature<'de, W, S>(
+    bytes: &'de [u8],
+) -> Result<Signature<W, S>, DecodeError>
+where
+    W: Eq + Hash + Copy + Serialize + Deserialize<'de>,
+    S: PartialEq + Copy + Serialize + Deserialize<'de>,
+{
+    let legacy: LegacySignature<W, S> =
+        deserialize_bincode(bytes).map_err(DecodeError::Malformed)?;
+    let chunks: Vec<_> = legacy
+        .checksum_to_hashes
+        .into_iter()
+        .flat_map(|(weak, hashes)| {
+            hashes
+                .into_iter()
+                .map(move |(strong, chunk_number)| ChunkRecord {
+                    weak,
+                    strong,
+                    chunk_number,
+                })
+        })
+        .collect();
+    if chunks.len() != legacy.chunk_count {
+        return Err(DecodeError::ChunkCountMismatch {
+            expected: legacy.chunk_count as u64,
+            found: chunks.len() as u64,
+        });
+    }
+    Signature::from_chunks(chunks, legacy.chunk_size, legacy.version)
+        .map_err(|e| DecodeError::Malformed(e.into()))
+}
+
+/// Decodes a headerless delta
+pub(crate) fn deserialize_delta<'de, S>(bytes: &'de [u8]) -> Result<Delta<'de, S>, DecodeError>
+where
+    S: Eq + PartialEq + Debug + Serialize + Deserialize<'de>,
+{
+    let legacy: LegacyDelta<S> = deserialize_bincode(bytes).map_err(DecodeError::Malformed)?;
+    Ok(legacy.into())
+}
+
+impl<S> LegacyDeltaToken<'_, S> {
+    /// Copies borrowed added data
+    pub(crate) fn into_owned(self) -> LegacyDeltaToken<'static, S> {
+        match self {
+            LegacyDeltaToken::Reused(chunk_number, hash) => {
+                LegacyDeltaToken::Reused(chunk_number, hash)
+            }
+            LegacyDeltaToken::Added(bytes) => {
+                LegacyDeltaToken::Added(Cow::Owned(bytes.into_owned()))
+            }
+            LegacyDeltaToken::Removed(chunk_number) => LegacyDeltaToken::Removed(chunk_number),
+        }
+    }
+}
+
+impl<'a, S> From<LegacyDeltaToken<'a, S>> for DeltaToken<'a, S>
+where
+    S: PartialEq + Debug,
+{
+    fn from(token: LegacyDeltaToken<'a, S>) -> Self {
+        match token {
+            LegacyDeltaToken::Reused(chunk_number, hash) => DeltaToken::Reused(chunk_number, hash),
+            LegacyDeltaToken::Added(bytes) => DeltaToken::Added(bytes),
+            LegacyDeltaToken::Removed(chunk_number) => DeltaToken::Removed(chunk_number),
+        }
+    }
+}
+
+impl<'a, S> From<LegacyDelta<'a, S>> for Delta<'a, S>
+where
+    S: Eq + PartialEq + Debug,
+{
+    fn from(legacy: LegacyDelta<'a, S>) -> Self {
+        Delta {
+            tokens: legacy.tokens.into_iter().map(DeltaToken::from).collect(),
+            chunk_size: legacy.chunk_size,
+            version: legacy.version,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::delta_generation::DeltaToken::{Added, Removed, Reused};
+    use crate::format::FormatError;
+    use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+    use crate::signature_generation::generate_signature;
+    use crate::strong_hash::md5::Md5Sum;
+
+    use super::*;
+
+    /// Writes `signature` in the legacy layout, claiming `chunk_count` chunks
+    fn legacy_bytes(signature: &Signature<u32, [u8; 16]>, chunk_count: usize) -> Vec<u8> {
+        let mut checksum_to_hashes: HashMap<u32, Vec<([u8; 16], ChunkNumber)>> = HashMap::new();
+        for chunk in signature.chunks() {
+            checksum_to_hashes
+                .entry(chunk.weak)
+                .or_default()
+                .push((chunk.strong, chunk.chunk_number));
+        }
+        bincode2::serialize(&LegacySignature {
+            checksum_to_hashes,
+            chunk_size: signature.chunk_size(),
+            chunk_count,
+            version: signature.version.clone(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_signature() {
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&[7; 3000]);
+        let bytes = legacy_bytes(&signature, signature.chunk_count());
+
+        let decoded: Signature<u32, [u8; 16]> = deserialize_signature(&bytes).unwrap();
+        assert!(decoded.chunks().eq(signature.chunks()));
+        assert_eq!(decoded.chunk_size(), signature.chunk_size());
+    }
+
+    #[test]
+    fn test_signature_chunk_count_mismatch() {
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&[7; 3000]);
+        let chunk_count = signature.chunk_count();
+        let bytes = legacy_bytes(&signature, chunk_count + 1);
+
+        let result: Result<Signature<u32, [u8; 16]>, _> = deserialize_signature(&bytes);
+        assert!(matches!(
+            result,
+            Err(DecodeError::ChunkCountMismatch { expected, found })
+                if expected == chunk_count as u64 + 1 && found == chunk_count as u64
+        ));
+    }
+
+    #[test]
+    fn test_delta() {
+        // 1 token: Added(b"hi"), then the chunk size 4 and the version "v1"
+        let mut bytes = vec![1, 0, 0, 0, 0, 0, 0, 0];
+        bytes.extend([1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, b'h', b'i']);
+        bytes.extend([4, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, b'v', b'1']);
+
+        let delta: Delta<[u8; 16]> = deserialize_delta(&bytes).unwrap();
+        assert_eq!(delta.tokens, [Added(Cow::Borrowed(b"hi"))]);
+        assert_eq!(delta.chunk_size, 4);
+        assert_eq!(delta.version, "v1");
+    }
+
+    #[test]
+    fn test_delta_tokens() {
+        let bytes = bincode2::serialize(&LegacyDelta::<[u8; 16]> {
+            tokens: vec![
+                LegacyDeltaToken::Reused(1, [1; 16]),
+                LegacyDeltaToken::Added(Cow::Borrowed(b"abc")),
+                LegacyDeltaToken::Removed(0),
+            ],
+            chunk_size: 8,
+            version: "v1".to_string(),
+        })
+        .unwrap();
+
+        let delta: Delta<[u8; 16]> = deserialize_delta(&bytes).unwrap();
+        assert_eq!(
+            delta.tokens,
+            [Reused(1, [1; 16]), Added(Cow::Borrowed(b"abc")), Removed(0)]
+        );
+    }
+
+    #[test]
+    fn test_trailing_bytes() {
+        let mut bytes = bincode2::serialize(&LegacyDelta::<[u8; 16]> {
+            tokens: vec![],
+            chunk_size: 8,
+            version: "v1".to_string(),
+        })
+        .unwrap();
+        bytes.push(0);
+
+        assert!(matches!(
+            deserialize_delta::<[u8; 16]>(&bytes),
+            Err(DecodeError::Malformed(FormatError::TrailingBytes(1)))
+        ));
+    }
+}
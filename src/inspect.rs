@@ -0,0 +1,906 @@
+//!
+//! What's inside an encoded signature or delta, for when a sync goes wrong
+//!
+//! [inspect] decodes the bytes of any file the command line tool writes and describes it as a
+//! [Report]: the header fields, the chunks of signatures and the tokens of deltas. A report prints
+//! as text and serializes to JSON.
+//!
+//! ```
+//! use rolling_in_the_diff::decode::DecodeLimits;
+//! use rolling_in_the_diff::diff::diff;
+//! use rolling_in_the_diff::format::{encode, Format, Kind};
+//! use rolling_in_the_diff::inspect::{inspect, Content, Detail};
+//! use rolling_in_the_diff::rolling_checksum::rolling_adler32::RollingAdler32;
+//! use rolling_in_the_diff::strong_hash::md5::Md5Sum;
+//!
+//! let delta = diff::<RollingAdler32, Md5Sum>(b"the quick brown fox", b"the quick brown cat");
+//! let mut encoded = Vec::new();
+//! encode(&delta, Kind::Delta, Format::Bincode, &mut encoded).unwrap();
+//!
+//! let report = inspect(&encoded, None, Detail::All, &DecodeLimits::default()).unwrap();
+//! match &report.content {
+//!     Content::Delta(delta) => assert_eq!(delta.output_len, 19),
+//!     _ => unreachable!(),
+//! }
+//! println!("{}", report);
+//! ```
+//!
+
+use std::fmt::{self, Display, Formatter};
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::decode::{
+    decode_delta, decode_signature, decode_tree_delta, decode_tree_signature, DecodeError,
+    DecodeLimits,
+};
+use crate::delta_generation::{Delta, DeltaToken};
+use crate::format::{Header, Kind};
+use crate::mapped_signature::{is_mapped_signature, MappedSignature, MappedSignatureError};
+use crate::signature_index::SignatureIndex;
+use crate::strong_hash::md5::Md5Sum;
+use crate::strong_hash::StrongHash;
+use crate::tree::{TreeDelta, TreeDeltaEntry, TreeSignature};
+use crate::{ChunkNumber, ChunkRecord, Signature};
+
+type Md5Hash = <Md5Sum as StrongHash>::HashType;
+
+/// How many chunks, tokens, files or entries a [Report] lists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Detail {
+    /// Only the header fields and the totals
+    Summary,
+    /// The first few of each
+    Head(usize),
+    /// Everything, including the chunks of every file of a tree signature and the tokens of
+    /// every modified file of a tree delta
+    All,
+}
+
+impl Detail {
+    /// The detail of the lists within the listed items, e.g. the chunks of a file
+    fn nested(self) -> Detail {
+        match self {
+            Detail::All => Detail::All,
+            _ => Detail::Summary,
+        }
+    }
+}
+
+///
+/// Describes the encoded signature or delta in `bytes`
+///
+/// What `bytes` holds comes from its header. Headerless input, written before there were headers,
+/// needs the `kind` - when both are there they have to match. The signatures and deltas are those
+/// of the command line tool, with the rolling Adler-32 and MD5.
+///
+pub fn inspect(
+    bytes: &[u8],
+    kind: Option<Kind>,
+    detail: Detail,
+    limits: &DecodeLimits,
+) -> Result<Report, InspectError> {
+    let len = bytes.len() as u64;
+    if is_mapped_signature(bytes) && kind.is_none_or(|kind| kind == Kind::Signature) {
+        let signature = MappedSignature::<u32, Md5Hash>::from_bytes(bytes)?;
+        let mut chunks: Vec<_> = signature.chunks().collect();
+        chunks.sort_by_key(|chunk| chunk.chunk_number);
+        return Ok(Report {
+            encoding: "mapped".to_string(),
+            len,
+            content: Content::MappedSignature(SignatureReport::new(
+                signature.version(),
+                signature.chunk_size(),
+                chunks.iter(),
+                detail,
+            )),
+        });
+    }
+
+    let header = Header::parse(bytes)
+        .map_err(DecodeError::InvalidHeader)?
+        .map(|(header, _)| header);
+    let encoding = match header {
+        Some(header) => header.format.to_string(),
+        None => "bincode without a header, as the first release wrote it".to_string(),
+    };
+    let content = match kind.or(header.map(|header| header.kind)) {
+        None => return Err(InspectError::MissingKind),
+        Some(Kind::Signature) => {
+            let signature: Signature<u32, Md5Hash> = decode_signature(bytes, limits)?;
+            Content::Signature(SignatureReport::new(
+                &signature.version,
+                signature.chunk_size(),
+                signature.chunks(),
+                detail,
+            ))
+        }
+        Some(Kind::Delta) => {
+            let delta: Delta<Md5Hash> = decode_delta(bytes, limits)?;
+            Content::Delta(DeltaReport::new(&delta, detail))
+        }
+        Some(Kind::TreeSignature) => {
+            let signature: TreeSignature<u32, Md5Hash> = decode_tree_signature(bytes, limits)?;
+            Content::TreeSignature(TreeSignatureReport::new(&signature, detail))
+        }
+        Some(Kind::TreeDelta) => {
+            let delta: TreeDelta<Md5Hash> = decode_tree_delta(bytes, limits)?;
+            Content::TreeDelta(TreeDeltaReport::new(&delta, detail))
+        }
+    };
+    Ok(Report {
+        encoding,
+        len,
+        content,
+    })
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    /// The format, or how the input is laid out when it's not one of the formats
+    pub encoding: String,
+    /// The length of the input
+    pub len: u64,
+    #[serde(flatten)]
+    pub content: Content,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Content {
+    Signature(SignatureReport),
+    MappedSignature(SignatureReport),
+    Delta(DeltaReport),
+    TreeSignature(TreeSignatureReport),
+    TreeDelta(TreeDeltaReport),
+}
+
+/// The algorithms of the command line tool
+#[derive(Serialize, Debug)]
+pub struct Algorithms {
+    pub rolling_checksum: &'static str,
+    pub strong_hash: &'static str,
+    /// Always, the files don't record their algorithms - these are the ones the input is decoded
+    /// with
+    pub assumed: bool,
+}
+
+const ALGORITHMS: Algorithms = Algorithms {
+    rolling_checksum: "adler32",
+    strong_hash: "md5",
+    assumed: true,
+};
+
+/// Some of the items of a longer list, see [Detail]
+#[derive(Serialize, Debug)]
+pub struct Listing<T> {
+    pub total: usize,
+    pub shown: Vec<T>,
+}
+
+impl<T> Listing<T> {
+    fn new(items: impl ExactSizeIterator<Item = T>, detail: Detail) -> Self {
+        let total = items.len();
+        let shown = match detail {
+            Detail::Summary => Vec::new(),
+            Detail::Head(count) => items.take(count).collect(),
+            Detail::All => items.collect(),
+        };
+        Listing { total, shown }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SignatureReport {
+    pub version: String,
+    pub chunk_size: usize,
+    pub chunk_count: usize,
+    pub algorithms: Algorithms,
+    pub chunks: Listing<ChunkReport>,
+}
+
+impl SignatureReport {
+    fn new<'c>(
+        version: &str,
+        chunk_size: usize,
+        chunks: impl ExactSizeIterator<Item = &'c ChunkRecord<u32, Md5Hash>>,
+        detail: Detail,
+    ) -> Self {
+        let chunks = chunk_reports(chunk_size, chunks);
+        SignatureReport {
+            version: version.to_string(),
+            chunk_size,
+            chunk_count: chunks.len(),
+            algorithms: ALGORITHMS,
+            chunks: Listing::new(chunks, detail),
+        }
+    }
+}
+
+fn chunk_reports<'c, I>(
+    chunk_size: usize,
+    chunks: I,
+) -> impl ExactSizeIterator<Item = ChunkReport> + use<'c, I>
+where
+    I: ExactSizeIterator<Item = &'c ChunkRecord<u32, Md5Hash>>,
+{
+    chunks.map(move |chunk| ChunkReport {
+        chunk_number: chunk.chunk_number,
+        offset: chunk.chunk_number.saturating_mul(chunk_size as u64),
+        weak: format!("{:08x}", chunk.weak),
+        strong: hex(&chunk.strong),
+    })
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct ChunkReport {
+    pub chunk_number: ChunkNumber,
+    /// Where the chunk starts in the old content
+    pub offset: u64,
+    pub weak: String,
+    pub strong: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DeltaReport {
+    pub version: String,
+    pub chunk_size: u64,
+    pub algorithms: Algorithms,
+    /// The length of the new content, at most that unless `exact`
+    pub output_len: u64,
+    /// Whether `output_len` and the token offsets are exact - reused and diffed chunks count as
+    /// whole ones, but the last chunk of the old content may be shorter
+    pub exact: bool,
+    pub totals: TokenTotals,
+    pub tokens: Listing<TokenReport>,
+}
+
+impl DeltaReport {
+    fn new(delta: &Delta<Md5Hash>, detail: Detail) -> Self {
+        let mut totals = TokenTotals::default();
+        let mut offset = 0u64;
+        let tokens: Vec<_> = delta
+            .tokens
+            .iter()
+            .map(|token| {
+                let token = TokenReport::new(token, offset, delta.chunk_size);
+                totals.add(&token);
+                offset = offset.saturating_add(token.len);
+                token
+            })
+            .collect();
+        DeltaReport {
+            version: delta.version.clone(),
+            chunk_size: delta.chunk_size,
+            algorithms: ALGORITHMS,
+            output_len: offset,
+            exact: totals.reused_chunks == 0 && totals.diffed_chunks == 0,
+            totals,
+            tokens: Listing::new(tokens.into_iter(), detail),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct TokenReport {
+    /// Where the output of the token starts in the new content, at most that after a reused or
+    /// diffed chunk (see [DeltaReport::exact])
+    pub offset: u64,
+    /// How many bytes of the new content the token produces, a whole chunk for reused and diffed
+    /// chunks even though the last one of the old content may be shorter
+    pub len: u64,
+    #[serde(flatten)]
+    pub token: TokenDetail,
+}
+
+impl TokenReport {
+    fn new(token: &DeltaToken<Md5Hash>, offset: u64, chunk_size: u64) -> Self {
+        let (len, token) = match token {
+            DeltaToken::Reused(chunk_number, strong) => (
+                chunk_size,
+                TokenDetail::Reused {
+                    chunk_number: *chunk_number,
+                    strong: hex(strong),
+                },
+            ),
+            DeltaToken::Added(bytes) => (bytes.len() as u64, TokenDetail::Added),
+            DeltaToken::Removed(chunk_number) => (
+                0,
+                TokenDetail::Removed {
+                    chunk_number: *chunk_number,
+                },
+            ),
+            DeltaToken::Copied(source_offset, len, strong) => (
+                *len,
+                TokenDetail::Copied {
+                    source_offset: *source_offset,
+                    strong: hex(strong),
+                },
+            ),
+            DeltaToken::BackReference(source_offset, len) => (
+                *len,
+                TokenDetail::BackReference {
+                    source_offset: *source_offset,
+                },
+            ),
+            DeltaToken::Fill(byte, len) => (*len, TokenDetail::Fill { byte: *byte }),
+            DeltaToken::Diff(chunk_number, strong, runs) => (
+                chunk_size,
+                TokenDetail::Diff {
+                    chunk_number: *chunk_number,
+                    strong: hex(strong),
+                    changed_bytes: runs.iter().map(|(_, bytes)| bytes.len() as u64).sum(),
+                },
+            ),
+        };
+        TokenReport { offset, len, token }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(tag = "token", rename_all = "snake_case")]
+pub enum TokenDetail {
+    Reused {
+        chunk_number: ChunkNumber,
+        strong: String,
+    },
+    Added,
+    Removed {
+        chunk_number: ChunkNumber,
+    },
+    /// From the old content
+    Copied {
+        source_offset: u64,
+        strong: String,
+    },
+    /// From the new content written so far
+    BackReference {
+        source_offset: u64,
+    },
+    Fill {
+        byte: u8,
+    },
+    Diff {
+        chunk_number: ChunkNumber,
+        strong: String,
+        changed_bytes: u64,
+    },
+}
+
+/// The number of tokens and the bytes they produce, by kind of token
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct TokenTotals {
+    pub token_count: usize,
+    pub reused_chunks: u64,
+    pub removed_chunks: u64,
+    pub added_bytes: u64,
+    pub copied_bytes: u64,
+    pub back_reference_bytes: u64,
+    pub fill_bytes: u64,
+    pub diffed_chunks: u64,
+    /// The bytes of the diffed chunks that differ from the old ones
+    pub changed_bytes: u64,
+}
+
+impl TokenTotals {
+    fn add(&mut self, token: &TokenReport) {
+        self.token_count += 1;
+        let total = match token.token {
+            TokenDetail::Reused { .. } => {
+                self.reused_chunks += 1;
+                return;
+            }
+            TokenDetail::Removed { .. } => {
+                self.removed_chunks += 1;
+                return;
+            }
+            TokenDetail::Added => &mut self.added_bytes,
+            TokenDetail::Copied { .. } => &mut self.copied_bytes,
+            TokenDetail::BackReference { .. } => &mut self.back_reference_bytes,
+            TokenDetail::Fill { .. } => &mut self.fill_bytes,
+            TokenDetail::Diff { changed_bytes, .. } => {
+                self.diffed_chunks += 1;
+                self.changed_bytes = self.changed_bytes.saturating_add(changed_bytes);
+                return;
+            }
+        };
+        *total = total.saturating_add(token.len);
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct TreeSignatureReport {
+    pub version: String,
+    pub algorithms: Algorithms,
+    pub files: Listing<FileSignatureReport>,
+}
+
+impl TreeSignatureReport {
+    fn new(signature: &TreeSignature<u32, Md5Hash>, detail: Detail) -> Self {
+        let files = signature
+            .files
+            .iter()
+            .map(|(path, file)| FileSignatureReport {
+                path: path.clone(),
+                len: file.len,
+                hash: hex(&file.hash),
+                chunk_size: file.signature.chunk_size(),
+                chunk_count: file.signature.chunk_count(),
+                chunks: Listing::new(
+                    chunk_reports(file.signature.chunk_size(), file.signature.chunks()),
+                    detail.nested(),
+                ),
+            });
+        TreeSignatureReport {
+            version: signature.version.clone(),
+            algorithms: ALGORITHMS,
+            files: Listing::new(files, detail),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileSignatureReport {
+    pub path: String,
+    pub len: u64,
+    pub hash: String,
+    pub chunk_size: usize,
+    pub chunk_count: usize,
+    pub chunks: Listing<ChunkReport>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TreeDeltaReport {
+    pub version: String,
+    pub algorithms: Algorithms,
+    pub entries: Listing<TreeEntryReport>,
+}
+
+impl TreeDeltaReport {
+    fn new(delta: &TreeDelta<Md5Hash>, detail: Detail) -> Self {
+        let entries = delta.entries.iter().map(|entry| match entry {
+            TreeDeltaEntry::Modified { path, delta } => TreeEntryReport::Modified {
+                path: path.clone(),
+                delta: DeltaReport::new(delta, detail.nested()),
+            },
+            TreeDeltaEntry::Created { path, content } => TreeEntryReport::Created {
+                path: path.clone(),
+                len: content.len() as u64,
+            },
+            TreeDeltaEntry::Deleted { path } => TreeEntryReport::Deleted { path: path.clone() },
+            TreeDeltaEntry::Renamed { from, to } => TreeEntryReport::Renamed {
+                from: from.clone(),
+                to: to.clone(),
+            },
+        });
+        TreeDeltaReport {
+            version: delta.version.clone(),
+            algorithms: ALGORITHMS,
+            entries: Listing::new(entries, detail),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "entry", rename_all = "snake_case")]
+pub enum TreeEntryReport {
+    Modified {
+        path: String,
+        #[serde(flatten)]
+        delta: DeltaReport,
+    },
+    Created {
+        path: String,
+        len: u64,
+    },
+    Deleted {
+        path: String,
+    },
+    Renamed {
+        from: String,
+        to: String,
+    },
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let kind = match &self.content {
+            Content::Signature(_) => "signature",
+            Content::MappedSignature(_) => "mapped signature",
+            Content::Delta(_) => "delta",
+            Content::TreeSignature(_) => "tree signature",
+            Content::TreeDelta(_) => "tree delta",
+        };
+        writeln!(f, "kind:        {}", kind)?;
+        writeln!(f, "encoding:    {}", self.encoding)?;
+        writeln!(f, "length:      {} bytes", self.len)?;
+        match &self.content {
+            Content::Signature(signature) | Content::MappedSignature(signature) => signature.fmt(f),
+            Content::Delta(delta) => delta.fmt(f),
+            Content::TreeSignature(signature) => signature.fmt(f),
+            Content::TreeDelta(delta) => delta.fmt(f),
+        }
+    }
+}
+
+impl Display for Algorithms {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, {}", self.rolling_checksum, self.strong_hash)?;
+        if self.assumed {
+            write!(f, " (assumed, not recorded)")?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Display> Display for Listing<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for item in &self.shown {
+            item.fmt(f)?;
+        }
+        if !self.shown.is_empty() && self.shown.len() < self.total {
+            writeln!(f, "... {} more", self.total - self.shown.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for SignatureReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version:     {}", self.version)?;
+        writeln!(f, "chunk size:  {}", self.chunk_size)?;
+        writeln!(f, "chunk count: {}", self.chunk_count)?;
+        writeln!(f, "algorithms:  {}", self.algorithms)?;
+        if !self.chunks.shown.is_empty() {
+            writeln!(f, "\n{:>8} {:>12} {:>8} strong", "chunk", "offset", "weak")?;
+        }
+        self.chunks.fmt(f)
+    }
+}
+
+impl Display for ChunkReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>8} {:>12} {:>8} {}",
+            self.chunk_number, self.offset, self.weak, self.strong
+        )
+    }
+}
+
+impl Display for DeltaReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version:     {}", self.version)?;
+        writeln!(f, "chunk size:  {}", self.chunk_size)?;
+        writeln!(f, "algorithms:  {}", self.algorithms)?;
+        writeln!(f, "output:      {}", OutputLen(self))?;
+        self.totals.fmt(f)?;
+        if !self.tokens.shown.is_empty() {
+            writeln!(f, "\n{:>12} {:>10} token", "offset", "length")?;
+        }
+        self.tokens.fmt(f)
+    }
+}
+
+/// The output length of a delta, with whether it's an upper bound
+struct OutputLen<'r>(&'r DeltaReport);
+
+impl Display for OutputLen<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.0.exact {
+            write!(f, "{} bytes", self.0.output_len)
+        } else {
+            write!(
+                f,
+                "at most {} bytes, reused and diffed chunks count as whole ones",
+                self.0.output_len
+            )
+        }
+    }
+}
+
+impl Display for TokenTotals {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "tokens:      {}", self.token_count)?;
+        let totals = [
+            ("reused", self.reused_chunks, "chunks"),
+            ("removed", self.removed_chunks, "chunks"),
+            ("added", self.added_bytes, "bytes"),
+            ("copied", self.copied_bytes, "bytes"),
+            ("back refs", self.back_reference_bytes, "bytes"),
+            ("fill", self.fill_bytes, "bytes"),
+            ("diffed", self.diffed_chunks, "chunks"),
+            ("changed", self.changed_bytes, "bytes"),
+        ];
+        for (name, total, unit) in totals.into_iter().filter(|(_, total, _)| *total > 0) {
+            writeln!(f, "  {:<10} {} {}", format!("{}:", name), total, unit)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for TokenReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>12} {:>10} ", self.offset, self.len)?;
+        match &self.token {
+            TokenDetail::Reused {
+                chunk_number,
+                strong,
+            } => writeln!(f, "reused chunk {} ({})", chunk_number, strong),
+            TokenDetail::Added => writeln!(f, "added"),
+            TokenDetail::Removed { chunk_number } => writeln!(f, "removed chunk {}", chunk_number),
+            TokenDetail::Copied {
+                source_offset,
+                strong,
+            } => writeln!(
+                f,
+                "copied from {} of the old content ({})",
+                source_offset, strong
+            ),
+            TokenDetail::BackReference { source_offset } => {
+                writeln!(f, "copied from {} of the new content", source_offset)
+            }
+            TokenDetail::Fill { byte } => writeln!(f, "fill with {:#04x}", byte),
+            TokenDetail::Diff {
+                chunk_number,
+                strong,
+                changed_bytes,
+            } => writeln!(
+                f,
+                "diff against chunk {} ({}), {} bytes changed",
+                chunk_number, strong, changed_bytes
+            ),
+        }
+    }
+}
+
+impl Display for TreeSignatureReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version:     {}", self.version)?;
+        writeln!(f, "algorithms:  {}", self.algorithms)?;
+        writeln!(f, "files:       {}", self.files.total)?;
+        self.files.fmt(f)
+    }
+}
+
+impl Display for FileSignatureReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "\n{}: {} bytes, {} chunks of {}, {}",
+            self.path, self.len, self.chunk_count, self.chunk_size, self.hash
+        )?;
+        self.chunks.fmt(f)
+    }
+}
+
+impl Display for TreeDeltaReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version:     {}", self.version)?;
+        writeln!(f, "algorithms:  {}", self.algorithms)?;
+        writeln!(f, "entries:     {}", self.entries.total)?;
+        self.entries.fmt(f)
+    }
+}
+
+impl Display for TreeEntryReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeEntryReport::Modified { path, delta } => {
+                writeln!(f, "\nmodified {}: {}", path, OutputLen(delta))?;
+                delta.totals.fmt(f)?;
+                delta.tokens.fmt(f)
+            }
+            TreeEntryReport::Created { path, len } => {
+                writeln!(f, "\ncreated {}: {} bytes", path, len)
+            }
+            TreeEntryReport::Deleted { path } => writeln!(f, "\ndeleted {}", path),
+            TreeEntryReport::Renamed { from, to } => writeln!(f, "\nrenamed {} to {}", from, to),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum InspectError {
+    #[error("the input has no header saying what it is, its kind has to be given")]
+    MissingKind,
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    #[error(transparent)]
+    MappedSignature(#[from] MappedSignatureError),
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+
+    use test_case::test_case;
+
+    use crate::delta_generation::DeltaToken::{Added, BackReference, Diff, Fill, Removed, Reused};
+    use crate::format::legacy::{LegacyDelta, LegacyDeltaToken};
+    use crate::format::{encode, Format};
+    use crate::mapped_signature::write_mapped_signature;
+    use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+    use crate::signature_generation::generate_signature;
+    use crate::DEFAULT_VERSION;
+
+    use super::*;
+
+    fn encoded_delta() -> Vec<u8> {
+        let delta = Delta::<Md5Hash> {
+            tokens: vec![
+                Reused(1, [0xab; 16]),
+                Added(Cow::Borrowed(b"new")),
+                Removed(0),
+                Fill(0, 10),
+                BackReference(0, 4),
+            ],
+            chunk_size: 8,
+            version: DEFAULT_VERSION.to_string(),
+        };
+        let mut encoded = Vec::new();
+        encode(&delta, Kind::Delta, Format::Bincode, &mut encoded).unwrap();
+        encoded
+    }
+
+    fn delta_report(report: Report) -> DeltaReport {
+        match report.content {
+            Content::Delta(delta) => delta,
+            content => panic!("not a delta: {:?}", content),
+        }
+    }
+
+    #[test]
+    fn test_inspect_delta() {
+        let report = inspect(&encoded_delta(), None, Detail::All, &Default::default()).unwrap();
+        assert_eq!(report.encoding, "bincode");
+        let delta = delta_report(report);
+
+        assert_eq!(delta.output_len, 8 + 3 + 10 + 4);
+        // the reused chunk may be the short last one
+        assert!(!delta.exact);
+        assert_eq!(
+            delta.totals,
+            TokenTotals {
+                token_count: 5,
+                reused_chunks: 1,
+                removed_chunks: 1,
+                added_bytes: 3,
+                fill_bytes: 10,
+                back_reference_bytes: 4,
+                ..Default::default()
+            }
+        );
+        let offsets: Vec<_> = delta
+            .tokens
+            .shown
+            .iter()
+            .map(|token| token.offset)
+            .collect();
+        assert_eq!(offsets, vec![0, 8, 11, 11, 21]);
+        assert_eq!(
+            delta.tokens.shown[0].token,
+            TokenDetail::Reused {
+                chunk_number: 1,
+                strong: "ab".repeat(16)
+            }
+        );
+    }
+
+    #[test]
+    fn test_inspect_diffed_chunks() {
+        let delta = Delta {
+            tokens: vec![
+                Added(Cow::Borrowed(b"new")),
+                Diff(0, [0; 16], vec![(1, vec![1, 2]), (5, vec![3])]),
+            ],
+            chunk_size: 8,
+            version: DEFAULT_VERSION.to_string(),
+        };
+        let delta = DeltaReport::new(&delta, Detail::All);
+
+        assert_eq!(delta.output_len, 3 + 8);
+        assert!(!delta.exact);
+        assert_eq!(
+            delta.totals,
+            TokenTotals {
+                token_count: 2,
+                added_bytes: 3,
+                diffed_chunks: 1,
+                changed_bytes: 3,
+                ..Default::default()
+            }
+        );
+        assert!(delta.to_string().contains("output:      at most 11 bytes"));
+
+        let delta = Delta::<Md5Hash> {
+            tokens: vec![Added(Cow::Borrowed(b"new")), Fill(0, 2)],
+            chunk_size: 8,
+            version: DEFAULT_VERSION.to_string(),
+        };
+        let delta = DeltaReport::new(&delta, Detail::All);
+        assert!(delta.exact);
+        assert!(delta.to_string().contains("output:      5 bytes"));
+        assert!(delta
+            .to_string()
+            .contains("algorithms:  adler32, md5 (assumed, not recorded)"));
+    }
+
+    #[test_case(Detail::Summary => 0; "when summarizing")]
+    #[test_case(Detail::Head(2) => 2; "when showing the first tokens")]
+    #[test_case(Detail::All => 5; "when showing everything")]
+    fn test_inspect_detail(detail: Detail) -> usize {
+        let report = inspect(&encoded_delta(), None, detail, &Default::default()).unwrap();
+        let delta = delta_report(report);
+        assert_eq!(delta.tokens.total, 5);
+        delta.tokens.shown.len()
+    }
+
+    #[test]
+    fn test_inspect_headerless() {
+        let legacy = LegacyDelta::<Md5Hash> {
+            tokens: vec![LegacyDeltaToken::Added(Cow::Borrowed(b"abc"))],
+            chunk_size: 4,
+            version: "v1".to_string(),
+        };
+        let headerless = &bincode2::serialize(&legacy).unwrap();
+
+        assert!(matches!(
+            inspect(headerless, None, Detail::All, &Default::default()),
+            Err(InspectError::MissingKind)
+        ));
+        let report = inspect(
+            headerless,
+            Some(Kind::Delta),
+            Detail::All,
+            &Default::default(),
+        );
+        assert_eq!(
+            report.unwrap().encoding,
+            "bincode without a header, as the first release wrote it"
+        );
+        assert!(matches!(
+            inspect(
+                &encoded_delta(),
+                Some(Kind::Signature),
+                Detail::All,
+                &Default::default()
+            ),
+            Err(InspectError::Decode(DecodeError::WrongKind { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_inspect_signatures() {
+        let content: Vec<u8> = (0..1 << 12).map(|x| (x * 13 % 251) as u8).collect();
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&content);
+        let mut encoded = Vec::new();
+        encode(&signature, Kind::Signature, Format::Bincode, &mut encoded).unwrap();
+        let mut mapped = Vec::new();
+        write_mapped_signature(&signature, &mut mapped).unwrap();
+
+        let report = inspect(&encoded, None, Detail::All, &Default::default()).unwrap();
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["kind"], "signature");
+        assert_eq!(json["chunk_count"], signature.chunk_count());
+        let Content::Signature(encoded) = report.content else {
+            panic!("not a signature")
+        };
+
+        let report = inspect(&mapped, None, Detail::All, &Default::default()).unwrap();
+        assert_eq!(report.encoding, "mapped");
+        let Content::MappedSignature(mapped) = report.content else {
+            panic!("not a mapped signature")
+        };
+        assert_eq!(mapped.chunks.shown, encoded.chunks.shown);
+        assert_eq!(
+            encoded.chunks.shown[1].offset,
+            signature.chunk_size() as u64
+        );
+    }
+}
@@ -1,43 +1,437 @@
 extern crate core;
 
-use std::collections::HashMap;
+use std::cmp::Ordering;
 use std::hash::Hash;
 
-use serde::{Deserialize, Serialize};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+#[cfg(feature = "async")]
+use crate::async_io::AsyncError;
+use crate::decode::DecodeError;
+use crate::delta_generation::DeltaError;
+use crate::format::FormatError;
+use crate::inspect::InspectError;
+use crate::mapped_signature::MappedSignatureError;
+use crate::patch::PatchError;
+use crate::signature_generation::SignatureError;
+use crate::signature_index::{weak_key, Prefilter, SignatureIndex};
+use crate::sync::SyncError;
+use crate::tree::TreePatchError;
+use crate::zsync::ReconstructError;
 
 pub const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
 // unwrap_or as a const fn is not stable yet
 const DEFAULT_VERSION: &str = "none";
 
+#[cfg(feature = "async")]
+pub mod async_io;
+pub mod cancel;
+pub mod decode;
 pub mod delta_generation;
+pub mod diff;
+pub mod differ;
+#[cfg(unix)]
+pub mod ffi;
+pub mod format;
+pub mod inspect;
+pub mod mapped_signature;
 pub mod patch;
+pub mod progress;
+#[cfg(feature = "python")]
+mod python;
 pub mod signature_generation;
+pub mod signature_index;
+pub mod sync;
+pub mod token_stream;
+pub mod tree;
+pub mod zsync;
 
 pub mod rolling_checksum;
 pub mod strong_hash;
 
 pub type ChunkNumber = u64;
 
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// Any of the errors of the library, for callers that don't need to tell the operations apart
+///
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Signature(#[from] SignatureError),
+    #[error(transparent)]
+    SignatureChunks(#[from] SignatureChunksError),
+    #[error(transparent)]
+    Delta(#[from] DeltaError),
+    #[error(transparent)]
+    Patch(#[from] PatchError),
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    #[error(transparent)]
+    Format(#[from] FormatError),
+    #[error(transparent)]
+    Inspect(#[from] InspectError),
+    #[error(transparent)]
+    MappedSignature(#[from] MappedSignatureError),
+    #[error(transparent)]
+    TreePatch(#[from] TreePatchError),
+    #[error(transparent)]
+    Sync(#[from] SyncError),
+    #[error(transparent)]
+    Reconstruct(#[from] ReconstructError),
+    #[cfg(feature = "async")]
+    #[error(transparent)]
+    Async(#[from] AsyncError),
+}
+
+///
+/// The weak checksums and strong hashes of each chunk of some content
+///
+/// It's serialized as a list of chunks ordered by chunk number (see [SignatureLayout]), so that the
+/// same content always gives the same bytes. The lookup index is rebuilt on deserialization.
+///
+/// The index is a single flat array of records sorted by a key derived from the weak checksum, so
+/// a lookup is a binary search over contiguous memory instead of chasing a per-checksum allocation.
+/// A bitset over the keys is checked first, which rejects most weak checksums that aren't there.
+/// The position of each chunk's record is kept too, so the chunks can be walked in order.
+///
+#[derive(Debug)]
 pub struct Signature<W, S>
 where
     W: Eq + Hash + PartialEq,
     S: PartialEq + Copy,
 {
-    checksum_to_hashes: HashMap<W, Vec<(S, ChunkNumber)>>,
+    records: Vec<ChunkRecord<W, S>>,
+    /// The index of each chunk's record in `records`, by chunk number
+    positions: Vec<usize>,
+    prefilter: Prefilter,
     chunk_size: usize,
     chunk_count: usize,
     pub version: String,
 }
 
+/// A single chunk of a [Signature]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkRecord<W, S> {
+    /// The rolling checksum of the chunk
+    pub weak: W,
+    /// The strong hash of the chunk
+    pub strong: S,
+    pub chunk_number: ChunkNumber,
+}
+
+/// Why chunk records don't make up a [Signature]
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SignatureChunksError {
+    #[error("chunk {0} is missing")]
+    MissingChunk(ChunkNumber),
+    #[error("chunk {0} appears more than once")]
+    DuplicateChunk(ChunkNumber),
+    #[error("a chunk size of {chunk_size} can't describe {chunk_count} chunks")]
+    InvalidChunkSize {
+        chunk_size: usize,
+        chunk_count: usize,
+    },
+}
+
 impl<W, S> Signature<W, S>
 where
     W: Eq + Hash + PartialEq,
     S: PartialEq + Copy,
 {
-    fn quick_query(&self, weak_checksum: &W) -> Option<&[(S, ChunkNumber)]> {
-        self.checksum_to_hashes
-            .get(weak_checksum)
-            .map(|x| x.as_slice())
+    ///
+    /// Builds a signature out of chunk records in any order, e.g. computed by another tool
+    ///
+    /// The chunk numbers must be `0..n` without gaps or duplicates, all chunks but the last one are
+    /// `chunk_size` bytes long.
+    ///
+    /// ```
+    /// use rolling_in_the_diff::{ChunkRecord, Signature, SignatureChunksError};
+    ///
+    /// let chunk = |chunk_number| ChunkRecord { weak: chunk_number as u32, strong: [0u8; 16], chunk_number };
+    /// let signature = Signature::from_chunks([chunk(1), chunk(0)], 1024, "custom".to_string()).unwrap();
+    /// assert_eq!(signature.chunk_count(), 2);
+    /// assert_eq!(signature.chunks().map(|chunk| chunk.weak).collect::<Vec<_>>(), [0, 1]);
+    ///
+    /// let error = Signature::from_chunks([chunk(0), chunk(2)], 1024, "custom".to_string());
+    /// assert_eq!(error.unwrap_err(), SignatureChunksError::MissingChunk(1));
+    /// ```
+    ///
+    pub fn from_chunks<I>(
+        chunks: I,
+        chunk_size: usize,
+        version: String,
+    ) -> Result<Self, SignatureChunksError>
+    where
+        I: IntoIterator<Item = ChunkRecord<W, S>>,
+    {
+        let mut chunks: Vec<ChunkRecord<W, S>> = chunks.into_iter().collect();
+        chunks.sort_by_key(|chunk| chunk.chunk_number);
+        for (expected, chunk) in chunks.iter().enumerate() {
+            let expected = expected as ChunkNumber;
+            match chunk.chunk_number.cmp(&expected) {
+                Ordering::Less => {
+                    return Err(SignatureChunksError::DuplicateChunk(chunk.chunk_number))
+                }
+                Ordering::Greater => return Err(SignatureChunksError::MissingChunk(expected)),
+                Ordering::Equal => {}
+            }
+        }
+        check_chunk_size(chunk_size, chunks.len())?;
+
+        let chunks = chunks.into_iter().map(|chunk| (chunk.weak, chunk.strong));
+        Ok(Signature::from_ordered_chunks(chunks, chunk_size, version))
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunk_count
+    }
+
+    /// The chunks ordered by chunk number
+    pub fn chunks(&self) -> impl ExactSizeIterator<Item = &ChunkRecord<W, S>> {
+        self.positions
+            .iter()
+            .map(|&position| &self.records[position])
+    }
+
+    /// The chunks with the given weak checksum, ordered by chunk number
+    pub fn chunks_with_weak<'s, 'w>(
+        &'s self,
+        weak_checksum: &'w W,
+    ) -> impl Iterator<Item = &'s ChunkRecord<W, S>> + use<'s, 'w, W, S> {
+        self.records_with_key(weak_key(weak_checksum))
+            .iter()
+            .filter(move |record| record.weak == *weak_checksum)
+    }
+
+    /// Builds the lookup index out of (weak checksum, strong hash) pairs ordered by chunk number
+    fn from_ordered_chunks<I>(chunks: I, chunk_size: usize, version: String) -> Self
+    where
+        I: IntoIterator<Item = (W, S)>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let chunks = chunks.into_iter();
+        let chunk_count = chunks.len();
+
+        let mut records: Vec<ChunkRecord<W, S>> = chunks
+            .enumerate()
+            .map(|(chunk_number, (weak, strong))| ChunkRecord {
+                weak,
+                strong,
+                chunk_number: chunk_number as ChunkNumber,
+            })
+            .collect();
+        // stable, so chunks with the same weak checksum stay ordered by chunk number - the keys are
+        // cached next to an index rather than along a copy of each record
+        records.sort_by_cached_key(|record| weak_key(&record.weak));
+
+        let mut positions = vec![0; chunk_count];
+        for (position, record) in records.iter().enumerate() {
+            positions[record.chunk_number as usize] = position;
+        }
+
+        let prefilter = Prefilter::new(
+            records.iter().map(|record| weak_key(&record.weak)),
+            chunk_count,
+        );
+
+        Signature {
+            records,
+            positions,
+            prefilter,
+            chunk_size,
+            chunk_count,
+            version,
+        }
+    }
+
+    /// The records whose weak checksum has the given [weak_key], ordered by chunk number
+    fn records_with_key(&self, key: u64) -> &[ChunkRecord<W, S>] {
+        if !self.prefilter.may_contain(key) {
+            return &[];
+        }
+
+        let start = self
+            .records
+            .partition_point(|record| weak_key(&record.weak) < key);
+        let len = self.records[start..]
+            .iter()
+            .take_while(|record| weak_key(&record.weak) == key)
+            .count();
+        &self.records[start..start + len]
+    }
+}
+
+impl<W, S> SignatureIndex<W, S> for Signature<W, S>
+where
+    W: Eq + Hash + PartialEq,
+    S: PartialEq + Copy,
+{
+    fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    fn chunk_count(&self) -> usize {
+        self.chunk_count
+    }
+
+    fn version(&self) -> &str {
+        &self.version
+    }
+
+    fn quick_query(&self, weak_checksum: &W) -> impl Iterator<Item = (S, ChunkNumber)> {
+        self.chunks_with_weak(weak_checksum)
+            .map(|record| (record.strong, record.chunk_number))
+    }
+
+    fn strong_hashes_by_chunk(&self) -> Vec<Option<S>> {
+        self.chunks().map(|record| Some(record.strong)).collect()
+    }
+}
+
+/// Whether `chunk_count` chunks of `chunk_size` bytes can be addressed
+fn check_chunk_size(chunk_size: usize, chunk_count: usize) -> Result<(), SignatureChunksError> {
+    if (chunk_size == 0 && chunk_count > 0) || chunk_size.checked_mul(chunk_count).is_none() {
+        return Err(SignatureChunksError::InvalidChunkSize {
+            chunk_size,
+            chunk_count,
+        });
+    }
+    Ok(())
+}
+
+/// The on-disk layout of a [Signature]
+#[derive(Serialize, Deserialize)]
+struct SignatureLayout<C> {
+    /// (weak checksum, strong hash) ordered by chunk number
+    chunks: C,
+    chunk_size: usize,
+    version: String,
+}
+
+impl<W, S> Serialize for Signature<W, S>
+where
+    W: Eq + Hash + PartialEq + Serialize,
+    S: PartialEq + Copy + Serialize,
+{
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        SignatureLayout {
+            chunks: OrderedChunks(self),
+            chunk_size: self.chunk_size,
+            version: self.version.clone(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Serializes the chunks of a [Signature] as (weak checksum, strong hash) ordered by chunk number
+struct OrderedChunks<'s, W, S>(&'s Signature<W, S>)
+where
+    W: Eq + Hash + PartialEq,
+    S: PartialEq + Copy;
+
+impl<W, S> Serialize for OrderedChunks<'_, W, S>
+where
+    W: Eq + Hash + PartialEq + Serialize,
+    S: PartialEq + Copy + Serialize,
+{
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        serializer.collect_seq(self.0.chunks().map(|record| (&record.weak, &record.strong)))
+    }
+}
+
+impl<'de, W, S> Deserialize<'de> for Signature<W, S>
+where
+    W: Eq + Hash + PartialEq + Deserialize<'de>,
+    S: PartialEq + Copy + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let layout = SignatureLayout::<Vec<(W, S)>>::deserialize(deserializer)?;
+        check_chunk_size(layout.chunk_size, layout.chunks.len()).map_err(D::Error::custom)?;
+        Ok(Signature::from_ordered_chunks(
+            layout.chunks,
+            layout.chunk_size,
+            layout.version,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use test_case::test_case;
+
+    use super::*;
+
+    fn chunk(chunk_number: ChunkNumber) -> ChunkRecord<u32, u64> {
+        ChunkRecord {
+            weak: (chunk_number % 2) as u32,
+            strong: chunk_number * 10,
+            chunk_number,
+        }
+    }
+
+    #[test_case(vec![2, 0, 1], 4, Ok(()); "when the chunks are shuffled")]
+    #[test_case(vec![], 0, Ok(()); "when there are no chunks")]
+    #[test_case(vec![0, 1, 1], 4, Err(SignatureChunksError::DuplicateChunk(1)); "when a chunk is duplicated")]
+    #[test_case(vec![1, 2], 4, Err(SignatureChunksError::MissingChunk(0)); "when a chunk is missing")]
+    #[test_case(vec![0], 0, Err(SignatureChunksError::InvalidChunkSize { chunk_size: 0, chunk_count: 1 }); "when the chunk size is 0")]
+    fn test_from_chunks(
+        chunk_numbers: Vec<ChunkNumber>,
+        chunk_size: usize,
+        expected: Result<(), SignatureChunksError>,
+    ) {
+        let signature = Signature::from_chunks(
+            chunk_numbers
+                .iter()
+                .map(|chunk_number| chunk(*chunk_number)),
+            chunk_size,
+            "test".to_string(),
+        );
+
+        match (signature, expected) {
+            (Ok(signature), Ok(())) => {
+                assert_eq!(signature.chunk_size(), chunk_size);
+                assert_eq!(signature.chunk_count(), chunk_numbers.len());
+                let chunks: Vec<_> = signature.chunks().copied().collect();
+                let expected: Vec<_> = (0..chunk_numbers.len() as ChunkNumber).map(chunk).collect();
+                assert_eq!(chunks, expected);
+            }
+            (Err(error), Err(expected)) => assert_eq!(error, expected),
+            (signature, expected) => {
+                panic!("{:?} instead of {:?}", signature.map(|_| ()), expected)
+            }
+        }
+    }
+
+    #[test_case(0, 1 => matches Err(_); "when the chunk size is 0")]
+    #[test_case(0, 0 => matches Ok(_); "when there are no chunks")]
+    #[test_case(4, 3 => matches Ok(_); "when the chunk size is set")]
+    fn test_deserialize(
+        chunk_size: usize,
+        chunk_count: u32,
+    ) -> Result<Signature<u32, u64>, String> {
+        let chunks: Vec<(u32, u64)> = (0..chunk_count).map(|weak| (weak, 0)).collect();
+        let bytes = serde_json::to_vec(&SignatureLayout {
+            chunks,
+            chunk_size,
+            version: "test".to_string(),
+        })
+        .unwrap();
+
+        serde_json::from_slice(&bytes).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_chunks_with_weak() {
+        let signature = Signature::from_chunks((0..5).map(chunk), 4, "test".to_string()).unwrap();
+
+        let odd: Vec<_> = signature.chunks_with_weak(&1).copied().collect();
+        assert_eq!(odd, [chunk(1), chunk(3)]);
+        assert_eq!(signature.chunks_with_weak(&7).count(), 0);
     }
 }
@@ -1,28 +1,188 @@
 use std::fs::File;
 use std::io::{BufWriter, Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use bincode2::{deserialize, serialize};
 use clap::Parser;
 use env_logger::Env;
+use indicatif::{ProgressBar, ProgressStyle};
 use log::{info, warn};
+use memmap2::Mmap;
 
-use rolling_in_the_diff::delta_generation::{generate_delta, Delta};
-use rolling_in_the_diff::patch::patch;
+use rolling_in_the_diff::cancel::CancellationToken;
+use rolling_in_the_diff::decode::{
+    decode_delta, decode_signature, decode_tree_delta, decode_tree_signature, DecodeLimits,
+};
+use rolling_in_the_diff::delta_generation::{
+    try_generate_delta_with_options, Delta, DeltaOptions, MatchPolicy,
+};
+use rolling_in_the_diff::diff::{diff_with_options, DiffOptions};
+use rolling_in_the_diff::format::{encode, Format, Kind};
+use rolling_in_the_diff::inspect::{inspect, Detail};
+use rolling_in_the_diff::mapped_signature::{
+    is_mapped_signature, write_mapped_signature, MappedSignature,
+};
+use rolling_in_the_diff::patch::{patch_with_progress, SparseWriter};
+use rolling_in_the_diff::progress::{NoProgress, Progress, ProgressObserver};
 use rolling_in_the_diff::rolling_checksum::rolling_adler32::RollingAdler32;
-use rolling_in_the_diff::signature_generation::generate_signature;
+use rolling_in_the_diff::signature_generation::try_generate_signature;
+use rolling_in_the_diff::signature_index::SignatureIndex;
 use rolling_in_the_diff::strong_hash::md5::Md5Sum;
 use rolling_in_the_diff::strong_hash::StrongHash;
+use rolling_in_the_diff::sync::{receive, send};
+use rolling_in_the_diff::tree::{
+    generate_tree_delta, generate_tree_signature, patch_tree, read_tree, TreeDelta, TreeSignature,
+};
+use rolling_in_the_diff::zsync::{reconstruct, SeekableRangeSource};
 use rolling_in_the_diff::{Signature, VERSION};
 
 #[derive(Parser, Debug)]
 #[clap(version, about)]
 /// Simple CLI tool that tries to replicate rdiff's signature and delta commands
 struct Cli {
+    #[clap(long, value_enum, global = true, default_value = "bar")]
+    /// How to report the progress of long-running operations
+    progress: ProgressMode,
+    #[clap(long, value_parser, global = true, default_value = "bincode")]
+    /// How to encode the written signatures and deltas: bincode, json, cbor or msgpack (the last two if built in). Reading picks up the format from the file
+    format: Format,
     #[clap(subcommand)]
     command: Commands,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ProgressMode {
+    /// No progress reporting
+    None,
+    /// A progress bar on stderr
+    Bar,
+    /// JSON lines on stdout (stderr when stdin/stdout are used by "send" or "receive")
+    Json,
+}
+
+/// Draws the progress of each phase as a progress bar on stderr
+struct BarProgress {
+    bar: ProgressBar,
+}
+
+impl BarProgress {
+    fn new() -> Self {
+        let bar = ProgressBar::new(0);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("{msg} {bar} {bytes}/{total_bytes}")
+                .unwrap(),
+        );
+        BarProgress { bar }
+    }
+}
+
+impl ProgressObserver for BarProgress {
+    fn on_progress(&self, progress: &Progress) {
+        if self.bar.is_finished() {
+            self.bar.reset();
+        }
+        self.bar.set_message(progress.phase.to_string());
+        self.bar
+            .set_length(progress.total_bytes.unwrap_or(progress.processed_bytes));
+        self.bar.set_position(progress.processed_bytes);
+    }
+
+    fn on_finish(&self, progress: &Progress) {
+        self.on_progress(progress);
+        self.bar.finish();
+    }
+}
+
+/// Progress lines closer than this are skipped, except for the last one of each phase
+const JSON_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Writes the progress as one JSON object per line
+struct JsonProgress {
+    /// the output and when it was last written to
+    out: Mutex<(Box<dyn Write + Send>, Option<Instant>)>,
+}
+
+#[derive(serde::Serialize)]
+struct JsonProgressLine<'a> {
+    #[serde(flatten)]
+    progress: &'a Progress,
+    finished: bool,
+}
+
+impl JsonProgress {
+    fn write_line(&self, progress: &Progress, finished: bool) {
+        let mut out = self.out.lock().unwrap();
+        let (out, last_written) = &mut *out;
+        if !finished && last_written.is_some_and(|last| last.elapsed() < JSON_PROGRESS_INTERVAL) {
+            return;
+        }
+        *last_written = Some(Instant::now());
+
+        let line = JsonProgressLine { progress, finished };
+        let written = serde_json::to_writer(&mut *out, &line)
+            .map_err(std::io::Error::from)
+            .and_then(|_| out.write_all(b"\n"))
+            .and_then(|_| out.flush());
+        if let Err(e) = written {
+            warn!("failed to report progress: {}", e);
+        }
+    }
+}
+
+impl ProgressObserver for JsonProgress {
+    fn on_progress(&self, progress: &Progress) {
+        self.write_line(progress, false)
+    }
+
+    fn on_finish(&self, progress: &Progress) {
+        self.write_line(progress, true)
+    }
+}
+
+fn progress_observer(mode: ProgressMode, stdout_is_taken: bool) -> Box<dyn ProgressObserver> {
+    match mode {
+        ProgressMode::None => Box::new(NoProgress),
+        ProgressMode::Bar => Box::new(BarProgress::new()),
+        ProgressMode::Json => {
+            let out: Box<dyn Write + Send> = if stdout_is_taken {
+                Box::new(std::io::stderr())
+            } else {
+                Box::new(std::io::stdout())
+            };
+            Box::new(JsonProgress {
+                out: Mutex::new((out, None)),
+            })
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct DeltaArgs {
+    #[clap(long, value_parser, default_value = "first")]
+    /// How to pick among matching chunks: "first" or "sequential" (prefers continuing with the next chunk)
+    match_policy: MatchPolicy,
+    #[clap(long)]
+    /// Encode repeated literal data as back-references to where it was first emitted
+    dedup_literals: bool,
+    #[clap(long)]
+    /// Encode runs of a repeated byte (e.g. zeros in sparse files) as fills
+    fill_runs: bool,
+}
+
+impl From<DeltaArgs> for DeltaOptions {
+    fn from(args: DeltaArgs) -> Self {
+        DeltaOptions {
+            match_policy: args.match_policy,
+            dedup_literals: args.dedup_literals,
+            fill_runs: args.fill_runs,
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(clap::Subcommand, Debug)]
 enum Commands {
     /// Generates a signature of --old-file=<OLD_FILE> into --signature-file=<SIGNATURE_FILE> to be later used as a source for the "delta" command
@@ -33,6 +193,12 @@ enum Commands {
         #[clap(long)]
         /// The resulting signature file
         signature_file: PathBuf,
+        #[clap(long)]
+        /// Treat --old-file as a directory and generate a signature of the whole tree
+        tree: bool,
+        #[clap(long, conflicts_with_all = &["tree", "format"])]
+        /// Write the signature in a layout that "delta" can memory-map and query in place
+        mapped: bool,
     },
     /// Generates the delta between a file described by --signature-file=<SIGNATURE_FILE> and a --new-file=<NEW_FILE> to --delta-file=<DELTA_FILE>
     Delta {
@@ -45,6 +211,28 @@ enum Commands {
         #[clap(long)]
         /// The resulting delta file
         delta_file: PathBuf,
+        #[clap(long)]
+        /// Treat --new-file as a directory and generate a delta against a tree signature
+        tree: bool,
+        #[clap(flatten)]
+        delta_args: DeltaArgs,
+    },
+    /// Generates the delta between --old-file=<OLD_FILE> and --new-file=<NEW_FILE> to --delta-file=<DELTA_FILE> without an intermediate signature file
+    Diff {
+        #[clap(long)]
+        /// The file with the original content
+        old_file: PathBuf,
+        #[clap(long)]
+        /// The file with the (potentially) updated content
+        new_file: PathBuf,
+        #[clap(long)]
+        /// The resulting delta file
+        delta_file: PathBuf,
+        #[clap(flatten)]
+        delta_args: DeltaArgs,
+        #[clap(long)]
+        /// Encode chunks similar to an old one as a byte-wise difference against it (bsdiff-style)
+        near_matches: bool,
     },
     /// Applies --delta-file=<DELTA_FILE> on top of --old-file=<OLD_FILE> (not in place) and produces --updated_file<UPDATED_FILE>
     Patch {
@@ -57,17 +245,99 @@ enum Commands {
         #[clap(long)]
         /// The file with the (potentially) updated content
         updated_file: PathBuf,
+        #[clap(long)]
+        /// Treat --old-file and --updated-file as directories and apply a tree delta
+        tree: bool,
+    },
+    /// Sends --new-file=<NEW_FILE> to a "receive" on the other end of stdin/stdout or of the given command (e.g. -- ssh host rolling-in-the-diff receive --old-file ...)
+    Send {
+        #[clap(long)]
+        /// The file with the (potentially) updated content
+        new_file: PathBuf,
+        #[clap(last = true)]
+        /// The command that runs the receiving side, stdin/stdout are used when omitted
+        command: Vec<String>,
+    },
+    /// Updates --old-file=<OLD_FILE> with the content "send" has over stdin/stdout
+    Receive {
+        #[clap(long)]
+        /// The file holding the old content
+        old_file: PathBuf,
+        #[clap(long)]
+        /// The file with the updated content, defaults to replacing --old-file
+        updated_file: Option<PathBuf>,
+    },
+    /// Rebuilds the file described by --signature-file=<SIGNATURE_FILE> into --updated-file=<UPDATED_FILE> reusing --old-file=<OLD_FILE> and fetching the rest from --source-file=<SOURCE_FILE>
+    Reconstruct {
+        #[clap(long)]
+        /// The published signature of the new content
+        signature_file: PathBuf,
+        #[clap(long)]
+        /// The local file with the old content
+        old_file: PathBuf,
+        #[clap(long)]
+        /// The published new content, only the missing ranges are read from it
+        source_file: PathBuf,
+        #[clap(long)]
+        /// The resulting file
+        updated_file: PathBuf,
+    },
+    /// Describes the signature or delta in --file=<FILE>: its header fields, chunks and tokens
+    Inspect {
+        #[clap(long)]
+        /// The signature or delta file
+        file: PathBuf,
+        #[clap(long, value_parser)]
+        /// What --file is (signature, delta, tree-signature or tree-delta), only needed for files written without a header
+        kind: Option<Kind>,
+        #[clap(long, value_enum, default_value = "text")]
+        /// How to print the description
+        output: InspectOutput,
+        #[clap(long, conflicts_with = "all")]
+        /// Only print the header fields and the totals
+        summary: bool,
+        #[clap(long)]
+        /// Print every chunk and token, also those of the files in a tree, rather than the first few
+        all: bool,
     },
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum InspectOutput {
+    /// Human-readable text
+    Text,
+    /// A JSON document
+    Json,
+}
+
+/// How many chunks or tokens "inspect" prints unless asked for all of them
+const INSPECT_HEAD: usize = 20;
+
 fn main() -> anyhow::Result<()> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     let cli: Cli = Cli::parse();
 
-    return match cli.command {
+    let stdout_is_taken = match &cli.command {
+        Commands::Send { command, .. } => command.is_empty(),
+        Commands::Receive { .. } => true,
+        _ => false,
+    };
+    let progress = progress_observer(cli.progress, stdout_is_taken);
+    let progress = progress.as_ref();
+    let format = cli.format;
+
+    match cli.command {
         Commands::Signature {
             old_file,
             signature_file,
+            tree: true,
+            ..
+        } => tree_signature(&old_file, &signature_file, format, progress),
+        Commands::Signature {
+            old_file,
+            signature_file,
+            tree: false,
+            mapped,
         } => {
             info!(
                 "Generating signature of {} into {}",
@@ -76,20 +346,43 @@ fn main() -> anyhow::Result<()> {
             );
 
             let mut old_file = File::open(old_file)?;
-            let mut old_file_content = Vec::<u8>::new();
-            old_file.read_to_end(&mut old_file_content)?;
-
-            let mut signature_file = File::create(signature_file)?;
+            let signature_file = File::create(signature_file)?;
 
-            let signature = generate_signature::<RollingAdler32, Md5Sum>(&old_file_content);
+            let signature = try_generate_signature::<RollingAdler32, Md5Sum, _>(
+                &mut old_file,
+                progress,
+                &CancellationToken::new(),
+            )?;
 
-            signature_file.write_all(serialize(&signature)?.as_slice())?;
+            let mut out = BufWriter::new(signature_file);
+            if mapped {
+                write_mapped_signature(&signature, &mut out)?;
+            } else {
+                encode(&signature, Kind::Signature, format, &mut out)?;
+            }
+            out.flush()?;
             Ok(())
         }
         Commands::Delta {
             signature_file,
             new_file,
             delta_file,
+            tree: true,
+            delta_args,
+        } => tree_delta(
+            &signature_file,
+            &new_file,
+            &delta_file,
+            &delta_args.into(),
+            format,
+            progress,
+        ),
+        Commands::Delta {
+            signature_file,
+            new_file,
+            delta_file,
+            tree: false,
+            delta_args,
         } => {
             info!(
                 "Generating the delta between {} and {} into {}",
@@ -98,14 +391,10 @@ fn main() -> anyhow::Result<()> {
                 delta_file.display(),
             );
 
-            let mut signature_file = File::open(signature_file)?;
-            let mut signature_file_content = Vec::<u8>::new();
-            signature_file.read_to_end(&mut signature_file_content)?;
-
-            let signature: Signature<
-                <RollingAdler32 as rolling_in_the_diff::rolling_checksum::RollingChecksum>::ChecksumType,
-                <Md5Sum as StrongHash>::HashType
-            > = deserialize(signature_file_content.as_slice())?;
+            let signature_file = File::open(signature_file)?;
+            // the mapped layout is queried in place, without reading the whole file up front
+            // (like any mmap, this assumes nobody truncates the signature file meanwhile)
+            let signature_file_content = unsafe { Mmap::map(&signature_file)? };
 
             let mut new_file = File::open(new_file)?;
             let mut new_file_content = Vec::<u8>::new();
@@ -113,27 +402,84 @@ fn main() -> anyhow::Result<()> {
 
             // TODO: signature_file and new_file processing should be done in separate threads
 
-            if VERSION.unwrap_or("") != signature.version {
-                // TODO: rather introduce a semver check here
-                warn!(
-                    "signature was built with a different version of the tool: {} {}",
-                    VERSION.unwrap_or(""),
-                    signature.version
-                );
+            let options = delta_args.into();
+            if is_mapped_signature(&signature_file_content) {
+                let signature: MappedSignature<
+                    <RollingAdler32 as rolling_in_the_diff::rolling_checksum::RollingChecksum>::ChecksumType,
+                    <Md5Sum as StrongHash>::HashType,
+                > = MappedSignature::from_bytes(&signature_file_content)?;
+                signature_delta(
+                    &signature,
+                    &new_file_content,
+                    &delta_file,
+                    &options,
+                    format,
+                    progress,
+                )
+            } else {
+                let signature: Signature<
+                    <RollingAdler32 as rolling_in_the_diff::rolling_checksum::RollingChecksum>::ChecksumType,
+                    <Md5Sum as StrongHash>::HashType,
+                > = decode_signature(&signature_file_content, &DecodeLimits::default())?;
+                signature_delta(
+                    &signature,
+                    &new_file_content,
+                    &delta_file,
+                    &options,
+                    format,
+                    progress,
+                )
             }
+        }
+        Commands::Diff {
+            old_file,
+            new_file,
+            delta_file,
+            delta_args,
+            near_matches,
+        } => {
+            info!(
+                "Generating the delta between {} and {} into {}",
+                old_file.display(),
+                new_file.display(),
+                delta_file.display(),
+            );
 
-            let delta =
-                generate_delta::<RollingAdler32, Md5Sum>(&signature, new_file_content.as_slice());
+            let mut old_file = File::open(old_file)?;
+            let mut old_file_content = Vec::<u8>::new();
+            old_file.read_to_end(&mut old_file_content)?;
 
-            let mut delta_file = File::create(delta_file)?;
+            let mut new_file = File::open(new_file)?;
+            let mut new_file_content = Vec::<u8>::new();
+            new_file.read_to_end(&mut new_file_content)?;
 
-            delta_file.write_all(serialize(&delta)?.as_slice())?;
+            let delta = diff_with_options::<RollingAdler32, Md5Sum>(
+                &old_file_content,
+                &new_file_content,
+                &DiffOptions {
+                    delta: delta_args.into(),
+                    near_matches,
+                },
+                progress,
+                &CancellationToken::new(),
+            )?;
+
+            let mut delta_file = BufWriter::new(File::create(delta_file)?);
+            encode(&delta, Kind::Delta, format, &mut delta_file)?;
+            delta_file.flush()?;
             Ok(())
         }
         Commands::Patch {
             delta_file,
             old_file,
             updated_file,
+            tree: true,
+        } => tree_patch(&delta_file, &old_file, &updated_file, progress),
+        Commands::Patch {
+            delta_file,
+            old_file,
+            updated_file,
+            tree: false,
         } => {
             info!(
                 "Applying delta {} on top of {} into {}",
@@ -151,8 +497,7 @@ fn main() -> anyhow::Result<()> {
             delta_file.read_to_end(&mut delta_file_content)?;
 
             let delta: Delta<<Md5Sum as StrongHash>::HashType> =
-                deserialize(delta_file_content.as_slice())?;
-            let out_file = File::create(updated_file)?;
+                decode_delta(delta_file_content.as_slice(), &DecodeLimits::default())?;
 
             if VERSION.unwrap_or("") != delta.version {
                 // TODO: rather introduce a semver check here
@@ -163,12 +508,334 @@ fn main() -> anyhow::Result<()> {
                 );
             }
 
-            patch::<Md5Sum, BufWriter<File>>(
-                old_file_content.as_slice(),
-                delta,
-                &mut BufWriter::new(out_file),
-            )?;
+            write_through_temp_file(&updated_file, |out_file| {
+                let mut out = BufWriter::new(SparseWriter::new(out_file));
+                patch_with_progress::<Md5Sum, _>(
+                    old_file_content.as_slice(),
+                    delta,
+                    &mut out,
+                    progress,
+                    &CancellationToken::new(),
+                )?;
+                out.into_inner().map_err(|e| e.into_error())?.finish()?;
+                Ok(())
+            })
+        }
+        Commands::Send { new_file, command } => sync_send(&new_file, &command, progress),
+        Commands::Receive {
+            old_file,
+            updated_file,
+        } => sync_receive(
+            &old_file,
+            updated_file.as_deref().unwrap_or(&old_file),
+            progress,
+        ),
+        Commands::Reconstruct {
+            signature_file,
+            old_file,
+            source_file,
+            updated_file,
+        } => {
+            info!(
+                "Reconstructing {} into {} from {} and {}",
+                signature_file.display(),
+                updated_file.display(),
+                old_file.display(),
+                source_file.display(),
+            );
+
+            let mut signature_file = File::open(signature_file)?;
+            let mut signature_file_content = Vec::<u8>::new();
+            signature_file.read_to_end(&mut signature_file_content)?;
+
+            let signature: Signature<
+                <RollingAdler32 as rolling_in_the_diff::rolling_checksum::RollingChecksum>::ChecksumType,
+                <Md5Sum as StrongHash>::HashType
+            > = decode_signature(signature_file_content.as_slice(), &DecodeLimits::default())?;
+
+            let mut old_file = File::open(old_file)?;
+            let mut old_file_content = Vec::<u8>::new();
+            old_file.read_to_end(&mut old_file_content)?;
+
+            let mut source = SeekableRangeSource::new(File::open(source_file)?);
+
+            write_through_temp_file(&updated_file, |out_file| {
+                let mut out = BufWriter::new(out_file);
+                reconstruct::<RollingAdler32, Md5Sum, _, _>(
+                    &signature,
+                    &old_file_content,
+                    &mut source,
+                    &mut out,
+                    progress,
+                    &CancellationToken::new(),
+                )?;
+                out.flush()?;
+                Ok(())
+            })
+        }
+        Commands::Inspect {
+            file,
+            kind,
+            output,
+            summary,
+            all,
+        } => {
+            let detail = match (summary, all) {
+                (true, _) => Detail::Summary,
+                (_, true) => Detail::All,
+                _ => Detail::Head(INSPECT_HEAD),
+            };
+            let content = std::fs::read(file)?;
+            let report = inspect(&content, kind, detail, &DecodeLimits::default())?;
+
+            let mut stdout = std::io::stdout().lock();
+            match output {
+                InspectOutput::Text => write!(stdout, "{}", report)?,
+                InspectOutput::Json => {
+                    serde_json::to_writer_pretty(&mut stdout, &report)?;
+                    writeln!(stdout)?;
+                }
+            }
             Ok(())
         }
-    };
+    }
+}
+
+fn signature_delta(
+    signature: &impl SignatureIndex<
+        <RollingAdler32 as rolling_in_the_diff::rolling_checksum::RollingChecksum>::ChecksumType,
+        <Md5Sum as StrongHash>::HashType,
+    >,
+    new_file_content: &[u8],
+    delta_file: &Path,
+    options: &DeltaOptions,
+    format: Format,
+    progress: &dyn ProgressObserver,
+) -> anyhow::Result<()> {
+    if VERSION.unwrap_or("") != signature.version() {
+        // TODO: rather introduce a semver check here
+        warn!(
+            "signature was built with a different version of the tool: {} {}",
+            VERSION.unwrap_or(""),
+            signature.version()
+        );
+    }
+
+    let delta = try_generate_delta_with_options::<RollingAdler32, Md5Sum>(
+        signature,
+        new_file_content,
+        options,
+        progress,
+        &CancellationToken::new(),
+    )?;
+
+    let mut delta_file = BufWriter::new(File::create(delta_file)?);
+    encode(&delta, Kind::Delta, format, &mut delta_file)?;
+    delta_file.flush()?;
+    Ok(())
+}
+
+fn sync_send(
+    new_file: &Path,
+    command: &[String],
+    progress: &dyn ProgressObserver,
+) -> anyhow::Result<()> {
+    let mut new_file = File::open(new_file)?;
+    let mut new_file_content = Vec::<u8>::new();
+    new_file.read_to_end(&mut new_file_content)?;
+
+    if command.is_empty() {
+        info!("Sending over stdin/stdout");
+        send::<RollingAdler32, Md5Sum, _, _>(
+            &mut std::io::stdin().lock(),
+            &mut std::io::stdout().lock(),
+            &new_file_content,
+            progress,
+            &CancellationToken::new(),
+        )?;
+        return Ok(());
+    }
+
+    info!("Sending to {}", command.join(" "));
+    let mut receiver = Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut input = receiver.stdout.take().expect("stdout is piped");
+    let mut output = receiver.stdin.take().expect("stdin is piped");
+
+    let result = send::<RollingAdler32, Md5Sum, _, _>(
+        &mut input,
+        &mut output,
+        &new_file_content,
+        progress,
+        &CancellationToken::new(),
+    );
+    drop(output);
+    let status = receiver.wait()?;
+    result?;
+    if !status.success() {
+        anyhow::bail!("receiver exited with {}", status);
+    }
+    Ok(())
+}
+
+fn sync_receive(
+    old_file: &Path,
+    updated_file: &Path,
+    progress: &dyn ProgressObserver,
+) -> anyhow::Result<()> {
+    info!(
+        "Receiving the update of {} into {}",
+        old_file.display(),
+        updated_file.display()
+    );
+
+    let mut old_file = File::open(old_file)?;
+    let mut old_file_content = Vec::<u8>::new();
+    old_file.read_to_end(&mut old_file_content)?;
+
+    write_through_temp_file(updated_file, |out_file| {
+        receive::<RollingAdler32, Md5Sum, _, _, _>(
+            &mut std::io::stdin().lock(),
+            &mut std::io::stdout().lock(),
+            &old_file_content,
+            &mut BufWriter::new(out_file),
+            progress,
+            &CancellationToken::new(),
+        )?;
+        Ok(())
+    })
+}
+
+///
+/// Writes into a temporary file next to `path` that is only renamed to `path` once `write` succeeds,
+/// so that a failed or cancelled operation leaves the target untouched
+///
+fn write_through_temp_file(
+    path: &Path,
+    write: impl FnOnce(File) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut temp_file_name = path.as_os_str().to_owned();
+    temp_file_name.push(".part");
+    let temp_file = PathBuf::from(temp_file_name);
+
+    if let Err(e) = write(File::create(&temp_file)?) {
+        std::fs::remove_file(&temp_file)?;
+        return Err(e);
+    }
+    std::fs::rename(&temp_file, path)?;
+    Ok(())
+}
+
+fn tree_signature(
+    old_dir: &Path,
+    signature_file: &Path,
+    format: Format,
+    progress: &dyn ProgressObserver,
+) -> anyhow::Result<()> {
+    info!(
+        "Generating tree signature of {} into {}",
+        old_dir.display(),
+        signature_file.display()
+    );
+
+    let old_tree = read_tree(old_dir)?;
+    let signature = generate_tree_signature::<RollingAdler32, Md5Sum>(
+        &old_tree,
+        progress,
+        &CancellationToken::new(),
+    )?;
+
+    let mut signature_file = BufWriter::new(File::create(signature_file)?);
+    encode(&signature, Kind::TreeSignature, format, &mut signature_file)?;
+    signature_file.flush()?;
+    Ok(())
+}
+
+fn tree_delta(
+    signature_file: &Path,
+    new_dir: &Path,
+    delta_file: &Path,
+    options: &DeltaOptions,
+    format: Format,
+    progress: &dyn ProgressObserver,
+) -> anyhow::Result<()> {
+    info!(
+        "Generating the tree delta between {} and {} into {}",
+        signature_file.display(),
+        new_dir.display(),
+        delta_file.display(),
+    );
+
+    let mut signature_file = File::open(signature_file)?;
+    let mut signature_file_content = Vec::<u8>::new();
+    signature_file.read_to_end(&mut signature_file_content)?;
+
+    let signature: TreeSignature<
+        <RollingAdler32 as rolling_in_the_diff::rolling_checksum::RollingChecksum>::ChecksumType,
+        <Md5Sum as StrongHash>::HashType,
+    > = decode_tree_signature(signature_file_content.as_slice(), &DecodeLimits::default())?;
+
+    if VERSION.unwrap_or("") != signature.version {
+        warn!(
+            "tree signature was built with a different version of the tool: {} {}",
+            VERSION.unwrap_or(""),
+            signature.version
+        );
+    }
+
+    let new_tree = read_tree(new_dir)?;
+    let delta = generate_tree_delta::<RollingAdler32, Md5Sum>(
+        &signature,
+        &new_tree,
+        options,
+        progress,
+        &CancellationToken::new(),
+    )?;
+    info!("changed files: {}", delta.entries.len());
+
+    let mut delta_file = BufWriter::new(File::create(delta_file)?);
+    encode(&delta, Kind::TreeDelta, format, &mut delta_file)?;
+    delta_file.flush()?;
+    Ok(())
+}
+
+fn tree_patch(
+    delta_file: &Path,
+    old_dir: &Path,
+    updated_dir: &Path,
+    progress: &dyn ProgressObserver,
+) -> anyhow::Result<()> {
+    info!(
+        "Applying tree delta {} on top of a copy of {} into {}",
+        delta_file.display(),
+        old_dir.display(),
+        updated_dir.display(),
+    );
+
+    let mut delta_file = File::open(delta_file)?;
+    let mut delta_file_content = Vec::<u8>::new();
+    delta_file.read_to_end(&mut delta_file_content)?;
+
+    let delta: TreeDelta<<Md5Sum as StrongHash>::HashType> =
+        decode_tree_delta(delta_file_content.as_slice(), &DecodeLimits::default())?;
+
+    if VERSION.unwrap_or("") != delta.version {
+        warn!(
+            "tree delta was built with a different version of the tool: {} {}",
+            VERSION.unwrap_or(""),
+            delta.version
+        );
+    }
+
+    patch_tree::<Md5Sum>(
+        old_dir,
+        delta,
+        updated_dir,
+        progress,
+        &CancellationToken::new(),
+    )?;
+    Ok(())
 }
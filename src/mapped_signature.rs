@@ -0,0 +1,417 @@
+//!
+//! A signature layout that is queried in place, e.g. straight out of a memory-mapped file
+//!
+//! The chunk records are stored sorted the same way as in [Signature], together with its
+//! prefilter, so opening one only means checking the header and the length - the records aren't
+//! touched until they are looked up, and there's no deserialization and no allocation. Lookups
+//! don't allocate either, but generating a delta still allocates per chunk as it does for any
+//! signature, e.g. to track the reused chunks or for [SignatureIndex::strong_hashes_by_chunk].
+//!
+//! A corrupt record is only noticed when a lookup returns it: the delta generation rejects chunk
+//! numbers out of range, and records out of order can only make lookups miss chunks.
+//!
+//! ```text
+//! magic | chunk size: u64 | chunk count: u64 | weak size: u32 | strong size: u32
+//!       | version len: u64 | prefilter len: u64 | version | prefilter
+//!       | (weak checksum, strong hash, chunk number: u64) * chunk count
+//! ```
+//!
+//! All the integers are little-endian.
+//!
+
+use std::hash::Hash;
+use std::io;
+use std::io::Write;
+use std::marker::PhantomData;
+
+use thiserror::Error;
+
+use crate::signature_index::{weak_key, Prefilter, SignatureIndex};
+use crate::{ChunkNumber, ChunkRecord, Signature};
+
+pub const MAGIC: &[u8; 8] = b"RITDMSIG";
+
+const HEADER_LEN: usize = MAGIC.len() + 8 + 8 + 4 + 4 + 8 + 8;
+
+///
+/// A fixed-size encoding for the weak checksums and the strong hashes stored in a [MappedSignature]
+///
+pub trait FixedBytes: Sized {
+    const SIZE: usize;
+
+    fn write_bytes(&self, out: &mut Vec<u8>);
+
+    /// `bytes` is always exactly [FixedBytes::SIZE] long
+    fn from_bytes(bytes: &[u8]) -> Self;
+}
+
+macro_rules! fixed_bytes_integer {
+    ($($integer:ty),*) => {
+        $(
+            impl FixedBytes for $integer {
+                const SIZE: usize = std::mem::size_of::<$integer>();
+
+                fn write_bytes(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn from_bytes(bytes: &[u8]) -> Self {
+                    <$integer>::from_le_bytes(bytes.try_into().expect("exactly SIZE bytes"))
+                }
+            }
+        )*
+    };
+}
+
+fixed_bytes_integer!(u8, u16, u32, u64);
+
+impl<const N: usize> FixedBytes for [u8; N] {
+    const SIZE: usize = N;
+
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        bytes.try_into().expect("exactly SIZE bytes")
+    }
+}
+
+///
+/// Writes `signature` in the [MappedSignature] layout
+///
+pub fn write_mapped_signature<W, S, Out>(
+    signature: &Signature<W, S>,
+    out: &mut Out,
+) -> io::Result<()>
+where
+    W: Eq + Hash + PartialEq + FixedBytes,
+    S: PartialEq + Copy + FixedBytes,
+    Out: Write,
+{
+    let prefilter = signature.prefilter.as_bytes();
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    (signature.chunk_size as u64).write_bytes(&mut header);
+    (signature.chunk_count as u64).write_bytes(&mut header);
+    (W::SIZE as u32).write_bytes(&mut header);
+    (S::SIZE as u32).write_bytes(&mut header);
+    (signature.version.len() as u64).write_bytes(&mut header);
+    (prefilter.len() as u64).write_bytes(&mut header);
+    out.write_all(&header)?;
+    out.write_all(signature.version.as_bytes())?;
+    out.write_all(prefilter)?;
+
+    let mut record = Vec::with_capacity(W::SIZE + S::SIZE + 8);
+    for chunk in &signature.records {
+        record.clear();
+        chunk.weak.write_bytes(&mut record);
+        chunk.strong.write_bytes(&mut record);
+        chunk.chunk_number.write_bytes(&mut record);
+        out.write_all(&record)?;
+    }
+    Ok(())
+}
+
+/// Whether `bytes` look like the start of a [MappedSignature] rather than a serialized [Signature]
+pub fn is_mapped_signature(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+///
+/// A signature in the layout written by [write_mapped_signature], borrowed from its bytes
+///
+/// ```
+/// use rolling_in_the_diff::delta_generation::try_generate_delta;
+/// use rolling_in_the_diff::mapped_signature::{write_mapped_signature, MappedSignature};
+/// use rolling_in_the_diff::rolling_checksum::rolling_adler32::RollingAdler32;
+/// use rolling_in_the_diff::signature_generation::generate_signature;
+/// use rolling_in_the_diff::strong_hash::md5::Md5Sum;
+///
+/// let signature = generate_signature::<RollingAdler32, Md5Sum>(b"the quick brown fox");
+/// let mut bytes = Vec::new();
+/// write_mapped_signature(&signature, &mut bytes).unwrap();
+///
+/// let mapped = MappedSignature::from_bytes(&bytes).unwrap();
+/// let delta = try_generate_delta::<RollingAdler32, Md5Sum>(&mapped, b"the quick brown cat").unwrap();
+/// assert_eq!(
+///     delta.tokens,
+///     try_generate_delta::<RollingAdler32, Md5Sum>(&signature, b"the quick brown cat")
+///         .unwrap()
+///         .tokens
+/// );
+/// ```
+#[derive(Debug)]
+pub struct MappedSignature<'a, W, S> {
+    chunk_size: usize,
+    chunk_count: usize,
+    version: &'a str,
+    prefilter: Prefilter<&'a [u8]>,
+    records: &'a [u8],
+    record_types: PhantomData<(W, S)>,
+}
+
+impl<'a, W, S> MappedSignature<'a, W, S>
+where
+    W: Hash + FixedBytes,
+    S: FixedBytes,
+{
+    const RECORD_LEN: usize = W::SIZE + S::SIZE + 8;
+
+    ///
+    /// Validates the header of `bytes` and that the records take up the rest of it
+    ///
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, MappedSignatureError> {
+        if !is_mapped_signature(bytes) {
+            return Err(MappedSignatureError::NotMapped);
+        }
+        if bytes.len() < HEADER_LEN {
+            return Err(MappedSignatureError::Truncated);
+        }
+
+        let mut rest = &bytes[MAGIC.len()..HEADER_LEN];
+        let mut next = |len: usize| {
+            let (field, tail) = rest.split_at(len);
+            rest = tail;
+            field
+        };
+        let chunk_size = u64::from_bytes(next(8));
+        let chunk_count = u64::from_bytes(next(8));
+        let weak_size = u32::from_bytes(next(4));
+        let strong_size = u32::from_bytes(next(4));
+        let version_len = u64::from_bytes(next(8));
+        let prefilter_len = u64::from_bytes(next(8));
+
+        if weak_size as usize != W::SIZE || strong_size as usize != S::SIZE {
+            return Err(MappedSignatureError::TypeMismatch {
+                weak_size,
+                strong_size,
+            });
+        }
+
+        if chunk_size == 0 && chunk_count > 0 {
+            return Err(MappedSignatureError::InvalidChunkSize);
+        }
+
+        let to_usize =
+            |value: u64| usize::try_from(value).map_err(|_| MappedSignatureError::Truncated);
+        let version_end = HEADER_LEN
+            .checked_add(to_usize(version_len)?)
+            .ok_or(MappedSignatureError::Truncated)?;
+        let prefilter_end = version_end
+            .checked_add(to_usize(prefilter_len)?)
+            .ok_or(MappedSignatureError::Truncated)?;
+        let records_end = to_usize(chunk_count)?
+            .checked_mul(Self::RECORD_LEN)
+            .and_then(|records_len| prefilter_end.checked_add(records_len))
+            .ok_or(MappedSignatureError::Truncated)?;
+        if bytes.len() < records_end {
+            return Err(MappedSignatureError::Truncated);
+        }
+        if bytes.len() > records_end {
+            return Err(MappedSignatureError::TrailingBytes);
+        }
+
+        let version = std::str::from_utf8(&bytes[HEADER_LEN..version_end])
+            .map_err(|_| MappedSignatureError::InvalidVersion)?;
+        let prefilter = Prefilter::from_bytes(&bytes[version_end..prefilter_end])
+            .ok_or(MappedSignatureError::InvalidPrefilter)?;
+
+        Ok(MappedSignature {
+            chunk_size: to_usize(chunk_size)?,
+            chunk_count: to_usize(chunk_count)?,
+            version,
+            prefilter,
+            records: &bytes[prefilter_end..records_end],
+            record_types: PhantomData,
+        })
+    }
+
+    /// The chunks in the order they are stored, which is by weak checksum rather than by number
+    pub fn chunks(&self) -> impl ExactSizeIterator<Item = ChunkRecord<W, S>> + '_ {
+        (0..self.chunk_count).map(|index| {
+            let (weak, strong, chunk_number) = self.record(index);
+            ChunkRecord {
+                weak,
+                strong,
+                chunk_number,
+            }
+        })
+    }
+
+    fn record(&self, index: usize) -> (W, S, ChunkNumber) {
+        let record = &self.records[index * Self::RECORD_LEN..(index + 1) * Self::RECORD_LEN];
+        let (weak, rest) = record.split_at(W::SIZE);
+        let (strong, chunk_number) = rest.split_at(S::SIZE);
+        (
+            W::from_bytes(weak),
+            S::from_bytes(strong),
+            ChunkNumber::from_bytes(chunk_number),
+        )
+    }
+
+    fn record_key(&self, index: usize) -> u64 {
+        let weak = &self.records[index * Self::RECORD_LEN..index * Self::RECORD_LEN + W::SIZE];
+        weak_key(&W::from_bytes(weak))
+    }
+}
+
+impl<'a, W, S> SignatureIndex<W, S> for MappedSignature<'a, W, S>
+where
+    W: Eq + Hash + FixedBytes,
+    S: Copy + FixedBytes,
+{
+    fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    fn chunk_count(&self) -> usize {
+        self.chunk_count
+    }
+
+    fn version(&self) -> &str {
+        self.version
+    }
+
+    fn quick_query(&self, weak_checksum: &W) -> impl Iterator<Item = (S, ChunkNumber)> {
+        let key = weak_key(weak_checksum);
+
+        // the first record with the key, or none at all when the prefilter rules it out
+        let mut start = self.chunk_count;
+        if self.prefilter.may_contain(key) {
+            start = 0;
+            let mut end = self.chunk_count;
+            while start < end {
+                let middle = start + (end - start) / 2;
+                if self.record_key(middle) < key {
+                    start = middle + 1;
+                } else {
+                    end = middle;
+                }
+            }
+        }
+
+        (start..self.chunk_count)
+            .take_while(move |index| self.record_key(*index) == key)
+            .map(|index| self.record(index))
+            .filter(move |(weak, _, _)| weak == weak_checksum)
+            .map(|(_, strong, chunk_number)| (strong, chunk_number))
+    }
+
+    fn strong_hashes_by_chunk(&self) -> Vec<Option<S>> {
+        let mut hashes = vec![None; self.chunk_count];
+        for index in 0..self.chunk_count {
+            let (_, strong, chunk_number) = self.record(index);
+            if let Some(slot) = hashes.get_mut(chunk_number as usize) {
+                *slot = Some(strong);
+            }
+        }
+        hashes
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum MappedSignatureError {
+    #[error("not a mapped signature")]
+    NotMapped,
+    #[error("the mapped signature is truncated")]
+    Truncated,
+    #[error("the mapped signature has trailing bytes")]
+    TrailingBytes,
+    #[error("the mapped signature holds {weak_size} byte weak checksums and {strong_size} byte strong hashes")]
+    TypeMismatch { weak_size: u32, strong_size: u32 },
+    #[error("the mapped signature has chunks but no chunk size")]
+    InvalidChunkSize,
+    #[error("the version of the mapped signature is not UTF-8")]
+    InvalidVersion,
+    #[error("the prefilter of the mapped signature is invalid")]
+    InvalidPrefilter,
+}
+
+#[cfg(test)]
+mod test {
+    use crate::delta_generation::{try_generate_delta, DeltaError};
+    use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+    use crate::signature_generation::generate_signature;
+    use crate::strong_hash::md5::Md5Sum;
+    use crate::strong_hash::StrongHash;
+
+    use super::*;
+
+    type Md5Hash = <Md5Sum as StrongHash>::HashType;
+
+    fn content() -> Vec<u8> {
+        (0..1 << 14).map(|x| (x * 13 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_mapped_signature_matches_signature() {
+        let content = content();
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&content);
+        let mut bytes = Vec::new();
+        write_mapped_signature(&signature, &mut bytes).unwrap();
+
+        let mapped = MappedSignature::<u32, Md5Hash>::from_bytes(&bytes).unwrap();
+
+        assert_eq!(mapped.chunk_size(), signature.chunk_size());
+        assert_eq!(mapped.chunk_count(), signature.chunk_count());
+        assert_eq!(mapped.version(), signature.version());
+        assert_eq!(
+            mapped.strong_hashes_by_chunk(),
+            signature.strong_hashes_by_chunk()
+        );
+        let mut chunks: Vec<_> = mapped.chunks().collect();
+        chunks.sort_by_key(|chunk| chunk.chunk_number);
+        assert!(chunks.iter().eq(signature.chunks()));
+        for record in &signature.records {
+            assert_eq!(
+                mapped.quick_query(&record.weak).collect::<Vec<_>>(),
+                signature.quick_query(&record.weak).collect::<Vec<_>>()
+            );
+        }
+        assert_eq!(mapped.quick_query(&0).count(), 0);
+    }
+
+    #[test]
+    fn test_mapped_signature_rejects_invalid_bytes() {
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&content());
+        let mut bytes = Vec::new();
+        write_mapped_signature(&signature, &mut bytes).unwrap();
+
+        assert!(matches!(
+            MappedSignature::<u32, Md5Hash>::from_bytes(&bytes[1..]),
+            Err(MappedSignatureError::NotMapped)
+        ));
+        assert!(matches!(
+            MappedSignature::<u32, Md5Hash>::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(MappedSignatureError::Truncated)
+        ));
+        assert!(matches!(
+            MappedSignature::<u32, u32>::from_bytes(&bytes),
+            Err(MappedSignatureError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_records_are_found_on_lookup() {
+        let content = content();
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&content);
+        let mut bytes = Vec::new();
+        write_mapped_signature(&signature, &mut bytes).unwrap();
+        let record_len = MappedSignature::<u32, Md5Hash>::RECORD_LEN;
+        let records_start = bytes.len() - signature.chunk_count() * record_len;
+
+        let chunk_number = records_start + record_len - 8..records_start + record_len;
+        bytes[chunk_number].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mapped = MappedSignature::<u32, Md5Hash>::from_bytes(&bytes).unwrap();
+
+        assert!(matches!(
+            try_generate_delta::<RollingAdler32, Md5Sum>(&mapped, &content),
+            Err(DeltaError::ChunkOutOfRange {
+                chunk_num: u64::MAX,
+                ..
+            })
+        ));
+    }
+}
@@ -1,11 +1,23 @@
+use std::cmp::min;
 use std::fmt::Debug;
-use std::io::Write;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
 
 use log::debug;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use thiserror::Error;
 
-use crate::delta_generation::{Delta, DeltaToken};
+use crate::cancel::{CancellationToken, Cancelled};
+use crate::decode::DecodeError;
+use crate::delta_generation::{Delta, DeltaToken, BACK_REFERENCE_WINDOW};
+use crate::progress::{NoProgress, Phase, Progress, ProgressObserver};
 use crate::strong_hash::StrongHash;
+use crate::token_stream::{DeltaReader, DeltaVisitor};
+
+/// Fills and back-references are written in blocks of this size so that a huge one doesn't need a
+/// huge buffer
+const FILL_BLOCK_SIZE: u64 = 1 << 16;
 
 pub fn patch<S, W>(
     old_content: &[u8],
@@ -16,19 +28,171 @@ where
     S: StrongHash,
     W: Write,
 {
-    for token in delta.tokens {
+    patch_with_progress::<S, W>(
+        old_content,
+        delta,
+        out,
+        &NoProgress,
+        &CancellationToken::new(),
+    )
+}
+
+///
+/// Applies `delta` on top of `old_content` into `out`
+///
+/// `cancel` is checked between tokens and between the blocks of a fill or a back-reference. On any
+/// error, including [PatchError::Cancelled], part of the output may have been written already -
+/// write into a temporary file and move it in place once done to never leave a half-patched file
+/// behind.
+///
+pub fn patch_with_progress<S, W>(
+    old_content: &[u8],
+    delta: Delta<S::HashType>,
+    out: &mut W,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+) -> Result<(), PatchError>
+where
+    S: StrongHash,
+    W: Write,
+{
+    patch_with_options::<S, W>(
+        old_content,
+        delta,
+        out,
+        &PatchOptions::default(),
+        progress,
+        cancel,
+    )
+}
+
+/// How much of the old content is checked against the delta while patching
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// Each reused chunk and copied range must match the strong hash recorded in the delta
+    #[default]
+    ReusedChunks,
+    /// The old content is trusted to be the one the delta was generated against - saves hashing
+    /// every reused chunk, but patching the wrong content silently gives wrong output
+    None,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct PatchOptions {
+    pub verification: Verification,
+}
+
+///
+/// Like [patch_with_progress], with the checks done along the way picked by `options`
+///
+pub fn patch_with_options<S, W>(
+    old_content: &[u8],
+    delta: Delta<S::HashType>,
+    out: &mut W,
+    options: &PatchOptions,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+) -> Result<(), PatchError>
+where
+    S: StrongHash,
+    W: Write,
+{
+    let keep_history = delta
+        .tokens
+        .iter()
+        .any(|token| matches!(token, DeltaToken::BackReference(..)));
+    let mut patcher =
+        Patcher::<S, W>::new(old_content, out, keep_history, options, progress, cancel);
+    delta.accept(&mut patcher)
+}
+
+///
+/// Like [patch_with_options], reading the delta a token at a time from `delta`
+///
+/// Whether the delta refers back to the output is only known once it was read, so the last
+/// [BACK_REFERENCE_WINDOW] bytes written are kept in memory - no back-reference can reach further.
+///
+pub fn patch_from_reader<S, Rd, W>(
+    old_content: &[u8],
+    delta: DeltaReader<Rd, S::HashType>,
+    out: &mut W,
+    options: &PatchOptions,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+) -> Result<(), PatchError>
+where
+    S: StrongHash,
+    <S as StrongHash>::HashType: Serialize + DeserializeOwned,
+    Rd: Read,
+    W: Write,
+{
+    let mut patcher = Patcher::<S, W>::new(old_content, out, true, options, progress, cancel);
+    delta.accept(&mut patcher)
+}
+
+/// Writes the output of each token as it's visited
+struct Patcher<'p, S, W: Write> {
+    old_content: &'p [u8],
+    chunk_size: u64,
+    out: PatchOutput<'p, W>,
+    options: &'p PatchOptions,
+    progress: &'p dyn ProgressObserver,
+    cancel: &'p CancellationToken,
+    report: Progress,
+    strong_hash: PhantomData<S>,
+}
+
+impl<'p, S, W: Write> Patcher<'p, S, W> {
+    /// The chunk size comes with the header of the delta
+    fn new(
+        old_content: &'p [u8],
+        out: &'p mut W,
+        keep_history: bool,
+        options: &'p PatchOptions,
+        progress: &'p dyn ProgressObserver,
+        cancel: &'p CancellationToken,
+    ) -> Self {
+        Patcher {
+            old_content,
+            chunk_size: 0,
+            out: PatchOutput {
+                out,
+                history: keep_history.then(History::default),
+                written: 0,
+            },
+            options,
+            progress,
+            cancel,
+            report: Progress::new(Phase::Patch, None),
+            strong_hash: PhantomData,
+        }
+    }
+}
+
+impl<S, W> DeltaVisitor<S::HashType> for Patcher<'_, S, W>
+where
+    S: StrongHash,
+    W: Write,
+{
+    type Error = PatchError;
+
+    fn visit_header(&mut self, chunk_size: u64, _version: &str) -> Result<(), PatchError> {
+        self.chunk_size = chunk_size;
+        Ok(())
+    }
+
+    fn visit_token(&mut self, token: DeltaToken<S::HashType>) -> Result<(), PatchError> {
+        let (old_content, out, cancel) = (self.old_content, &mut self.out, self.cancel);
+        cancel.check()?;
+        if matches!(token, DeltaToken::Reused(..)) {
+            self.report.reused_chunks += 1;
+        }
         match token {
             DeltaToken::Reused(chunk_number, hash) => {
-                let chunk = old_content
-                    .chunks(delta.chunk_size as usize)
-                    .nth(chunk_number as usize)
-                    .ok_or(PatchError::ChunkOutOfBound {
-                        chunk_num: chunk_number,
-                        chunk_size: delta.chunk_size,
-                        old_content_len: old_content.len() as u64,
-                    })?;
+                let chunk = old_chunk(old_content, self.chunk_size, chunk_number)?;
 
-                if S::hash(chunk) != hash {
+                if self.options.verification == Verification::ReusedChunks && S::hash(chunk) != hash
+                {
                     return Err(PatchError::ChunkHashMismatch {
                         chunk_num: chunk_number,
                     });
@@ -37,14 +201,246 @@ where
                 out.write_all(chunk)?;
             }
             DeltaToken::Added(bytes) => {
-                out.write_all(bytes)?;
+                out.write_all(&bytes)?;
             }
             DeltaToken::Removed(chunk_number) => {
                 debug!("chunk {} removed", chunk_number);
             }
+            DeltaToken::Copied(offset, len, hash) => {
+                let range = offset
+                    .checked_add(len)
+                    .filter(|end| *end <= old_content.len() as u64)
+                    .map(|end| &old_content[offset as usize..end as usize])
+                    .ok_or(PatchError::RangeOutOfBound {
+                        offset,
+                        len,
+                        old_content_len: old_content.len() as u64,
+                    })?;
+
+                if self.options.verification == Verification::ReusedChunks && S::hash(range) != hash
+                {
+                    return Err(PatchError::RangeHashMismatch { offset, len });
+                }
+
+                out.write_all(range)?;
+            }
+            DeltaToken::BackReference(offset, len) => {
+                out.copy_back(offset, len, cancel)?;
+            }
+            DeltaToken::Diff(chunk_number, hash, runs) => {
+                let chunk = old_chunk(old_content, self.chunk_size, chunk_number)?;
+
+                if self.options.verification == Verification::ReusedChunks && S::hash(chunk) != hash
+                {
+                    return Err(PatchError::ChunkHashMismatch {
+                        chunk_num: chunk_number,
+                    });
+                }
+
+                let mut patched = chunk.to_vec();
+                for (offset, bytes) in runs {
+                    let run = offset
+                        .checked_add(bytes.len() as u64)
+                        .filter(|end| *end <= chunk.len() as u64)
+                        .map(|end| &mut patched[offset as usize..end as usize])
+                        .ok_or(PatchError::DiffLengthMismatch {
+                            chunk_num: chunk_number,
+                            chunk_len: chunk.len() as u64,
+                            diff_len: offset.saturating_add(bytes.len() as u64),
+                        })?;
+                    run.copy_from_slice(&bytes);
+                }
+                out.write_all(&patched)?;
+            }
+            DeltaToken::Fill(byte, len) => {
+                let block = vec![byte; min(len, FILL_BLOCK_SIZE) as usize];
+                let mut remaining = len;
+                while remaining > 0 {
+                    cancel.check()?;
+                    let block_len = min(remaining, FILL_BLOCK_SIZE);
+                    out.write_all(&block[..block_len as usize])?;
+                    remaining -= block_len;
+                }
+            }
+        }
+        self.report.processed_bytes = out.written;
+        self.progress.on_progress(&self.report);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), PatchError> {
+        self.progress.on_finish(&self.report);
+        Ok(())
+    }
+}
+
+fn old_chunk(old_content: &[u8], chunk_size: u64, chunk_number: u64) -> Result<&[u8], PatchError> {
+    // a chunk size of 0 has no chunks at all, rather than making the chunking panic
+    chunk_number
+        .checked_mul(chunk_size)
+        .filter(|start| chunk_size > 0 && *start < old_content.len() as u64)
+        .map(|start| {
+            let end = min(start.saturating_add(chunk_size), old_content.len() as u64);
+            &old_content[start as usize..end as usize]
+        })
+        .ok_or(PatchError::ChunkOutOfBound {
+            chunk_num: chunk_number,
+            chunk_size,
+            old_content_len: old_content.len() as u64,
+        })
+}
+
+/// Keeps the last [BACK_REFERENCE_WINDOW] bytes written when the delta refers back to them
+struct PatchOutput<'w, W: Write> {
+    out: &'w mut W,
+    history: Option<History>,
+    written: u64,
+}
+
+impl<W: Write> PatchOutput<'_, W> {
+    fn write_all(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        if let Some(history) = self.history.as_mut() {
+            history.push(bytes);
         }
+        self.written += bytes.len() as u64;
+        self.out.write_all(bytes)
+    }
+
+    fn copy_back(
+        &mut self,
+        offset: u64,
+        len: u64,
+        cancel: &CancellationToken,
+    ) -> Result<(), PatchError> {
+        let written = self.written;
+        if offset >= written || written - offset > BACK_REFERENCE_WINDOW {
+            return Err(PatchError::BackReferenceOutOfBound {
+                offset,
+                len,
+                written,
+            });
+        }
+
+        // the referenced range may overlap with the bytes being produced, repeating every
+        // `distance` bytes - any earlier repetition still in the window can be copied from
+        let distance = written - offset;
+        let mut remaining = len;
+        while remaining > 0 {
+            cancel.check()?;
+            let repetitions = (min(self.written - offset, BACK_REFERENCE_WINDOW) / distance).max(1);
+            let source = self.written - repetitions * distance;
+            let block_len = min(min(remaining, repetitions * distance), FILL_BLOCK_SIZE);
+            let history = self
+                .history
+                .as_ref()
+                .expect("history is kept for back-references");
+            let block = history.get(source, block_len).to_vec();
+            self.write_all(&block)?;
+            remaining -= block_len;
+        }
+        Ok(())
+    }
+}
+
+/// The tail of the output, at least [BACK_REFERENCE_WINDOW] bytes of it once there's that much
+#[derive(Default)]
+struct History {
+    bytes: Vec<u8>,
+    /// Position of `bytes[0]` in the output
+    start: u64,
+}
+
+impl History {
+    fn push(&mut self, bytes: &[u8]) {
+        let window = BACK_REFERENCE_WINDOW as usize;
+        if bytes.len() >= window {
+            self.start += (self.bytes.len() + bytes.len() - window) as u64;
+            self.bytes.clear();
+            self.bytes.extend_from_slice(&bytes[bytes.len() - window..]);
+            return;
+        }
+        self.bytes.extend_from_slice(bytes);
+        // dropping the front only once it's as long as the window keeps it amortized
+        if self.bytes.len() >= 2 * window {
+            let dropped = self.bytes.len() - window;
+            self.bytes.drain(..dropped);
+            self.start += dropped as u64;
+        }
+    }
+
+    fn get(&self, offset: u64, len: u64) -> &[u8] {
+        let start = (offset - self.start) as usize;
+        &self.bytes[start..start + len as usize]
+    }
+}
+
+/// Zero writes of at least that size become holes
+const MIN_HOLE_SIZE: usize = 1 << 12;
+
+///
+/// Turns big all-zero writes into seeks, leaving holes in files that support sparse regions
+///
+/// Meant for fresh outputs only - the skipped regions keep whatever was there before.
+/// [SparseWriter::finish] has to be called at the end so that a trailing hole makes it to the file length.
+///
+/// ```
+/// use std::io::{Cursor, Write};
+/// use rolling_in_the_diff::patch::SparseWriter;
+///
+/// let mut out = SparseWriter::new(Cursor::new(Vec::new()));
+/// out.write_all(&[1, 2, 3]).unwrap();
+/// out.write_all(&[0; 1 << 13]).unwrap();
+///
+/// let out = out.finish().unwrap().into_inner();
+/// assert_eq!(out.len(), 3 + (1 << 13));
+/// ```
+pub struct SparseWriter<W: Write + Seek> {
+    inner: W,
+    pending_hole: u64,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    pub fn new(inner: W) -> Self {
+        SparseWriter {
+            inner,
+            pending_hole: 0,
+        }
+    }
+
+    fn skip_pending_hole(&mut self) -> std::io::Result<()> {
+        if self.pending_hole > 0 {
+            self.inner
+                .seek(SeekFrom::Current(self.pending_hole as i64))?;
+            self.pending_hole = 0;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> std::io::Result<W> {
+        if self.pending_hole > 0 {
+            // writing the very last byte is what extends the file over the hole
+            self.pending_hole -= 1;
+            self.skip_pending_hole()?;
+            self.inner.write_all(&[0])?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write + Seek> Write for SparseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.len() >= MIN_HOLE_SIZE && buf.iter().all(|&b| b == 0) {
+            self.pending_hole += buf.len() as u64;
+            return Ok(buf.len());
+        }
+        self.skip_pending_hole()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
     }
-    Ok(())
 }
 
 #[derive(Error, Debug)]
@@ -55,8 +451,270 @@ pub enum PatchError {
         chunk_size: u64,
         old_content_len: u64,
     },
+    #[error("range {offset}+{len} is out of bound: {old_content_len}")]
+    RangeOutOfBound {
+        offset: u64,
+        len: u64,
+        old_content_len: u64,
+    },
+    #[error("back-reference {offset}+{len} is out of bound: {written}")]
+    BackReferenceOutOfBound { offset: u64, len: u64, written: u64 },
+    #[error("diff of chunk {chunk_num} goes past its end: {chunk_len} {diff_len}")]
+    DiffLengthMismatch {
+        chunk_num: u64,
+        chunk_len: u64,
+        diff_len: u64,
+    },
     #[error("hash mismatch on chunk {chunk_num}")]
     ChunkHashMismatch { chunk_num: u64 },
+    #[error("hash mismatch on range {offset}+{len}")]
+    RangeHashMismatch { offset: u64, len: u64 },
     #[error("output error")]
     OutputFailure(#[from] std::io::Error),
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+    #[error("invalid delta")]
+    Decode(#[from] DecodeError),
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+    use std::io::Cursor;
+
+    use crate::delta_generation::DeltaToken::{Added, BackReference, Copied, Diff, Fill, Reused};
+    use crate::format::{encode, Format, Kind};
+    use crate::strong_hash::md5::Md5Sum;
+    use crate::DEFAULT_VERSION;
+
+    use super::*;
+
+    #[test]
+    fn test_patch_with_overlapping_back_reference() {
+        let delta = Delta {
+            tokens: vec![Added(Cow::Borrowed(&[1, 2])), BackReference(0, 5)],
+            chunk_size: 0,
+            version: DEFAULT_VERSION.to_string(),
+        };
+
+        let mut out = Vec::new();
+        patch::<Md5Sum, _>(&[], delta, &mut out).unwrap();
+        assert_eq!(out, [1, 2, 1, 2, 1, 2, 1]);
+    }
+
+    #[test]
+    fn test_patch_from_reader() {
+        let old_content = [1, 2, 3, 4, 5, 6];
+        let delta = Delta {
+            tokens: vec![
+                Reused(1, Md5Sum::hash(&[4, 5, 6])),
+                Added(Cow::Borrowed(&[7])),
+                BackReference(1, 3),
+            ],
+            chunk_size: 3,
+            version: DEFAULT_VERSION.to_string(),
+        };
+        let mut encoded = Vec::new();
+        encode(&delta, Kind::Delta, Format::Bincode, &mut encoded).unwrap();
+        let reader = DeltaReader::new(encoded.as_slice(), &Default::default()).unwrap();
+
+        let mut out = Vec::new();
+        let options = PatchOptions::default();
+        let cancel = CancellationToken::new();
+        patch_from_reader::<Md5Sum, _, _>(
+            &old_content,
+            reader,
+            &mut out,
+            &options,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(out, [4, 5, 6, 7, 5, 6, 7]);
+
+        let truncated = &encoded[..encoded.len() - 1];
+        let reader = DeltaReader::new(truncated, &Default::default()).unwrap();
+        let result = patch_from_reader::<Md5Sum, _, _>(
+            &old_content,
+            reader,
+            &mut out,
+            &options,
+            &NoProgress,
+            &cancel,
+        );
+        assert!(matches!(result, Err(PatchError::Decode(_))));
+    }
+
+    #[test]
+    fn test_patch_with_fill() {
+        let delta = Delta {
+            tokens: vec![
+                Added(Cow::Borrowed(&[1])),
+                Fill(0, 3 * FILL_BLOCK_SIZE / 2),
+                Fill(7, 2),
+            ],
+            chunk_size: 0,
+            version: DEFAULT_VERSION.to_string(),
+        };
+
+        let mut out = Vec::new();
+        patch::<Md5Sum, _>(&[], delta, &mut out).unwrap();
+        assert_eq!(
+            out,
+            [
+                &[1][..],
+                &vec![0; 3 * FILL_BLOCK_SIZE as usize / 2],
+                &[7, 7]
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_cancelled_patch() {
+        let delta = Delta {
+            tokens: vec![Added(Cow::Borrowed(&[1])), Fill(0, 1 << 40)],
+            chunk_size: 0,
+            version: DEFAULT_VERSION.to_string(),
+        };
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let mut out = Vec::new();
+        assert!(matches!(
+            patch_with_progress::<Md5Sum, _>(&[], delta, &mut out, &NoProgress, &cancel),
+            Err(PatchError::Cancelled(_))
+        ));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_sparse_writer_with_holes_in_between() {
+        let mut out = SparseWriter::new(Cursor::new(vec![]));
+        out.write_all(&[0; MIN_HOLE_SIZE]).unwrap();
+        out.write_all(&[1]).unwrap();
+        out.write_all(&[0; MIN_HOLE_SIZE]).unwrap();
+        out.write_all(&[0; 2]).unwrap();
+
+        let out = out.finish().unwrap().into_inner();
+        let mut expected = vec![0; 2 * MIN_HOLE_SIZE + 3];
+        expected[MIN_HOLE_SIZE] = 1;
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_patch_with_back_reference_out_of_bound() {
+        let delta = Delta {
+            tokens: vec![Added(Cow::Borrowed(&[1, 2])), BackReference(2, 1)],
+            chunk_size: 0,
+            version: DEFAULT_VERSION.to_string(),
+        };
+
+        assert!(matches!(
+            patch::<Md5Sum, _>(&[], delta, &mut Vec::new()),
+            Err(PatchError::BackReferenceOutOfBound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_patch_with_zero_chunk_size() {
+        let delta = Delta {
+            tokens: vec![Reused(0, Md5Sum::hash(&[1, 2, 3]))],
+            chunk_size: 0,
+            version: DEFAULT_VERSION.to_string(),
+        };
+
+        assert!(matches!(
+            patch::<Md5Sum, _>(&[1, 2, 3], delta, &mut Vec::new()),
+            Err(PatchError::ChunkOutOfBound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_patch_with_copied_range_hash_mismatch() {
+        let delta = || Delta {
+            tokens: vec![Copied(1, 2, Md5Sum::hash(&[2, 3]))],
+            chunk_size: 3,
+            version: DEFAULT_VERSION.to_string(),
+        };
+
+        let mut out = Vec::new();
+        patch::<Md5Sum, _>(&[1, 2, 3], delta(), &mut out).unwrap();
+        assert_eq!(out, [2, 3]);
+
+        assert!(matches!(
+            patch::<Md5Sum, _>(&[1, 2, 4], delta(), &mut Vec::new()),
+            Err(PatchError::RangeHashMismatch { offset: 1, len: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_patch_with_long_back_reference() {
+        let len = 3 * FILL_BLOCK_SIZE + 1;
+        let delta = Delta {
+            tokens: vec![Added(Cow::Borrowed(&[1, 2, 3])), BackReference(1, len)],
+            chunk_size: 0,
+            version: DEFAULT_VERSION.to_string(),
+        };
+
+        let mut out = Vec::new();
+        patch::<Md5Sum, _>(&[], delta, &mut out).unwrap();
+        let expected: Vec<u8> = [1].into_iter().chain([2, 3].repeat(len as usize)).collect();
+        assert_eq!(out, expected[..3 + len as usize]);
+    }
+
+    #[test]
+    fn test_patch_with_back_reference_beyond_the_window() {
+        let delta = Delta {
+            tokens: vec![
+                Added(Cow::Borrowed(&[1])),
+                Fill(0, BACK_REFERENCE_WINDOW),
+                BackReference(0, 1),
+            ],
+            chunk_size: 0,
+            version: DEFAULT_VERSION.to_string(),
+        };
+
+        assert!(matches!(
+            patch::<Md5Sum, _>(&[], delta, &mut Vec::new()),
+            Err(PatchError::BackReferenceOutOfBound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_history_keeps_the_window() {
+        let window = BACK_REFERENCE_WINDOW as usize;
+        let mut history = History::default();
+        history.push(&[1; 3]);
+        history.push(&vec![2; 2 * window]);
+        assert_eq!(history.start, window as u64 + 3);
+        assert_eq!(history.get(window as u64 + 3, 1), [2]);
+
+        history.push(&[3; 2]);
+        history.push(&vec![4; window - 2]);
+        assert!(history.bytes.len() < 2 * window);
+        assert_eq!(history.get(2 * window as u64 + 3, 2), [3, 3]);
+    }
+
+    #[test]
+    fn test_patch_with_diff() {
+        let delta = |old_chunk: &[u8]| Delta {
+            tokens: vec![Diff(1, Md5Sum::hash(old_chunk), vec![(1, vec![7])])],
+            chunk_size: 3,
+            version: DEFAULT_VERSION.to_string(),
+        };
+
+        let mut out = Vec::new();
+        patch::<Md5Sum, _>(&[1, 2, 3, 4, 5, 6], delta(&[4, 5, 6]), &mut out).unwrap();
+        assert_eq!(out, [4, 7, 6]);
+
+        assert!(matches!(
+            patch::<Md5Sum, _>(&[1, 2, 3, 4, 0, 6], delta(&[4, 5, 6]), &mut Vec::new()),
+            Err(PatchError::ChunkHashMismatch { chunk_num: 1 })
+        ));
+        assert!(matches!(
+            patch::<Md5Sum, _>(&[1, 2, 3, 4], delta(&[4]), &mut Vec::new()),
+            Err(PatchError::DiffLengthMismatch { .. })
+        ));
+    }
 }
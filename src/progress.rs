@@ -0,0 +1,125 @@
+//!
+//! Progress reporting of the long-running operations
+//!
+//! The library doesn't draw anything itself - the operations report to a [ProgressObserver] and
+//! the caller decides what to do with it (a terminal bar, a GUI, log lines or nothing at all).
+//!
+
+use std::fmt::{Display, Formatter};
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Signature,
+    Delta,
+    Patch,
+    Reconstruct,
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Phase::Signature => "signature",
+            Phase::Delta => "delta",
+            Phase::Patch => "patch",
+            Phase::Reconstruct => "reconstruct",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Progress {
+    pub phase: Phase,
+    /// Bytes of content hashed, scanned or written so far
+    pub processed_bytes: u64,
+    /// Bytes the phase is going to process, when known up front
+    pub total_bytes: Option<u64>,
+    /// Old chunks reused so far
+    pub reused_chunks: u64,
+}
+
+impl Progress {
+    pub fn new(phase: Phase, total_bytes: Option<u64>) -> Self {
+        Progress {
+            phase,
+            processed_bytes: 0,
+            total_bytes,
+            reused_chunks: 0,
+        }
+    }
+}
+
+///
+/// Receives the progress of an operation
+///
+/// Signatures are generated in parallel, so observers may be called from several threads.
+///
+/// Closures are observers too:
+///
+/// ```
+/// use std::sync::atomic::{AtomicU64, Ordering};
+/// use rolling_in_the_diff::progress::Progress;
+/// use rolling_in_the_diff::rolling_checksum::rolling_adler32::RollingAdler32;
+/// use rolling_in_the_diff::signature_generation::generate_signature_with_progress;
+/// use rolling_in_the_diff::strong_hash::md5::Md5Sum;
+///
+/// let processed = AtomicU64::new(0);
+/// let content = vec![1; 1 << 16];
+/// generate_signature_with_progress::<RollingAdler32, Md5Sum>(&content, &|progress: &Progress| {
+///     processed.fetch_max(progress.processed_bytes, Ordering::Relaxed);
+/// });
+/// assert_eq!(processed.into_inner(), content.len() as u64);
+/// ```
+pub trait ProgressObserver: Sync {
+    fn on_progress(&self, progress: &Progress);
+
+    /// Called once the phase is done, with the final numbers
+    fn on_finish(&self, progress: &Progress) {
+        self.on_progress(progress)
+    }
+}
+
+impl<F: Fn(&Progress) + Sync> ProgressObserver for F {
+    fn on_progress(&self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// Ignores the progress
+pub struct NoProgress;
+
+impl ProgressObserver for NoProgress {
+    fn on_progress(&self, _: &Progress) {}
+}
+
+///
+/// Reports the progress of a single file as part of the progress over a whole tree
+///
+/// The files only finish the phase of the tree once the last one is done.
+///
+pub(crate) struct TreeFileProgress<'a> {
+    pub(crate) inner: &'a dyn ProgressObserver,
+    /// Bytes of the tree processed before this file
+    pub(crate) offset: u64,
+    pub(crate) total_bytes: u64,
+    /// Chunks of the tree reused before this file
+    pub(crate) reused_chunks: u64,
+}
+
+impl ProgressObserver for TreeFileProgress<'_> {
+    fn on_progress(&self, progress: &Progress) {
+        self.inner.on_progress(&Progress {
+            processed_bytes: self.offset + progress.processed_bytes,
+            total_bytes: Some(self.total_bytes),
+            reused_chunks: self.reused_chunks + progress.reused_chunks,
+            ..*progress
+        })
+    }
+
+    fn on_finish(&self, progress: &Progress) {
+        self.on_progress(progress)
+    }
+}
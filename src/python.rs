@@ -0,0 +1,218 @@
+//!
+//! Python bindings, with the rolling Adler-32 and MD5 the command line tool uses
+//!
+//! ```python
+//! import rolling_in_the_diff as ritd
+//!
+//! signature = ritd.generate_signature(open("old", "rb"))
+//! delta = ritd.generate_delta(signature, open("new", "rb"))
+//! assert ritd.patch(open("old", "rb"), delta) == open("new", "rb").read()
+//! ```
+//!
+//! Contents can be bytes-like or file-like objects (anything with a `read()` returning bytes).
+//! The hashing and patching run without holding the GIL.
+//!
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::pybacked::PyBackedBytes;
+use pyo3::types::PyBytes;
+
+use crate::cancel::CancellationToken;
+use crate::decode::{decode_delta, decode_signature, DecodeLimits};
+use crate::format::{encode, Format, Kind};
+use crate::progress::NoProgress;
+use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+use crate::strong_hash::md5::Md5Sum;
+use crate::strong_hash::StrongHash;
+use crate::{delta_generation, patch, signature_generation, Signature};
+
+type Md5Hash = <Md5Sum as StrongHash>::HashType;
+
+create_exception!(
+    rolling_in_the_diff,
+    Error,
+    PyException,
+    "Base class of the errors of the module"
+);
+create_exception!(rolling_in_the_diff, SignatureError, Error);
+create_exception!(rolling_in_the_diff, DeltaError, Error);
+create_exception!(rolling_in_the_diff, DecodeError, Error);
+create_exception!(rolling_in_the_diff, PatchError, Error);
+
+/// The signature of some content, picklable and convertible to and from bytes
+#[pyclass(name = "Signature", module = "rolling_in_the_diff", frozen)]
+struct PySignature(Signature<u32, Md5Hash>);
+
+#[pymethods]
+impl PySignature {
+    /// Decodes a signature from `to_bytes` or the command line tool
+    #[staticmethod]
+    fn from_bytes(py: Python<'_>, bytes: PyBackedBytes) -> PyResult<Self> {
+        py.detach(|| decode_signature(&bytes, &DecodeLimits::default()))
+            .map(PySignature)
+            .map_err(|e| DecodeError::new_err(e.to_string()))
+    }
+
+    fn to_bytes<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let mut bytes = Vec::new();
+        encode(&self.0, Kind::Signature, Format::Bincode, &mut bytes)
+            .map_err(|e| Error::new_err(e.to_string()))?;
+        Ok(PyBytes::new(py, &bytes))
+    }
+
+    #[getter]
+    fn chunk_size(&self) -> usize {
+        self.0.chunk_size
+    }
+
+    #[getter]
+    fn chunk_count(&self) -> usize {
+        self.0.chunk_count
+    }
+
+    #[getter]
+    fn version(&self) -> &str {
+        &self.0.version
+    }
+
+    fn __reduce__<'py>(
+        slf: &Bound<'py, Self>,
+    ) -> PyResult<(Bound<'py, PyAny>, (Bound<'py, PyBytes>,))> {
+        let from_bytes = slf.get_type().getattr("from_bytes")?;
+        Ok((from_bytes, (slf.get().to_bytes(slf.py())?,)))
+    }
+}
+
+/// The bytes of a bytes-like object, or everything a file-like object reads
+fn read_content(content: &Bound<'_, PyAny>) -> PyResult<PyBackedBytes> {
+    match content.extract::<PyBackedBytes>() {
+        Ok(bytes) => Ok(bytes),
+        Err(_) if content.hasattr("read")? => Ok(content.call_method0("read")?.extract()?),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The signature of `content`, to compute deltas against
+#[pyfunction]
+fn generate_signature(py: Python<'_>, content: &Bound<'_, PyAny>) -> PyResult<PySignature> {
+    let content = read_content(content)?;
+    py.detach(|| {
+        signature_generation::try_generate_signature::<RollingAdler32, Md5Sum, _>(
+            &mut &content[..],
+            &NoProgress,
+            &CancellationToken::new(),
+        )
+    })
+    .map(PySignature)
+    .map_err(|e| SignatureError::new_err(e.to_string()))
+}
+
+/// The delta between `signature` and `new_content`, encoded like the command line tool does
+#[pyfunction]
+fn generate_delta<'py>(
+    py: Python<'py>,
+    signature: &PySignature,
+    new_content: &Bound<'py, PyAny>,
+) -> PyResult<Bound<'py, PyBytes>> {
+    let new_content = read_content(new_content)?;
+    let delta = py.detach(|| {
+        let delta = delta_generation::try_generate_delta::<RollingAdler32, Md5Sum>(
+            &signature.0,
+            &new_content,
+        )
+        .map_err(|e| DeltaError::new_err(e.to_string()))?;
+        let mut bytes = Vec::new();
+        encode(&delta, Kind::Delta, Format::Bincode, &mut bytes)
+            .map_err(|e| Error::new_err(e.to_string()))?;
+        Ok::<_, PyErr>(bytes)
+    })?;
+    Ok(PyBytes::new(py, &delta))
+}
+
+/// Applies the encoded `delta` on top of `old_content`
+#[pyfunction(name = "patch")]
+fn apply_patch<'py>(
+    py: Python<'py>,
+    old_content: &Bound<'py, PyAny>,
+    delta: &Bound<'py, PyAny>,
+) -> PyResult<Bound<'py, PyBytes>> {
+    let old_content = read_content(old_content)?;
+    let delta = read_content(delta)?;
+    let patched_content = py.detach(|| {
+        let delta = decode_delta::<Md5Hash>(&delta, &DecodeLimits::default())
+            .map_err(|e| DecodeError::new_err(e.to_string()))?;
+        let mut patched_content = Vec::new();
+        patch::patch::<Md5Sum, _>(&old_content, delta, &mut patched_content)
+            .map_err(|e| PatchError::new_err(e.to_string()))?;
+        Ok::<_, PyErr>(patched_content)
+    })?;
+    Ok(PyBytes::new(py, &patched_content))
+}
+
+#[pymodule]
+fn rolling_in_the_diff(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add_class::<PySignature>()?;
+    m.add_function(wrap_pyfunction!(generate_signature, m)?)?;
+    m.add_function(wrap_pyfunction!(generate_delta, m)?)?;
+    m.add_function(wrap_pyfunction!(apply_patch, m)?)?;
+    m.add("Error", py.get_type::<Error>())?;
+    m.add("SignatureError", py.get_type::<SignatureError>())?;
+    m.add("DeltaError", py.get_type::<DeltaError>())?;
+    m.add("DecodeError", py.get_type::<DecodeError>())?;
+    m.add("PatchError", py.get_type::<PatchError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use pyo3::types::PyDict;
+
+    use super::*;
+
+    #[test]
+    fn test_python_round_trip() {
+        Python::initialize();
+        Python::attach(|py| {
+            let module = pyo3::wrap_pymodule!(rolling_in_the_diff)(py);
+            // registered like an installed extension module, for pickle to find it
+            py.import("sys")
+                .unwrap()
+                .getattr("modules")
+                .unwrap()
+                .set_item("rolling_in_the_diff", &module)
+                .unwrap();
+            let locals = PyDict::new(py);
+            locals.set_item("ritd", module).unwrap();
+            py.run(
+                cr#"
+import io
+import pickle
+
+old = bytes(x * 31 % 251 for x in range(1 << 14))
+new = old[:1000] + b"*" * 100 + old[1100:]
+
+signature = ritd.generate_signature(io.BytesIO(old))
+signature = pickle.loads(pickle.dumps(signature))
+assert signature.chunk_count > 0
+assert ritd.Signature.from_bytes(signature.to_bytes()).to_bytes() == signature.to_bytes()
+
+delta = ritd.generate_delta(signature, bytearray(new))
+assert ritd.patch(io.BytesIO(old), delta) == new
+
+for content, error in [(new, ritd.PatchError), (old, ritd.DecodeError)]:
+    try:
+        ritd.patch(content, delta[: len(delta) // 2] if error is ritd.DecodeError else delta)
+        raise AssertionError("no error raised")
+    except error as e:
+        assert isinstance(e, ritd.Error)
+"#,
+                None,
+                Some(&locals),
+            )
+            .unwrap();
+        });
+    }
+}
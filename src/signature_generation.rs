@@ -1,15 +1,55 @@
-use std::collections::HashMap;
 use std::hash::Hash;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use log::info;
-use rayon::iter::{IndexedParallelIterator, ParallelIterator};
+use rayon::iter::ParallelIterator;
 use rayon::slice::ParallelSlice;
+use thiserror::Error;
 
+use crate::cancel::{CancellationToken, Cancelled};
+use crate::progress::{NoProgress, Phase, Progress, ProgressObserver};
 use crate::rolling_checksum::RollingChecksum;
 use crate::strong_hash::StrongHash;
 use crate::{ChunkNumber, Signature};
 
 pub fn generate_signature<R, S>(content: &[u8]) -> Signature<R::ChecksumType, S::HashType>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash,
+    S: StrongHash,
+    <R as RollingChecksum>::ChecksumType: Send + Copy,
+    <S as StrongHash>::HashType: Send,
+{
+    generate_signature_with_progress::<R, S>(content, &NoProgress)
+}
+
+pub fn generate_signature_with_progress<R, S>(
+    content: &[u8],
+    progress: &dyn ProgressObserver,
+) -> Signature<R::ChecksumType, S::HashType>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash,
+    S: StrongHash,
+    <R as RollingChecksum>::ChecksumType: Send + Copy,
+    <S as StrongHash>::HashType: Send,
+{
+    cancellable_signature::<R, S>(
+        content,
+        &ChunkSizePolicy::default(),
+        progress,
+        &CancellationToken::new(),
+    )
+    .expect("nothing cancels a new token")
+}
+
+pub(crate) fn cancellable_signature<R, S>(
+    content: &[u8],
+    chunk_size_policy: &ChunkSizePolicy,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+) -> Result<Signature<R::ChecksumType, S::HashType>, Cancelled>
 where
     R: RollingChecksum,
     <R as RollingChecksum>::ChecksumType: Eq + Hash,
@@ -18,15 +58,12 @@ where
     <S as StrongHash>::HashType: Send,
 {
     let version = crate::VERSION.unwrap_or(crate::DEFAULT_VERSION).to_string();
+    let mut report = Progress::new(Phase::Signature, Some(content.len() as u64));
     if content.is_empty() {
-        return Signature {
-            checksum_to_hashes: HashMap::<R::ChecksumType, Vec<(S::HashType, ChunkNumber)>>::new(),
-            chunk_size: 0,
-            chunk_count: 0,
-            version,
-        };
+        progress.on_finish(&report);
+        return Ok(Signature::from_ordered_chunks(Vec::new(), 0, version));
     }
-    let chunk_size = determine_chunk_size::<R::ChecksumType, S::HashType>(content.len());
+    let chunk_size = chunk_size_policy.chunk_size::<R::ChecksumType, S::HashType>(content.len());
     info!(
         "content len: {} chunk count: {}; chunk size: {}",
         content.len(),
@@ -35,39 +72,114 @@ where
     );
 
     // calculate checksum + hash for each chunk in parallel
-    let checksum_hash_tuples: Vec<(usize, R::ChecksumType, S::HashType)> = content
+    let processed_bytes = AtomicU64::new(0);
+    let checksum_hash_tuples: Vec<(R::ChecksumType, S::HashType)> = content
         .par_chunks(chunk_size)
-        .enumerate()
-        .map(|(chunk_number, chunk)| {
+        .map(|chunk| {
+            cancel.check()?;
             let checksum = R::new(chunk).checksum();
             let hash = S::hash(chunk);
-            (chunk_number, checksum, hash)
+            let processed = processed_bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed)
+                + chunk.len() as u64;
+            progress.on_progress(&Progress {
+                processed_bytes: processed,
+                ..report
+            });
+            Ok((checksum, hash))
         })
-        .collect();
-
-    let mut signature_map: HashMap<R::ChecksumType, Vec<(S::HashType, ChunkNumber)>> =
-        HashMap::with_capacity(checksum_hash_tuples.len());
+        .collect::<Result<_, Cancelled>>()?;
 
-    let chunk_count = checksum_hash_tuples.len();
     // go through all chunks sequentially - if this is too slow,
     // concurrent hash maps are an option that might speed things up
-    for (chunk_number, checksum, hash) in checksum_hash_tuples {
-        signature_map
-            .entry(checksum)
-            .or_insert_with(|| Vec::with_capacity(1))
-            .push((hash, chunk_number as ChunkNumber));
-    }
+    let signature = Signature::from_ordered_chunks(checksum_hash_tuples, chunk_size, version);
+    report.processed_bytes = content.len() as u64;
+    progress.on_finish(&report);
+    Ok(signature)
+}
+
+///
+/// Generates the signature of everything `content` yields
+///
+/// The content is read into memory first, the chunks are hashed in parallel. `cancel` is checked
+/// between reads and between chunks.
+///
+pub fn try_generate_signature<R, S, Rd>(
+    content: &mut Rd,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+) -> Result<Signature<R::ChecksumType, S::HashType>, SignatureError>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash,
+    S: StrongHash,
+    <R as RollingChecksum>::ChecksumType: Send + Copy,
+    <S as StrongHash>::HashType: Send,
+    Rd: Read,
+{
+    let buffer = read_to_end(content, cancel)?;
+    Ok(cancellable_signature::<R, S>(
+        &buffer,
+        &ChunkSizePolicy::default(),
+        progress,
+        cancel,
+    )?)
+}
 
-    Signature {
-        checksum_to_hashes: signature_map,
-        chunk_size,
-        chunk_count,
-        version,
+/// Reads everything `content` yields, checking `cancel` between reads
+pub(crate) fn read_to_end<Rd: Read>(
+    content: &mut Rd,
+    cancel: &CancellationToken,
+) -> Result<Vec<u8>, SignatureError> {
+    let mut buffer = Vec::new();
+    loop {
+        cancel.check()?;
+        if content.take(READ_STEP).read_to_end(&mut buffer)? == 0 {
+            return Ok(buffer);
+        }
     }
 }
 
+/// How much content is read between two checks of the cancellation
+pub(crate) const READ_STEP: u64 = 1 << 20;
+
+#[derive(Error, Debug)]
+pub enum SignatureError {
+    #[error("failed to read the content")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+}
+
 const MAGIC_CHUNK_COUNT: usize = (1 << 10) << 2;
 
+/// How the chunk size of a signature is picked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkSizePolicy {
+    /// Splits the content into at most this many chunks, fewer when the hashes would outweigh the
+    /// content (see [determine_chunk_size])
+    MaxChunkCount(usize),
+    /// Always uses this chunk size, capped by the content length
+    Fixed(usize),
+}
+
+impl Default for ChunkSizePolicy {
+    fn default() -> Self {
+        ChunkSizePolicy::MaxChunkCount(MAGIC_CHUNK_COUNT)
+    }
+}
+
+impl ChunkSizePolicy {
+    /// The chunk size for content of `content_len` bytes, hashed into `R` checksums and `S` hashes
+    pub fn chunk_size<R, S>(&self, content_len: usize) -> usize {
+        match *self {
+            ChunkSizePolicy::MaxChunkCount(max_chunk_count) => {
+                chunk_size_for_count::<R, S>(content_len, max_chunk_count)
+            }
+            ChunkSizePolicy::Fixed(chunk_size) => chunk_size.max(1).min(content_len),
+        }
+    }
+}
+
 ///
 /// Determines a "good" chunk size based on the content length
 ///
@@ -85,10 +197,14 @@ const MAGIC_CHUNK_COUNT: usize = (1 << 10) << 2;
 /// assert_eq!(determine_chunk_size::<u8, u8>(content_len), content_len / 16);
 /// ```
 pub fn determine_chunk_size<R, S>(content_len: usize) -> usize {
+    chunk_size_for_count::<R, S>(content_len, MAGIC_CHUNK_COUNT)
+}
+
+fn chunk_size_for_count<R, S>(content_len: usize, max_chunk_count: usize) -> usize {
     let overhead_per_chunk =
         std::mem::size_of::<R>() + std::mem::size_of::<S>() + std::mem::size_of::<ChunkNumber>();
 
-    let mut chunk_count = MAGIC_CHUNK_COUNT;
+    let mut chunk_count = max_chunk_count;
     while chunk_count > 0 {
         let overhead = chunk_count * overhead_per_chunk;
         if overhead >= content_len {
@@ -109,10 +225,13 @@ pub fn determine_chunk_size<R, S>(content_len: usize) -> usize {
 
 #[cfg(test)]
 mod test {
+    use std::time::Instant;
+
     use adler32::RollingAdler32 as actual_adler32;
     use test_case::test_case;
 
     use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+    use crate::signature_index::SignatureIndex;
     use crate::strong_hash::md5::Md5Sum;
     use crate::strong_hash::StrongHash;
 
@@ -133,8 +252,7 @@ mod test {
         );
         for (chunk_number, chunk) in content.chunks(signature.chunk_size).enumerate() {
             let checksum = actual_adler32::from_buffer(chunk).hash();
-            assert!(signature.checksum_to_hashes.contains_key(&checksum));
-            let chunks = signature.checksum_to_hashes.get(&checksum).unwrap();
+            let chunks: Vec<_> = signature.quick_query(&checksum).collect();
 
             assert!(chunks.contains(&(Md5Sum::hash(chunk), chunk_number as ChunkNumber)))
         }
@@ -146,7 +264,41 @@ mod test {
         let signature = generate_signature::<RollingAdler32, Md5Sum>(content);
         assert_eq!(signature.chunk_count, 0);
         assert_eq!(signature.chunk_size, 0);
-        assert!(signature.checksum_to_hashes.is_empty());
+        assert!(signature.records.is_empty());
+    }
+
+    #[test]
+    fn test_signature_generation_past_deadline() {
+        let content = vec![1; 1 << 16];
+        let cancel = CancellationToken::with_deadline(Instant::now());
+
+        assert!(matches!(
+            try_generate_signature::<RollingAdler32, Md5Sum, _>(
+                &mut content.as_slice(),
+                &NoProgress,
+                &cancel
+            ),
+            Err(SignatureError::Cancelled(_))
+        ));
+    }
+
+    #[test]
+    fn test_signature_serialization_is_deterministic() {
+        let content: Vec<u8> = (0..1 << 12).map(|x| (x * 13 % 251) as u8).collect();
+
+        let serialized =
+            bincode2::serialize(&generate_signature::<RollingAdler32, Md5Sum>(&content)).unwrap();
+        let serialized_again =
+            bincode2::serialize(&generate_signature::<RollingAdler32, Md5Sum>(&content)).unwrap();
+        assert_eq!(serialized, serialized_again);
+
+        let deserialized: Signature<u32, <Md5Sum as StrongHash>::HashType> =
+            bincode2::deserialize(&serialized).unwrap();
+        assert_eq!(bincode2::serialize(&deserialized).unwrap(), serialized);
+        assert_eq!(
+            deserialized.records,
+            generate_signature::<RollingAdler32, Md5Sum>(&content).records
+        );
     }
 
     struct DummyRolling {}
@@ -181,14 +333,13 @@ mod test {
         let signature = generate_signature::<DummyRolling, DummyHash>(&content);
 
         assert!(signature.chunk_count > 1);
-        assert_eq!(signature.checksum_to_hashes.keys().len(), 1);
+        let chunk_numbers: Vec<_> = signature
+            .quick_query(&DUMMY_CHECKSUM)
+            .map(|(_, chunk_number)| chunk_number)
+            .collect();
         assert_eq!(
-            signature
-                .checksum_to_hashes
-                .get(&DUMMY_CHECKSUM)
-                .unwrap()
-                .len(),
-            signature.chunk_count
+            chunk_numbers,
+            (0..signature.chunk_count as ChunkNumber).collect::<Vec<_>>()
         );
     }
 }
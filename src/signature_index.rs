@@ -0,0 +1,167 @@
+//!
+//! The lookups the delta generation needs from a signature, and the pieces shared by the
+//! implementations: the in-memory [Signature](crate::Signature) and the memory-mapped
+//! [MappedSignature](crate::mapped_signature::MappedSignature)
+//!
+
+use std::hash::{Hash, Hasher};
+
+use crate::ChunkNumber;
+
+///
+/// Read access to the chunks of a signature
+///
+pub trait SignatureIndex<W, S> {
+    fn chunk_size(&self) -> usize;
+
+    fn chunk_count(&self) -> usize;
+
+    /// The version of the tool the signature was built with
+    fn version(&self) -> &str;
+
+    /// The (strong hash, chunk number) of every chunk with the given weak checksum, ordered by chunk number
+    fn quick_query(&self, weak_checksum: &W) -> impl Iterator<Item = (S, ChunkNumber)>;
+
+    /// The strong hashes indexed by chunk number
+    fn strong_hashes_by_chunk(&self) -> Vec<Option<S>>;
+}
+
+/// Bits of prefilter per chunk - about 12% false positives with a single hash
+const PREFILTER_BITS_PER_CHUNK: usize = 8;
+
+///
+/// The key the signature records are sorted by
+///
+/// It has to be stable across runs and platforms, so std's randomly seeded hashers are out.
+/// FNV-1a followed by a finalizer that spreads the bits, so the top bits can index the prefilter.
+///
+pub(crate) fn weak_key<W: Hash>(weak: &W) -> u64 {
+    let mut hasher = FnvHasher(0xcbf29ce484222325);
+    weak.hash(&mut hasher);
+
+    // murmur3's fmix64
+    let mut key = hasher.0;
+    key ^= key >> 33;
+    key = key.wrapping_mul(0xff51afd7ed558ccd);
+    key ^= key >> 33;
+    key = key.wrapping_mul(0xc4ceb9fe1a85ec53);
+    key ^ (key >> 33)
+}
+
+struct FnvHasher(u64);
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    // integers are hashed in native byte order by default, which would make the keys differ
+    // between platforms
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64)
+    }
+}
+
+///
+/// A bitset over the top bits of the weak keys, so that most of the weak checksums that are not
+/// in the signature are rejected before any searching (or strong hashing) happens
+///
+#[derive(Debug, Clone)]
+pub(crate) struct Prefilter<B = Vec<u8>> {
+    bytes: B,
+    shift: u32,
+}
+
+impl Prefilter {
+    pub(crate) fn new<I: IntoIterator<Item = u64>>(keys: I, key_count: usize) -> Self {
+        let bit_count = (key_count * PREFILTER_BITS_PER_CHUNK)
+            .next_power_of_two()
+            .max(64);
+        let mut prefilter = Prefilter {
+            bytes: vec![0; bit_count / 8],
+            shift: u64::BITS - bit_count.trailing_zeros(),
+        };
+        for key in keys {
+            let bit = prefilter.bit(key);
+            prefilter.bytes[bit / 8] |= 1 << (bit % 8);
+        }
+        prefilter
+    }
+}
+
+impl<'a> Prefilter<&'a [u8]> {
+    /// A prefilter over the bytes of [Prefilter::as_bytes], `None` if there can't be one
+    pub(crate) fn from_bytes(bytes: &'a [u8]) -> Option<Self> {
+        let bit_count = bytes.len().checked_mul(8)?;
+        if bit_count < 64 || !bit_count.is_power_of_two() {
+            return None;
+        }
+        Some(Prefilter {
+            bytes,
+            shift: u64::BITS - bit_count.trailing_zeros(),
+        })
+    }
+}
+
+impl<B: AsRef<[u8]>> Prefilter<B> {
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.bytes.as_ref()
+    }
+
+    fn bit(&self, key: u64) -> usize {
+        (key >> self.shift) as usize
+    }
+
+    pub(crate) fn may_contain(&self, key: u64) -> bool {
+        let bit = self.bit(key);
+        self.bytes.as_ref()[bit / 8] & (1 << (bit % 8)) != 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_weak_key_is_stable() {
+        // the keys end up on disk, so they must not change between versions
+        assert_eq!(weak_key(&0u32), 0x4d33_a937_2719_2487);
+        assert_ne!(weak_key(&1u32), weak_key(&2u32));
+    }
+
+    #[test]
+    fn test_prefilter() {
+        let keys: Vec<u64> = (0..100u32).map(|x| weak_key(&x)).collect();
+        let prefilter = Prefilter::new(keys.iter().copied(), keys.len());
+
+        assert!(keys.iter().all(|key| prefilter.may_contain(*key)));
+        let false_positives = (100..10100u32)
+            .filter(|x| prefilter.may_contain(weak_key(x)))
+            .count();
+        assert!(false_positives < 2000, "{}", false_positives);
+
+        let raw = Prefilter::from_bytes(prefilter.as_bytes()).unwrap();
+        assert!((0..10100u32)
+            .all(|x| { prefilter.may_contain(weak_key(&x)) == raw.may_contain(weak_key(&x)) }));
+    }
+}
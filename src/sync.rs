@@ -0,0 +1,356 @@
+//!
+//! rsync-style exchange between a receiver holding the old content and a sender holding the new one
+//!
+//! receiver -> sender: Signature of the old content
+//! sender -> receiver: Delta between the signature and the new content
+//! receiver -> sender: Ack with the length and strong hash of the patched content
+//!
+//! Either side can send an Error frame instead of the expected one and then hang up.
+//!
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::Hash;
+use std::io::{Read, Write};
+
+use bincode2::{deserialize, serialize};
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::cancel::{CancellationToken, Cancelled};
+use crate::decode::{decode_delta, decode_signature, DecodeError, DecodeLimits};
+use crate::delta_generation::{try_generate_delta_with_options, Delta, DeltaError, DeltaOptions};
+use crate::format::{encode, Format, FormatError, Kind};
+use crate::patch::{patch_with_progress, PatchError};
+use crate::progress::ProgressObserver;
+use crate::rolling_checksum::RollingChecksum;
+use crate::signature_generation::{cancellable_signature, ChunkSizePolicy};
+use crate::strong_hash::StrongHash;
+use crate::{Signature, DEFAULT_VERSION};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Signature = 1,
+    Delta = 2,
+    Ack = 3,
+    Error = 4,
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = SyncError;
+
+    fn try_from(value: u8) -> Result<Self, SyncError> {
+        match value {
+            1 => Ok(FrameKind::Signature),
+            2 => Ok(FrameKind::Delta),
+            3 => Ok(FrameKind::Ack),
+            4 => Ok(FrameKind::Error),
+            _ => Err(SyncError::UnknownFrame(value)),
+        }
+    }
+}
+
+impl Display for FrameKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Ack<S> {
+    pub len: u64,
+    pub hash: S,
+}
+
+///
+/// Writes a single frame: 1 byte kind, 8 bytes little-endian payload length and the payload itself
+///
+pub fn write_frame<W: Write>(out: &mut W, kind: FrameKind, payload: &[u8]) -> std::io::Result<()> {
+    out.write_all(&[kind as u8])?;
+    out.write_all(&(payload.len() as u64).to_le_bytes())?;
+    out.write_all(payload)?;
+    out.flush()
+}
+
+pub fn read_frame<R: Read>(input: &mut R) -> Result<(FrameKind, Vec<u8>), SyncError> {
+    let mut kind = [0u8; 1];
+    input.read_exact(&mut kind)?;
+    let kind = FrameKind::try_from(kind[0])?;
+
+    let mut len = [0u8; 8];
+    input.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    let limit = DecodeLimits::default().max_input_len;
+    if len > limit {
+        return Err(SyncError::FrameTooLarge { len, limit });
+    }
+
+    // don't trust the length for a single up-front allocation
+    let mut payload = Vec::new();
+    input.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        return Err(SyncError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok((kind, payload))
+}
+
+fn expect_frame<R: Read>(input: &mut R, expected: FrameKind) -> Result<Vec<u8>, SyncError> {
+    match read_frame(input)? {
+        (kind, payload) if kind == expected => Ok(payload),
+        (FrameKind::Error, payload) => Err(SyncError::Remote(
+            String::from_utf8_lossy(&payload).into_owned(),
+        )),
+        (kind, _) => Err(SyncError::UnexpectedFrame {
+            expected,
+            actual: kind,
+        }),
+    }
+}
+
+fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T, SyncError> {
+    deserialize(payload).map_err(SyncError::Encoding)
+}
+
+/// A signature or delta with its header, the way [crate::decode] reads it back
+fn encode_payload<T: Serialize>(value: &T, kind: Kind) -> Result<Vec<u8>, SyncError> {
+    let mut payload = Vec::new();
+    encode(value, kind, Format::Bincode, &mut payload)?;
+    Ok(payload)
+}
+
+/// Reports `error` to the other side before handing it back to the caller
+fn report<W: Write>(output: &mut W, error: SyncError) -> SyncError {
+    if let Err(e) = write_frame(output, FrameKind::Error, error.to_string().as_bytes()) {
+        warn!("failed to report an error to the other side: {}", e);
+    }
+    error
+}
+
+///
+/// The side holding the new content: waits for a signature, answers with a delta and
+/// checks the acknowledgement against the new content
+///
+/// `cancel` is checked while generating the delta.
+///
+pub fn send<R, S, In, Out>(
+    input: &mut In,
+    output: &mut Out,
+    new_content: &[u8],
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+) -> Result<(), SyncError>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash + Copy + Serialize + DeserializeOwned,
+    S: StrongHash,
+    <S as StrongHash>::HashType: Eq + Serialize + DeserializeOwned,
+    In: Read,
+    Out: Write,
+{
+    let payload = expect_frame(input, FrameKind::Signature)?;
+    let signature: Signature<R::ChecksumType, S::HashType> =
+        decode_signature(&payload, &DecodeLimits::default())
+            .map_err(|e| report(output, e.into()))?;
+
+    if crate::VERSION.unwrap_or(DEFAULT_VERSION) != signature.version {
+        warn!(
+            "remote signature was built with a different version of the tool: {}",
+            signature.version
+        );
+    }
+
+    let delta = try_generate_delta_with_options::<R, S>(
+        &signature,
+        new_content,
+        &DeltaOptions::default(),
+        progress,
+        cancel,
+    )
+    .map_err(|e| report(output, e.into()))?;
+    let payload = encode_payload(&delta, Kind::Delta).map_err(|e| report(output, e))?;
+    write_frame(output, FrameKind::Delta, &payload)?;
+
+    let payload = expect_frame(input, FrameKind::Ack)?;
+    let ack: Ack<S::HashType> = decode(&payload)?;
+    if ack.len != new_content.len() as u64 || ack.hash != S::hash(new_content) {
+        return Err(SyncError::VerificationFailed);
+    }
+    info!("remote acknowledged {} bytes", ack.len);
+    Ok(())
+}
+
+///
+/// The side holding the old content: sends its signature, applies the delta it gets back into `out`
+/// and acknowledges the result
+///
+/// `cancel` is checked while generating the signature and while patching.
+///
+pub fn receive<R, S, In, Out, W>(
+    input: &mut In,
+    output: &mut Out,
+    old_content: &[u8],
+    out: &mut W,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+) -> Result<(), SyncError>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash + Send + Copy + Serialize,
+    S: StrongHash,
+    <S as StrongHash>::HashType: Send + Serialize + DeserializeOwned,
+    In: Read,
+    Out: Write,
+    W: Write,
+{
+    let signature =
+        cancellable_signature::<R, S>(old_content, &ChunkSizePolicy::default(), progress, cancel)?;
+    write_frame(
+        output,
+        FrameKind::Signature,
+        &encode_payload(&signature, Kind::Signature)?,
+    )?;
+
+    let payload = expect_frame(input, FrameKind::Delta)?;
+    let delta: Delta<S::HashType> =
+        decode_delta(&payload, &DecodeLimits::default()).map_err(|e| report(output, e.into()))?;
+
+    // the result is kept in memory so that it can be hashed for the acknowledgement
+    let mut updated_content = Vec::with_capacity(old_content.len());
+    patch_with_progress::<S, _>(old_content, delta, &mut updated_content, progress, cancel)
+        .map_err(|e| report(output, SyncError::Patch(e)))?;
+
+    let ack = Ack {
+        len: updated_content.len() as u64,
+        hash: S::hash(&updated_content),
+    };
+    out.write_all(&updated_content)?;
+    out.flush()?;
+    write_frame(output, FrameKind::Ack, &serialize(&ack)?)?;
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error("unknown frame kind {0}")]
+    UnknownFrame(u8),
+    #[error("expected a {expected} frame, got {actual}")]
+    UnexpectedFrame {
+        expected: FrameKind,
+        actual: FrameKind,
+    },
+    #[error("the other side reported an error: {0}")]
+    Remote(String),
+    #[error("the acknowledged content doesn't match the sent one")]
+    VerificationFailed,
+    #[error("failed to encode or decode a frame")]
+    Encoding(#[from] bincode2::Error),
+    #[error("failed to encode the signature or delta")]
+    Format(#[from] FormatError),
+    #[error("invalid signature or delta")]
+    Decode(#[from] DecodeError),
+    #[error("frame of {len} bytes is over the limit of {limit}")]
+    FrameTooLarge { len: u64, limit: u64 },
+    #[error("failed to generate the delta")]
+    Delta(#[from] DeltaError),
+    #[error("failed to apply the delta")]
+    Patch(#[from] PatchError),
+    #[error("transport error")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use crate::progress::NoProgress;
+    use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+    use crate::strong_hash::md5::Md5Sum;
+
+    use super::*;
+
+    #[test]
+    fn test_send_and_receive() {
+        let old_content: Vec<u8> = (0..1 << 12).map(|x| (x % 251) as u8).collect();
+        let mut new_content = old_content.clone();
+        new_content.splice(1000..1010, [42; 30]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut receiver_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut sender_stream, _) = listener.accept().unwrap();
+        let expected = new_content.clone();
+        let sender = thread::spawn(move || {
+            let mut input = sender_stream.try_clone().unwrap();
+            send::<RollingAdler32, Md5Sum, _, _>(
+                &mut input,
+                &mut sender_stream,
+                &expected,
+                &NoProgress,
+                &CancellationToken::new(),
+            )
+        });
+
+        let mut input = receiver_stream.try_clone().unwrap();
+        let mut updated_content = Vec::new();
+        receive::<RollingAdler32, Md5Sum, _, _, _>(
+            &mut input,
+            &mut receiver_stream,
+            &old_content,
+            &mut updated_content,
+            &NoProgress,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+
+        sender.join().unwrap().unwrap();
+        assert_eq!(updated_content, new_content);
+    }
+
+    #[test]
+    fn test_remote_error_is_reported() {
+        let mut input = Vec::new();
+        write_frame(&mut input, FrameKind::Error, b"boom").unwrap();
+
+        let result = send::<RollingAdler32, Md5Sum, _, _>(
+            &mut input.as_slice(),
+            &mut Vec::new(),
+            &[],
+            &NoProgress,
+            &CancellationToken::new(),
+        );
+        assert!(matches!(result, Err(SyncError::Remote(message)) if message == "boom"));
+    }
+
+    #[test]
+    fn test_undecodable_signature_is_reported_back() {
+        let mut input = Vec::new();
+        write_frame(&mut input, FrameKind::Signature, &[1, 2, 3]).unwrap();
+
+        let mut output = Vec::new();
+        let result = send::<RollingAdler32, Md5Sum, _, _>(
+            &mut input.as_slice(),
+            &mut output,
+            &[],
+            &NoProgress,
+            &CancellationToken::new(),
+        );
+        assert!(matches!(result, Err(SyncError::Decode(_))));
+
+        let (kind, _) = read_frame(&mut output.as_slice()).unwrap();
+        assert_eq!(kind, FrameKind::Error);
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let mut input = Vec::new();
+        write_frame(&mut input, FrameKind::Delta, &[1, 2, 3]).unwrap();
+        input.pop();
+
+        assert!(matches!(
+            read_frame(&mut input.as_slice()),
+            Err(SyncError::Io(_))
+        ));
+    }
+}
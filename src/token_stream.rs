@@ -0,0 +1,447 @@
+//!
+//! Reading and writing the tokens of an encoded delta one at a time, and visiting them
+//!
+//! A [DeltaReader] yields the tokens of an encoded [Delta] without holding all of them in memory,
+//! a [DeltaWriter] produces the same bytes as encoding the whole delta. Both the in-memory deltas
+//! and the readers feed a [DeltaVisitor], which is also how patching consumes them.
+//! Only bincode deltas can be streamed, see [crate::format].
+//!
+//! ```
+//! use std::fmt::Debug;
+//! use rolling_in_the_diff::decode::DecodeLimits;
+//! use rolling_in_the_diff::delta_generation::DeltaToken;
+//! use rolling_in_the_diff::diff::diff;
+//! use rolling_in_the_diff::rolling_checksum::rolling_adler32::RollingAdler32;
+//! use rolling_in_the_diff::strong_hash::md5::Md5Sum;
+//! use rolling_in_the_diff::token_stream::{DeltaReader, DeltaVisitor, DeltaWriter};
+//!
+//! /// Counts the bytes that are sent along in the delta
+//! #[derive(Default)]
+//! struct AddedBytes(usize);
+//!
+//! impl<S: PartialEq + Debug> DeltaVisitor<S> for AddedBytes {
+//!     type Error = rolling_in_the_diff::decode::DecodeError;
+//!
+//!     fn visit_token(&mut self, token: DeltaToken<S>) -> Result<(), Self::Error> {
+//!         if let DeltaToken::Added(bytes) = token {
+//!             self.0 += bytes.len();
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let delta = diff::<RollingAdler32, Md5Sum>(b"the quick brown fox", b"the quick brown cat");
+//! let mut encoded = DeltaWriter::new(Vec::new());
+//! delta.accept(&mut encoded).unwrap();
+//! let encoded = encoded.into_inner();
+//!
+//! let mut added_bytes = AddedBytes::default();
+//! DeltaReader::<_, [u8; 16]>::new(encoded.as_slice(), &DecodeLimits::default())
+//!     .unwrap()
+//!     .accept(&mut added_bytes)
+//!     .unwrap();
+//! assert!(added_bytes.0 > 0);
+//! ```
+//!
+
+use std::fmt::Debug;
+use std::io::{self, Chain, Cursor, Read, Take, Write};
+use std::marker::PhantomData;
+
+use serde::de::{DeserializeOwned, DeserializeSeed};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::decode::{DecodeError, DecodeLimits, TokenValidator};
+use crate::delta_generation::{Delta, DeltaToken};
+use crate::format::legacy::{LegacyDelta, LegacyDeltaToken};
+use crate::format::{deserialize_bincode, Format, FormatError, Header, Kind, HEADER_LEN};
+
+///
+/// Receives the parts of a delta in order: the header, each token, then the end
+///
+pub trait DeltaVisitor<S>
+where
+    S: PartialEq + Debug,
+{
+    type Error;
+
+    fn visit_header(&mut self, _chunk_size: u64, _version: &str) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn visit_token(&mut self, token: DeltaToken<S>) -> Result<(), Self::Error>;
+
+    /// Called once all the tokens were visited
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl<S> Delta<'_, S>
+where
+    S: Eq + PartialEq + Debug,
+{
+    /// Feeds the delta to `visitor`
+    pub fn accept<V: DeltaVisitor<S>>(self, visitor: &mut V) -> Result<(), V::Error> {
+        visitor.visit_header(self.chunk_size, &self.version)?;
+        for token in self.tokens {
+            visitor.visit_token(token)?;
+        }
+        visitor.finish()
+    }
+}
+
+///
+/// Reads the tokens of an encoded [Delta] one at a time
+///
+/// The tokens are checked like [crate::decode::decode_delta] does, reading stops with an error
+/// past the input limit. The delta is in bincode with a [Header], or headerless in the layout of
+/// the first release - that one has the chunk size after the tokens, so it's read whole first.
+///
+pub struct DeltaReader<Rd: Read, S> {
+    /// what was read looking for a header, followed by the rest of the input
+    input: Chain<Cursor<Vec<u8>>, Take<Rd>>,
+    /// the tokens of a headerless delta, which was read whole
+    legacy_tokens: Option<std::vec::IntoIter<LegacyDeltaToken<'static, S>>>,
+    chunk_size: u64,
+    version: String,
+    validator: TokenValidator,
+    done: bool,
+    hash_type: PhantomData<S>,
+}
+
+impl<Rd, S> DeltaReader<Rd, S>
+where
+    Rd: Read,
+    S: PartialEq + Debug + Serialize + DeserializeOwned,
+{
+    /// Reads the header of the delta
+    pub fn new(input: Rd, limits: &DecodeLimits) -> Result<Self, DecodeError> {
+        let mut input = input.take(limits.max_input_len);
+        let mut prefix = Vec::with_capacity(HEADER_LEN);
+        (&mut input)
+            .take(HEADER_LEN as u64)
+            .read_to_end(&mut prefix)
+            .map_err(|e| DecodeError::Malformed(e.into()))?;
+        match Header::parse(&prefix).map_err(DecodeError::InvalidHeader)? {
+            Some((header, _)) if header.format != Format::Bincode => {
+                return Err(DecodeError::InvalidHeader(FormatError::NotStreamable(
+                    header.format,
+                )))
+            }
+            Some((header, _)) if header.kind != Kind::Delta => {
+                return Err(DecodeError::WrongKind {
+                    expected: Kind::Delta,
+                    found: header.kind,
+                })
+            }
+            Some(_) => prefix.clear(),
+            // headerless, the prefix is the start of the delta
+            None => {
+                input
+                    .read_to_end(&mut prefix)
+                    .map_err(|e| DecodeError::Malformed(e.into()))?;
+                let LegacyDelta {
+                    tokens,
+                    chunk_size,
+                    version,
+                } = deserialize_bincode::<LegacyDelta<S>>(&prefix)
+                    .map_err(DecodeError::Malformed)?;
+                let tokens: Vec<_> = tokens
+                    .into_iter()
+                    .map(LegacyDeltaToken::into_owned)
+                    .collect();
+                return Ok(DeltaReader {
+                    input: Cursor::new(Vec::new()).chain(input),
+                    legacy_tokens: Some(tokens.into_iter()),
+                    chunk_size,
+                    version,
+                    validator: TokenValidator::new(chunk_size, limits),
+                    done: false,
+                    hash_type: PhantomData,
+                });
+            }
+        }
+        let mut input = Cursor::new(prefix).chain(input);
+
+        let (chunk_size, version): (u64, String) = bincode2::config()
+            .limit(remaining_len(&input))
+            .deserialize_from(&mut input)
+            .map_err(|e| DecodeError::Malformed(e.into()))?;
+        Ok(DeltaReader {
+            input,
+            legacy_tokens: None,
+            chunk_size,
+            version,
+            validator: TokenValidator::new(chunk_size, limits),
+            done: false,
+            hash_type: PhantomData,
+        })
+    }
+
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// The next token, `None` once the end of the delta was read
+    pub fn next_token(&mut self) -> Result<Option<DeltaToken<'static, S>>, DecodeError> {
+        if self.done {
+            return Ok(None);
+        }
+        // set until the token is read successfully, so that nothing is read after an error
+        self.done = true;
+        let token = match &mut self.legacy_tokens {
+            Some(tokens) => tokens.next().map(DeltaToken::from),
+            None => bincode2::config()
+                .limit(remaining_len(&self.input))
+                .deserialize_from_seed(OwnedToken(PhantomData), &mut self.input)
+                .map_err(|e| DecodeError::Malformed(e.into()))?,
+        };
+        if token.is_none() && self.legacy_tokens.is_none() {
+            // nothing may follow the end of the delta
+            let trailing = io::copy(&mut self.input, &mut io::sink())
+                .map_err(|e| DecodeError::Malformed(e.into()))?;
+            if trailing > 0 {
+                return Err(DecodeError::Malformed(FormatError::TrailingBytes(trailing)));
+            }
+        }
+        if let Some(token) = &token {
+            self.validator.validate(token)?;
+            self.done = false;
+        }
+        Ok(token)
+    }
+
+    /// Feeds the rest of the delta to `visitor`
+    pub fn accept<V>(mut self, visitor: &mut V) -> Result<(), V::Error>
+    where
+        V: DeltaVisitor<S>,
+        V::Error: From<DecodeError>,
+    {
+        visitor.visit_header(self.chunk_size, &self.version)?;
+        while let Some(token) = self.next_token()? {
+            visitor.visit_token(token)?;
+        }
+        visitor.finish()
+    }
+}
+
+impl<Rd, S> Iterator for DeltaReader<Rd, S>
+where
+    Rd: Read,
+    S: PartialEq + Debug + Serialize + DeserializeOwned,
+{
+    type Item = Result<DeltaToken<'static, S>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_token().transpose()
+    }
+}
+
+/// How many more bytes may be read from the input of a [DeltaReader]
+fn remaining_len<Rd: Read>(input: &Chain<Cursor<Vec<u8>>, Take<Rd>>) -> u64 {
+    let (prefix, rest) = input.get_ref();
+    (prefix.get_ref().len() as u64 - prefix.position()) + rest.limit()
+}
+
+/// Deserializes an optional token that doesn't borrow from the input
+struct OwnedToken<S>(PhantomData<S>);
+
+impl<'de, S> DeserializeSeed<'de> for OwnedToken<S>
+where
+    S: PartialEq + Debug + Deserialize<'de>,
+{
+    type Value = Option<DeltaToken<'static, S>>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        // read from a stream, the added data is owned already
+        let token = Option::<DeltaToken<S>>::deserialize(deserializer)?;
+        Ok(token.map(DeltaToken::into_owned))
+    }
+}
+
+///
+/// Writes a delta a token at a time, the same bytes as encoding the whole [Delta] with
+/// [crate::format::encode] in bincode
+///
+/// The delta is only complete once [DeltaVisitor::finish] was called.
+///
+pub struct DeltaWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> DeltaWriter<W> {
+    pub fn new(out: W) -> Self {
+        DeltaWriter { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W, S> DeltaVisitor<S> for DeltaWriter<W>
+where
+    W: Write,
+    S: PartialEq + Debug + Serialize,
+{
+    type Error = bincode2::Error;
+
+    fn visit_header(&mut self, chunk_size: u64, version: &str) -> Result<(), Self::Error> {
+        Header {
+            format: Format::Bincode,
+            kind: Kind::Delta,
+        }
+        .write(&mut self.out)?;
+        bincode2::serialize_into(&mut self.out, &(chunk_size, version))
+    }
+
+    fn visit_token(&mut self, token: DeltaToken<S>) -> Result<(), Self::Error> {
+        bincode2::serialize_into(&mut self.out, &Some(token))
+    }
+
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        bincode2::serialize_into(&mut self.out, &None::<DeltaToken<S>>)?;
+        Ok(self.out.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+
+    use test_case::test_case;
+
+    use crate::delta_generation::DeltaToken::{Added, BackReference, Diff, Fill, Removed, Reused};
+    use crate::format::encode;
+    use crate::strong_hash::md5::Md5Sum;
+    use crate::strong_hash::StrongHash;
+    use crate::DEFAULT_VERSION;
+
+    use super::*;
+
+    type Md5Hash = <Md5Sum as StrongHash>::HashType;
+
+    fn delta(tokens: Vec<DeltaToken<'static, Md5Hash>>) -> Delta<'static, Md5Hash> {
+        Delta {
+            tokens,
+            chunk_size: 4,
+            version: DEFAULT_VERSION.to_string(),
+        }
+    }
+
+    fn encoded_delta(tokens: Vec<DeltaToken<'static, Md5Hash>>) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        encode(&delta(tokens), Kind::Delta, Format::Bincode, &mut encoded).unwrap();
+        encoded
+    }
+
+    #[test_case(vec![]; "when there are no tokens")]
+    #[test_case(vec![
+        Reused(0, Md5Sum::hash(b"abcd")),
+        Added(Cow::Borrowed(b"new data")),
+        BackReference(4, 4),
+        Fill(0, 100),
+        Diff(1, Md5Sum::hash(b"efgh"), vec![(1, vec![2])]),
+        Removed(2),
+    ]; "when there are all kinds of tokens")]
+    fn test_write_and_read(tokens: Vec<DeltaToken<'static, Md5Hash>>) {
+        let encoded = encoded_delta(tokens.clone());
+
+        let mut writer = DeltaWriter::new(Vec::new());
+        delta(tokens.clone()).accept(&mut writer).unwrap();
+        assert_eq!(writer.into_inner(), encoded);
+
+        let reader = DeltaReader::<_, Md5Hash>::new(encoded.as_slice(), &Default::default());
+        let reader = reader.unwrap();
+        assert_eq!(reader.chunk_size(), 4);
+        let read: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        assert_eq!(read, tokens);
+    }
+
+    #[test]
+    fn test_read_headerless() {
+        let legacy = LegacyDelta::<Md5Hash> {
+            tokens: vec![
+                LegacyDeltaToken::Reused(0, Md5Sum::hash(b"abcd")),
+                LegacyDeltaToken::Added(Cow::Borrowed(b"new data")),
+                LegacyDeltaToken::Removed(1),
+            ],
+            chunk_size: 4,
+            version: DEFAULT_VERSION.to_string(),
+        };
+        let encoded = bincode2::serialize(&legacy).unwrap();
+
+        let reader = DeltaReader::<_, Md5Hash>::new(encoded.as_slice(), &Default::default());
+        let reader = reader.unwrap();
+        assert_eq!(reader.chunk_size(), 4);
+        let read: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            read,
+            [
+                Reused(0, Md5Sum::hash(b"abcd")),
+                Added(Cow::Borrowed(b"new data")),
+                Removed(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_trailing_bytes() {
+        let mut encoded = encoded_delta(vec![Fill(0, 1)]);
+        encoded.push(0);
+
+        let mut reader =
+            DeltaReader::<_, Md5Hash>::new(encoded.as_slice(), &Default::default()).unwrap();
+        assert!(matches!(reader.next(), Some(Ok(Fill(0, 1)))));
+        assert!(matches!(
+            reader.next(),
+            Some(Err(DecodeError::Malformed(FormatError::TrailingBytes(1))))
+        ));
+    }
+
+    #[test_case(Format::Json, Kind::Delta => matches DecodeError::InvalidHeader(FormatError::NotStreamable(Format::Json)); "when not in bincode")]
+    #[test_case(Format::Bincode, Kind::Signature => matches DecodeError::WrongKind { found: Kind::Signature, .. }; "when not a delta")]
+    fn test_read_other_header(format: Format, kind: Kind) -> DecodeError {
+        let mut encoded = Vec::new();
+        Header { format, kind }.write(&mut encoded).unwrap();
+        bincode2::serialize_into(&mut encoded, &delta(vec![])).unwrap();
+
+        match DeltaReader::<_, Md5Hash>::new(encoded.as_slice(), &Default::default()) {
+            Ok(_) => panic!("the header was accepted"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn test_read_invalid_tokens() {
+        let tokens = vec![Fill(0, 10), Fill(0, 10), Fill(0, 10)];
+        let encoded = encoded_delta(tokens);
+        let limits = DecodeLimits {
+            max_output_len: 15,
+            ..Default::default()
+        };
+
+        let mut reader = DeltaReader::<_, Md5Hash>::new(encoded.as_slice(), &limits).unwrap();
+        assert!(matches!(reader.next(), Some(Ok(Fill(0, 10)))));
+        assert!(matches!(
+            reader.next(),
+            Some(Err(DecodeError::OutputTooLarge { .. }))
+        ));
+        assert!(reader.next().is_none());
+
+        // the end of the delta is cut off
+        let encoded = encoded_delta(vec![Fill(0, 1)]);
+        let truncated = &encoded[..encoded.len() - 1];
+        let mut reader = DeltaReader::<_, Md5Hash>::new(truncated, &limits).unwrap();
+        assert!(matches!(reader.next(), Some(Ok(Fill(0, 1)))));
+        assert!(matches!(
+            reader.next(),
+            Some(Err(DecodeError::Malformed(_)))
+        ));
+    }
+}
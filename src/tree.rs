@@ -0,0 +1,712 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Debug;
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::io::BufWriter;
+use std::path::{Component, Path, PathBuf};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::cancel::{CancellationToken, Cancelled};
+use crate::delta_generation::{
+    try_generate_delta_with_options, Delta, DeltaError, DeltaOptions, DeltaToken,
+};
+use crate::patch::{patch_with_progress, PatchError, SparseWriter};
+use crate::progress::{Phase, Progress, ProgressObserver, TreeFileProgress};
+use crate::rolling_checksum::RollingChecksum;
+use crate::signature_generation::{cancellable_signature, ChunkSizePolicy};
+use crate::strong_hash::StrongHash;
+use crate::{Signature, DEFAULT_VERSION};
+
+/// Content of a directory tree keyed by the '/'-separated path relative to its root
+pub type Tree = BTreeMap<String, Vec<u8>>;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileSignature<W, S>
+where
+    W: Eq + Hash + PartialEq,
+    S: PartialEq + Copy,
+{
+    pub len: u64,
+    /// strong hash over the whole file, used to skip unchanged files and detect renames
+    pub hash: S,
+    pub signature: Signature<W, S>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TreeSignature<W, S>
+where
+    W: Eq + Hash + PartialEq,
+    S: PartialEq + Copy,
+{
+    pub files: BTreeMap<String, FileSignature<W, S>>,
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum TreeDeltaEntry<'a, S>
+where
+    S: Eq + PartialEq + Debug,
+{
+    Modified {
+        path: String,
+        #[serde(borrow)]
+        delta: Delta<'a, S>,
+    },
+    Created {
+        path: String,
+        #[serde(borrow, with = "crate::format::bytes")]
+        content: Cow<'a, [u8]>,
+    },
+    Deleted {
+        path: String,
+    },
+    Renamed {
+        from: String,
+        to: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TreeDelta<'a, S>
+where
+    S: Eq + PartialEq + Debug,
+{
+    #[serde(borrow)]
+    pub entries: Vec<TreeDeltaEntry<'a, S>>,
+    pub version: String,
+}
+
+///
+/// Reads all the regular files under `root` into memory
+///
+/// Empty directories are not part of the resulting tree
+///
+pub fn read_tree(root: &Path) -> io::Result<Tree> {
+    let mut tree = Tree::new();
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                let path = entry.path();
+                tree.insert(relative_path(root, &path)?, fs::read(&path)?);
+            } else {
+                debug!("skipping {} - not a regular file", entry.path().display());
+            }
+        }
+    }
+    Ok(tree)
+}
+
+fn relative_path(root: &Path, path: &Path) -> io::Result<String> {
+    let relative = path
+        .strip_prefix(root)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut components = Vec::new();
+    for component in relative.components() {
+        components.push(component.as_os_str().to_str().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not valid UTF-8", path.display()),
+            )
+        })?);
+    }
+    Ok(components.join("/"))
+}
+
+///
+/// Generates the signature of each file of `tree`
+///
+/// `cancel` is checked between the chunks of each file.
+///
+pub fn generate_tree_signature<R, S>(
+    tree: &Tree,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+) -> Result<TreeSignature<R::ChecksumType, S::HashType>, Cancelled>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash,
+    S: StrongHash,
+    <R as RollingChecksum>::ChecksumType: Send + Copy,
+    <S as StrongHash>::HashType: Send,
+{
+    let total_bytes = tree.values().map(|content| content.len() as u64).sum();
+    let mut offset = 0;
+    let files = tree
+        .iter()
+        .map(|(path, content)| {
+            let file_progress = TreeFileProgress {
+                inner: progress,
+                offset,
+                total_bytes,
+                reused_chunks: 0,
+            };
+            offset += content.len() as u64;
+            let signature = cancellable_signature::<R, S>(
+                content,
+                &ChunkSizePolicy::default(),
+                &file_progress,
+                cancel,
+            )?;
+            Ok((
+                path.clone(),
+                FileSignature {
+                    len: content.len() as u64,
+                    hash: S::hash(content),
+                    signature,
+                },
+            ))
+        })
+        .collect::<Result<_, Cancelled>>()?;
+    progress.on_finish(&Progress {
+        processed_bytes: total_bytes,
+        ..Progress::new(Phase::Signature, Some(total_bytes))
+    });
+
+    Ok(TreeSignature {
+        files,
+        version: crate::VERSION.unwrap_or(DEFAULT_VERSION).to_string(),
+    })
+}
+
+///
+/// Generates the delta of each file of `new_tree` that isn't the same in `old_signature`
+///
+/// `cancel` is checked while generating the delta of each file.
+///
+pub fn generate_tree_delta<'a, R, S>(
+    old_signature: &TreeSignature<R::ChecksumType, S::HashType>,
+    new_tree: &'a Tree,
+    options: &DeltaOptions,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+) -> Result<TreeDelta<'a, S::HashType>, DeltaError>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash,
+    S: StrongHash,
+    <S as StrongHash>::HashType: Eq,
+{
+    let mut entries = Vec::new();
+    // old files that are gone from the new tree are the only rename candidates
+    let mut vanished: Vec<_> = old_signature
+        .files
+        .iter()
+        .filter(|(path, _)| !new_tree.contains_key(*path))
+        .collect();
+    let mut renamed = HashSet::new();
+    let mut report = Progress::new(
+        Phase::Delta,
+        Some(new_tree.values().map(|content| content.len() as u64).sum()),
+    );
+
+    for (path, content) in new_tree {
+        let offset = report.processed_bytes;
+        report.processed_bytes += content.len() as u64;
+        match old_signature.files.get(path) {
+            Some(old_file) => {
+                // the cheap size check goes first so that the hash is only computed when needed
+                if old_file.len == content.len() as u64 && old_file.hash == S::hash(content) {
+                    continue;
+                }
+                let file_progress = TreeFileProgress {
+                    inner: progress,
+                    offset,
+                    total_bytes: report.total_bytes.unwrap_or_default(),
+                    reused_chunks: report.reused_chunks,
+                };
+                let delta = try_generate_delta_with_options::<R, S>(
+                    &old_file.signature,
+                    content,
+                    options,
+                    &file_progress,
+                    cancel,
+                )?;
+                report.reused_chunks += delta
+                    .tokens
+                    .iter()
+                    .filter(|token| matches!(token, DeltaToken::Reused(..)))
+                    .count() as u64;
+                entries.push(TreeDeltaEntry::Modified {
+                    path: path.clone(),
+                    delta,
+                });
+            }
+            None => {
+                let hash = S::hash(content);
+                let rename_source = vanished.iter().position(|(_, old_file)| {
+                    old_file.len == content.len() as u64 && old_file.hash == hash
+                });
+                match rename_source {
+                    Some(index) => {
+                        let (from, _) = vanished.swap_remove(index);
+                        renamed.insert(from);
+                        entries.push(TreeDeltaEntry::Renamed {
+                            from: from.clone(),
+                            to: path.clone(),
+                        });
+                    }
+                    None => entries.push(TreeDeltaEntry::Created {
+                        path: path.clone(),
+                        content: Cow::Borrowed(content),
+                    }),
+                }
+            }
+        }
+    }
+
+    for path in old_signature.files.keys() {
+        if !new_tree.contains_key(path) && !renamed.contains(path) {
+            entries.push(TreeDeltaEntry::Deleted { path: path.clone() });
+        }
+    }
+    progress.on_finish(&report);
+
+    Ok(TreeDelta {
+        entries,
+        version: crate::VERSION.unwrap_or(DEFAULT_VERSION).to_string(),
+    })
+}
+
+///
+/// Copies `old_root` into `out_root` and applies `delta` on top of the copy
+///
+/// All the file contents are taken from `old_root`, which is left untouched. `out_root` must not
+/// exist or be an empty directory, so that nothing but the new tree ends up in it.
+///
+/// The tree is patched into a `.part` directory next to `out_root`, which is only renamed to
+/// `out_root` once every file is written. `cancel` is checked between files and while patching
+/// each of them. On any error, including [TreePatchError::Cancelled], the `.part` directory is
+/// removed and `out_root` is left as it was.
+///
+pub fn patch_tree<S>(
+    old_root: &Path,
+    delta: TreeDelta<S::HashType>,
+    out_root: &Path,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+) -> Result<(), TreePatchError>
+where
+    S: StrongHash,
+{
+    for entry in &delta.entries {
+        match entry {
+            TreeDeltaEntry::Modified { path, .. }
+            | TreeDeltaEntry::Created { path, .. }
+            | TreeDeltaEntry::Deleted { path } => validate_path(path)?,
+            TreeDeltaEntry::Renamed { from, to } => {
+                validate_path(from)?;
+                validate_path(to)?;
+            }
+        }
+    }
+
+    ensure_empty(out_root)?;
+    let mut partial_root = out_root.as_os_str().to_owned();
+    partial_root.push(".part");
+    let partial_root = PathBuf::from(partial_root);
+    ensure_empty(&partial_root)?;
+
+    let result = patch_into::<S>(old_root, delta, &partial_root, progress, cancel).and_then(|()| {
+        // an empty directory is in the way of the rename on some platforms
+        if out_root.exists() {
+            fs::remove_dir(out_root)?;
+        }
+        fs::rename(&partial_root, out_root)?;
+        Ok(())
+    });
+    if result.is_err() {
+        match fs::remove_dir_all(&partial_root) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                warn!("failed to remove {}: {}", partial_root.display(), e)
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Writes the patched tree into `out_root`, which is created
+fn patch_into<S>(
+    old_root: &Path,
+    delta: TreeDelta<S::HashType>,
+    out_root: &Path,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+) -> Result<(), TreePatchError>
+where
+    S: StrongHash,
+{
+    cancel.check()?;
+    copy_dir(old_root, out_root)?;
+
+    // removals go first - a removed file might be in the way of a new one (or its directory)
+    for entry in &delta.entries {
+        match entry {
+            TreeDeltaEntry::Deleted { path } | TreeDeltaEntry::Renamed { from: path, .. } => {
+                remove_file(out_root, path)?;
+            }
+            _ => {}
+        }
+    }
+
+    let mut written = 0;
+    let mut report = Progress::new(Phase::Patch, None);
+    for entry in delta.entries {
+        cancel.check()?;
+        match entry {
+            TreeDeltaEntry::Modified { path, delta } => {
+                let old_content = fs::read(old_root.join(&path))?;
+                let out_file = fs::File::create(create_parent(out_root, &path)?)?;
+                let mut out = BufWriter::new(SparseWriter::new(out_file));
+                let reused_chunks = delta
+                    .tokens
+                    .iter()
+                    .filter(|token| matches!(token, DeltaToken::Reused(..)))
+                    .count() as u64;
+                // the total output of a tree isn't known up front, so it's only reported per file
+                let file_progress = |file_report: &Progress| {
+                    progress.on_progress(&Progress {
+                        processed_bytes: report.processed_bytes + file_report.processed_bytes,
+                        reused_chunks: report.reused_chunks + file_report.reused_chunks,
+                        ..report
+                    })
+                };
+                patch_with_progress::<S, _>(&old_content, delta, &mut out, &file_progress, cancel)
+                    .map_err(|source| TreePatchError::Patch { path, source })?;
+                let out_file = out.into_inner().map_err(|e| e.into_error())?.finish()?;
+                report.processed_bytes += out_file.metadata()?.len();
+                report.reused_chunks += reused_chunks;
+            }
+            TreeDeltaEntry::Created { path, content } => {
+                fs::write(create_parent(out_root, &path)?, &content)?;
+                report.processed_bytes += content.len() as u64;
+            }
+            TreeDeltaEntry::Renamed { from, to } => {
+                report.processed_bytes +=
+                    fs::copy(old_root.join(&from), create_parent(out_root, &to)?)?;
+            }
+            TreeDeltaEntry::Deleted { .. } => continue,
+        }
+        written += 1;
+        progress.on_progress(&report);
+    }
+    progress.on_finish(&report);
+    info!("files written: {}", written);
+    Ok(())
+}
+
+fn validate_path(path: &str) -> Result<(), TreePatchError> {
+    let is_valid = !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !is_valid {
+        return Err(TreePatchError::InvalidPath(path.to_string()));
+    }
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()))?;
+        } else if file_type.is_file() {
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+fn create_parent(root: &Path, path: &str) -> io::Result<PathBuf> {
+    let full_path = root.join(path);
+    if let Some(parent) = full_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(full_path)
+}
+
+fn remove_file(root: &Path, path: &str) -> io::Result<()> {
+    let full_path = root.join(path);
+    fs::remove_file(&full_path)?;
+
+    // clean up the directories that became empty so that they don't clash with new files
+    let mut dir = full_path.parent();
+    while let Some(current) = dir {
+        if current == root || fs::read_dir(current)?.next().is_some() {
+            break;
+        }
+        fs::remove_dir(current)?;
+        dir = current.parent();
+    }
+    Ok(())
+}
+
+/// Fails unless `dir` doesn't exist or is an empty directory
+fn ensure_empty(dir: &Path) -> Result<(), TreePatchError> {
+    match fs::read_dir(dir).map(|mut entries| entries.next().is_none()) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(TreePatchError::OutputNotEmpty(dir.to_path_buf())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TreePatchError {
+    #[error("invalid path in tree delta: {0}")]
+    InvalidPath(String),
+    #[error("{0} already has content, the patched tree goes into a new or empty directory")]
+    OutputNotEmpty(PathBuf),
+    #[error("failed to patch {path}")]
+    Patch {
+        path: String,
+        #[source]
+        source: PatchError,
+    },
+    #[error("file system error")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+}
+
+#[cfg(test)]
+mod test {
+    use test_case::test_case;
+
+    use crate::progress::NoProgress;
+    use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+    use crate::strong_hash::md5::Md5Sum;
+
+    use super::*;
+
+    fn tree(files: &[(&str, &[u8])]) -> Tree {
+        files
+            .iter()
+            .map(|(path, content)| (path.to_string(), content.to_vec()))
+            .collect()
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "rolling-in-the-diff-tree-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn write_tree(root: &Path, tree: &Tree) {
+        for (path, content) in tree {
+            fs::write(create_parent(root, path).unwrap(), content).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_generate_tree_delta() {
+        let old_tree = tree(&[
+            ("unchanged", b"same old content"),
+            ("modified", b"some content that will change"),
+            ("deleted", b"gone"),
+            ("dir/moved", b"moving around"),
+        ]);
+        let new_tree = tree(&[
+            ("unchanged", b"same old content"),
+            ("modified", b"some content that has changed"),
+            ("created", b"brand new"),
+            ("other/moved", b"moving around"),
+        ]);
+
+        let cancel = CancellationToken::new();
+        let signature =
+            generate_tree_signature::<RollingAdler32, Md5Sum>(&old_tree, &NoProgress, &cancel)
+                .unwrap();
+        let delta = generate_tree_delta::<RollingAdler32, Md5Sum>(
+            &signature,
+            &new_tree,
+            &DeltaOptions::default(),
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+
+        let summary: Vec<String> = delta
+            .entries
+            .iter()
+            .map(|entry| match entry {
+                TreeDeltaEntry::Modified { path, .. } => format!("M {}", path),
+                TreeDeltaEntry::Created { path, .. } => format!("C {}", path),
+                TreeDeltaEntry::Deleted { path } => format!("D {}", path),
+                TreeDeltaEntry::Renamed { from, to } => format!("R {} {}", from, to),
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                "C created",
+                "M modified",
+                "R dir/moved other/moved",
+                "D deleted"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_patch_tree() {
+        let root = test_dir("patch");
+        let (old_root, new_root, out_root) = (root.join("old"), root.join("new"), root.join("out"));
+
+        let old_tree = tree(&[
+            ("a", b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"),
+            ("b/c", b"will be renamed"),
+            ("d/e", b"will be deleted, making room for a file named d"),
+        ]);
+        let new_tree = tree(&[
+            ("a", b"aaaaaaaaaaaaaaaaaaaXaaaaaaaaaaaaaaaaaaaa"),
+            ("c", b"will be renamed"),
+            ("d", b"new file where a directory used to be"),
+        ]);
+        write_tree(&old_root, &old_tree);
+        write_tree(&new_root, &new_tree);
+
+        let cancel = CancellationToken::new();
+        let signature = generate_tree_signature::<RollingAdler32, Md5Sum>(
+            &read_tree(&old_root).unwrap(),
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        let new_tree = read_tree(&new_root).unwrap();
+        let delta = generate_tree_delta::<RollingAdler32, Md5Sum>(
+            &signature,
+            &new_tree,
+            &DeltaOptions::default(),
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        patch_tree::<Md5Sum>(&old_root, delta, &out_root, &NoProgress, &cancel).unwrap();
+
+        assert_eq!(read_tree(&out_root).unwrap(), new_tree);
+        assert_eq!(read_tree(&old_root).unwrap(), old_tree);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test_case("../escape"; "parent directory")]
+    #[test_case("/absolute"; "absolute path")]
+    #[test_case("./a"; "current directory")]
+    #[test_case(""; "empty path")]
+    fn test_patch_tree_rejects_invalid_paths(path: &str) {
+        let root = test_dir("invalid");
+        let delta = TreeDelta::<<Md5Sum as StrongHash>::HashType> {
+            entries: vec![TreeDeltaEntry::Created {
+                path: path.to_string(),
+                content: Cow::Borrowed(b"evil"),
+            }],
+            version: DEFAULT_VERSION.to_string(),
+        };
+
+        assert!(matches!(
+            patch_tree::<Md5Sum>(
+                &root.join("old"),
+                delta,
+                &root.join("out"),
+                &NoProgress,
+                &CancellationToken::new(),
+            ),
+            Err(TreePatchError::InvalidPath(_))
+        ));
+    }
+
+    #[test]
+    fn test_patch_tree_rejects_existing_output() {
+        let root = test_dir("existing");
+        let (old_root, out_root) = (root.join("old"), root.join("out"));
+        write_tree(&old_root, &tree(&[("a", b"old")]));
+        write_tree(&out_root, &tree(&[("left/over", b"from an earlier run")]));
+        let delta = TreeDelta::<<Md5Sum as StrongHash>::HashType> {
+            entries: vec![],
+            version: DEFAULT_VERSION.to_string(),
+        };
+
+        assert!(matches!(
+            patch_tree::<Md5Sum>(
+                &old_root,
+                delta,
+                &out_root,
+                &NoProgress,
+                &CancellationToken::new(),
+            ),
+            Err(TreePatchError::OutputNotEmpty(_))
+        ));
+        assert!(out_root.join("left/over").exists());
+        assert!(!out_root.join("a").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_cancelled_patch_tree() {
+        let root = test_dir("cancelled");
+        let (old_root, out_root) = (root.join("old"), root.join("out"));
+        write_tree(&old_root, &tree(&[("a", b"old"), ("b", b"old too")]));
+        fs::create_dir_all(&out_root).unwrap();
+        let delta = TreeDelta::<<Md5Sum as StrongHash>::HashType> {
+            entries: vec![
+                TreeDeltaEntry::Created {
+                    path: "c".to_string(),
+                    content: Cow::Borrowed(b"new"),
+                },
+                TreeDeltaEntry::Created {
+                    path: "d".to_string(),
+                    content: Cow::Borrowed(b"never written"),
+                },
+            ],
+            version: DEFAULT_VERSION.to_string(),
+        };
+
+        // cancels once the first file is written
+        let cancel = CancellationToken::new();
+        let cancel_after_first_file = |_: &Progress| cancel.cancel();
+        assert!(matches!(
+            patch_tree::<Md5Sum>(
+                &old_root,
+                delta,
+                &out_root,
+                &cancel_after_first_file,
+                &cancel
+            ),
+            Err(TreePatchError::Cancelled(_))
+        ));
+        assert_eq!(fs::read_dir(&out_root).unwrap().count(), 0);
+        assert!(!root.join("out.part").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_cancelled_tree_signature() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let result = generate_tree_signature::<RollingAdler32, Md5Sum>(
+            &tree(&[("a", b"some content")]),
+            &NoProgress,
+            &cancel,
+        );
+        assert!(matches!(result, Err(Cancelled)));
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use log::info;
+use thiserror::Error;
+
+use crate::cancel::{CancellationToken, Cancelled};
+use crate::delta_generation::find_reused_chunk;
+use crate::progress::{Phase, Progress, ProgressObserver};
+use crate::rolling_checksum::RollingChecksum;
+use crate::signature_index::SignatureIndex;
+use crate::strong_hash::StrongHash;
+use crate::{ChunkNumber, Signature, SignatureChunksError};
+
+///
+/// Where the chunks missing from the local content get fetched from, e.g. HTTP range requests
+///
+pub trait RangeSource {
+    /// Fetches up to `len` bytes at `offset` - fewer bytes mean the end of the content was reached
+    fn fetch(&mut self, offset: u64, len: u64) -> io::Result<Vec<u8>>;
+}
+
+/// A [RangeSource] over anything seekable - a local copy of the published file for example
+pub struct SeekableRangeSource<T: Read + Seek> {
+    inner: T,
+}
+
+impl<T: Read + Seek> SeekableRangeSource<T> {
+    pub fn new(inner: T) -> Self {
+        SeekableRangeSource { inner }
+    }
+}
+
+impl<T: Read + Seek> RangeSource for SeekableRangeSource<T> {
+    fn fetch(&mut self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+        let mut range = Vec::new();
+        self.inner.by_ref().take(len).read_to_end(&mut range)?;
+        Ok(range)
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ReconstructionStats {
+    pub reused_bytes: u64,
+    pub fetched_bytes: u64,
+    pub fetch_count: u64,
+}
+
+///
+/// Rebuilds the content described by `new_signature` into `out`
+///
+/// Chunks that can be found in `old_content` are copied from there and only the rest is fetched
+/// from `source`. Every chunk is verified against its strong hash before being written.
+///
+/// `cancel` is checked while looking for the local chunks and between the chunks written. On
+/// [ReconstructError::Cancelled], part of the output may have been written already.
+///
+pub fn reconstruct<R, S, Src, W>(
+    new_signature: &Signature<R::ChecksumType, S::HashType>,
+    old_content: &[u8],
+    source: &mut Src,
+    out: &mut W,
+    progress: &dyn ProgressObserver,
+    cancel: &CancellationToken,
+) -> Result<ReconstructionStats, ReconstructError>
+where
+    R: RollingChecksum,
+    <R as RollingChecksum>::ChecksumType: Eq + Hash,
+    S: StrongHash,
+    <S as StrongHash>::HashType: Eq + Hash,
+    Src: RangeSource,
+    W: Write,
+{
+    let chunk_size = new_signature.chunk_size;
+    let chunk_hashes = new_signature.strong_hashes_by_chunk();
+    // fetched ranges are split into chunks of this size
+    if chunk_size == 0 && !chunk_hashes.is_empty() {
+        return Err(SignatureChunksError::InvalidChunkSize {
+            chunk_size,
+            chunk_count: chunk_hashes.len(),
+        }
+        .into());
+    }
+
+    // strong hash -> (offset, len) of content that is already present locally
+    let mut local_chunks = HashMap::new();
+    let mut left = 0;
+    while let Some(found) =
+        find_reused_chunk::<R, S>(new_signature, &old_content[left..], None, cancel)
+    {
+        left += found.bytes_until_reused;
+        local_chunks
+            .entry(found.chunk_strong_hash)
+            .or_insert((left, found.reused_chunk_size));
+        left += found.reused_chunk_size;
+    }
+    // the search also ends early when cancelled
+    cancel.check()?;
+
+    let mut stats = ReconstructionStats::default();
+    let mut report = Progress::new(Phase::Reconstruct, None);
+    let mut chunk_number = 0;
+    while chunk_number < chunk_hashes.len() {
+        cancel.check()?;
+        let hash = chunk_hashes[chunk_number].ok_or(ReconstructError::MissingChunk {
+            chunk_num: chunk_number as ChunkNumber,
+        })?;
+        if let Some(&(offset, len)) = local_chunks.get(&hash) {
+            out.write_all(&old_content[offset..offset + len])?;
+            stats.reused_bytes += len as u64;
+            chunk_number += 1;
+            report.processed_bytes += len as u64;
+            report.reused_chunks += 1;
+            progress.on_progress(&report);
+            continue;
+        }
+
+        // fetch the whole run of missing chunks with a single request
+        let run_start = chunk_number;
+        while chunk_number < chunk_hashes.len()
+            && !matches!(chunk_hashes[chunk_number], Some(hash) if local_chunks.contains_key(&hash))
+        {
+            chunk_number += 1;
+        }
+        let range = source
+            .fetch(
+                (run_start * chunk_size) as u64,
+                ((chunk_number - run_start) * chunk_size) as u64,
+            )
+            .map_err(ReconstructError::Source)?;
+        stats.fetched_bytes += range.len() as u64;
+        stats.fetch_count += 1;
+
+        let mut chunks = range.chunks(chunk_size);
+        for (missing_chunk, expected_hash) in
+            chunk_hashes[run_start..chunk_number].iter().enumerate()
+        {
+            let chunk_num = (run_start + missing_chunk) as ChunkNumber;
+            let chunk = chunks
+                .next()
+                .ok_or(ReconstructError::ShortRange { chunk_num })?;
+            if Some(S::hash(chunk)) != *expected_hash {
+                return Err(ReconstructError::ChunkHashMismatch { chunk_num });
+            }
+            out.write_all(chunk)?;
+        }
+        report.processed_bytes += range.len() as u64;
+        progress.on_progress(&report);
+    }
+    progress.on_finish(&report);
+
+    info!(
+        "reused bytes: {} fetched bytes: {} in {} requests",
+        stats.reused_bytes, stats.fetched_bytes, stats.fetch_count
+    );
+    Ok(stats)
+}
+
+#[derive(Error, Debug)]
+pub enum ReconstructError {
+    #[error("chunk {chunk_num} is missing from the signature")]
+    MissingChunk { chunk_num: u64 },
+    #[error("the source ended before chunk {chunk_num}")]
+    ShortRange { chunk_num: u64 },
+    #[error("hash mismatch on fetched chunk {chunk_num}")]
+    ChunkHashMismatch { chunk_num: u64 },
+    #[error("invalid signature")]
+    InvalidSignature(#[from] SignatureChunksError),
+    #[error("failed to fetch from the source")]
+    Source(#[source] io::Error),
+    #[error("output error")]
+    OutputFailure(#[from] io::Error),
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::progress::NoProgress;
+    use crate::rolling_checksum::rolling_adler32::RollingAdler32;
+    use crate::signature_generation::generate_signature;
+    use crate::strong_hash::md5::Md5Sum;
+
+    use super::*;
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|x| (x * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_reconstruct() {
+        let old_content = content(1 << 14);
+        let mut new_content = old_content.clone();
+        new_content.splice(5000..5100, [1; 10]);
+        new_content.extend_from_slice(&[2; 100]);
+
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&new_content);
+        let mut source = SeekableRangeSource::new(Cursor::new(new_content.clone()));
+
+        let mut out = Vec::new();
+        let stats = reconstruct::<RollingAdler32, Md5Sum, _, _>(
+            &signature,
+            &old_content,
+            &mut source,
+            &mut out,
+            &NoProgress,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+
+        assert_eq!(out, new_content);
+        assert!(stats.fetched_bytes < new_content.len() as u64 / 4);
+        assert_eq!(stats.reused_bytes + stats.fetched_bytes, out.len() as u64);
+    }
+
+    #[test]
+    fn test_reconstruct_without_local_content() {
+        let new_content = content(1000);
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&new_content);
+        let mut source = SeekableRangeSource::new(Cursor::new(new_content.clone()));
+
+        let mut out = Vec::new();
+        let stats = reconstruct::<RollingAdler32, Md5Sum, _, _>(
+            &signature,
+            &[],
+            &mut source,
+            &mut out,
+            &NoProgress,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+
+        assert_eq!(out, new_content);
+        assert_eq!(stats.fetch_count, 1);
+    }
+
+    #[test]
+    fn test_reconstruct_rejects_corrupted_ranges() {
+        let new_content = content(1000);
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&new_content);
+        let mut corrupted = new_content.clone();
+        corrupted[500] ^= 1;
+        let mut source = SeekableRangeSource::new(Cursor::new(corrupted));
+
+        assert!(matches!(
+            reconstruct::<RollingAdler32, Md5Sum, _, _>(
+                &signature,
+                &[],
+                &mut source,
+                &mut Vec::new(),
+                &NoProgress,
+                &CancellationToken::new(),
+            ),
+            Err(ReconstructError::ChunkHashMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_reconstruct_rejects_a_zero_chunk_size() {
+        let signature: Signature<u32, [u8; 16]> =
+            Signature::from_ordered_chunks([(1, [0; 16])], 0, "test".to_string());
+        let mut source = SeekableRangeSource::new(Cursor::new(vec![0; 10]));
+
+        assert!(matches!(
+            reconstruct::<RollingAdler32, Md5Sum, _, _>(
+                &signature,
+                &[],
+                &mut source,
+                &mut Vec::new(),
+                &NoProgress,
+                &CancellationToken::new(),
+            ),
+            Err(ReconstructError::InvalidSignature(
+                SignatureChunksError::InvalidChunkSize {
+                    chunk_size: 0,
+                    chunk_count: 1
+                }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_cancelled_reconstruct() {
+        let new_content = content(1000);
+        let signature = generate_signature::<RollingAdler32, Md5Sum>(&new_content);
+        let mut source = SeekableRangeSource::new(Cursor::new(new_content.clone()));
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let mut out = Vec::new();
+        assert!(matches!(
+            reconstruct::<RollingAdler32, Md5Sum, _, _>(
+                &signature,
+                &new_content,
+                &mut source,
+                &mut out,
+                &NoProgress,
+                &cancel,
+            ),
+            Err(ReconstructError::Cancelled(_))
+        ));
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,126 @@
+/* Exercises the C API, built and run by tests/c_api.rs */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+#include "rolling_in_the_diff.h"
+
+#define CONTENT_LEN (1 << 16)
+
+static int failures = 0;
+
+static void expect(RitdStatus status, RitdStatus expected, const char *what) {
+  if (status != expected) {
+    const char *message = ritd_last_error_message();
+    fprintf(stderr, "%s: expected status %d, got %d (%s)\n", what, expected, status,
+            message ? message : "no message");
+    failures++;
+  }
+}
+
+static void expect_content(const uint8_t *actual, size_t actual_len, const uint8_t *expected,
+                           size_t expected_len, const char *what) {
+  if (actual_len != expected_len || memcmp(actual, expected, expected_len) != 0) {
+    fprintf(stderr, "%s: unexpected content\n", what);
+    failures++;
+  }
+}
+
+/* A temporary file holding `len` bytes of `content`, positioned at its start */
+static int temp_fd(const uint8_t *content, size_t len) {
+  FILE *file = tmpfile();
+  if (!file || fwrite(content, 1, len, file) != len || fflush(file) != 0) {
+    perror("tmpfile");
+    exit(1);
+  }
+  int fd = dup(fileno(file));
+  fclose(file);
+  lseek(fd, 0, SEEK_SET);
+  return fd;
+}
+
+static void test_buffers(const uint8_t *old_content, const uint8_t *new_content) {
+  RitdSignature *signature = NULL;
+  expect(ritd_signature_new(old_content, CONTENT_LEN, &signature), RITD_STATUS_OK,
+         "signature");
+
+  RitdBuffer encoded = {0};
+  expect(ritd_signature_encode(signature, &encoded), RITD_STATUS_OK, "encode signature");
+  ritd_signature_free(signature);
+  signature = NULL;
+  expect(ritd_signature_decode(encoded.data, encoded.len, &signature), RITD_STATUS_OK,
+         "decode signature");
+  ritd_buffer_free(&encoded);
+
+  RitdBuffer delta = {0};
+  expect(ritd_delta_new(signature, new_content, CONTENT_LEN, &delta), RITD_STATUS_OK, "delta");
+  ritd_signature_free(signature);
+
+  RitdBuffer patched = {0};
+  expect(ritd_patch(old_content, CONTENT_LEN, delta.data, delta.len, &patched), RITD_STATUS_OK,
+         "patch");
+  expect_content(patched.data, patched.len, new_content, CONTENT_LEN, "patch");
+  ritd_buffer_free(&patched);
+
+  /* the delta doesn't apply on top of other content */
+  expect(ritd_patch(new_content, CONTENT_LEN, delta.data, delta.len, &patched),
+         RITD_STATUS_CHUNK_HASH_MISMATCH, "patch on top of the wrong content");
+  if (ritd_last_error_message() == NULL) {
+    fprintf(stderr, "no message for the hash mismatch\n");
+    failures++;
+  }
+  expect(ritd_patch(old_content, CONTENT_LEN, delta.data, delta.len / 2, &patched),
+         RITD_STATUS_MALFORMED, "patch with a truncated delta");
+  ritd_buffer_free(&delta);
+}
+
+static void test_file_descriptors(const uint8_t *old_content, const uint8_t *new_content) {
+  int old_fd = temp_fd(old_content, CONTENT_LEN);
+  RitdSignature *signature = NULL;
+  expect(ritd_signature_new_from_fd(old_fd, &signature), RITD_STATUS_OK, "signature from fd");
+
+  int new_fd = temp_fd(new_content, CONTENT_LEN);
+  int delta_fd = temp_fd(NULL, 0);
+  expect(ritd_delta_new_from_fd(signature, new_fd, delta_fd), RITD_STATUS_OK, "delta from fd");
+  ritd_signature_free(signature);
+
+  int out_fd = temp_fd(NULL, 0);
+  lseek(old_fd, 0, SEEK_SET);
+  lseek(delta_fd, 0, SEEK_SET);
+  expect(ritd_patch_from_fd(old_fd, delta_fd, out_fd), RITD_STATUS_OK, "patch from fd");
+
+  uint8_t *patched = malloc(CONTENT_LEN + 1);
+  lseek(out_fd, 0, SEEK_SET);
+  ssize_t patched_len = read(out_fd, patched, CONTENT_LEN + 1);
+  expect_content(patched, patched_len < 0 ? 0 : (size_t)patched_len, new_content, CONTENT_LEN,
+                 "patch from fd");
+  free(patched);
+
+  close(old_fd);
+  close(new_fd);
+  close(delta_fd);
+  close(out_fd);
+}
+
+int main(void) {
+  uint8_t *old_content = malloc(CONTENT_LEN);
+  uint8_t *new_content = malloc(CONTENT_LEN);
+  for (size_t i = 0; i < CONTENT_LEN; i++) {
+    old_content[i] = (uint8_t)(i * 31 % 251);
+  }
+  memcpy(new_content, old_content, CONTENT_LEN);
+  memset(new_content + 1000, 42, 100);
+
+  test_buffers(old_content, new_content);
+  test_file_descriptors(old_content, new_content);
+
+  RitdSignature *signature = NULL;
+  expect(ritd_signature_new(NULL, 10, &signature), RITD_STATUS_INVALID_ARGUMENT,
+         "signature of null content");
+
+  free(old_content);
+  free(new_content);
+  return failures == 0 ? 0 : 1;
+}
@@ -0,0 +1,82 @@
+//!
+//! Builds the C test program in tests/c against the generated header and the static library,
+//! then runs it
+//!
+//! With the python feature the static library cargo built for the tests needs libpython too, so
+//! the test builds its own one without it.
+//!
+#![cfg(unix)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// What a Rust static library needs from the system on this target, as printed by rustc for an
+/// empty one
+fn native_libs(scratch: &Path) -> Vec<String> {
+    let source = scratch.join("empty.rs");
+    fs::write(&source, "").unwrap();
+    let printed = Command::new(env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string()))
+        .args(["--crate-type", "staticlib", "--print", "native-static-libs"])
+        .arg("-o")
+        .arg(scratch.join("libempty.a"))
+        .arg(&source)
+        .output()
+        .unwrap();
+    assert!(printed.status.success());
+    let stderr = String::from_utf8(printed.stderr).unwrap();
+    let libs = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("note: native-static-libs:"))
+        .expect("rustc prints the native libraries");
+    libs.split_whitespace().map(str::to_string).collect()
+}
+
+/// The static library to link, built without the python feature if the tests have it
+fn static_library(manifest_dir: &Path, scratch: &Path) -> PathBuf {
+    if !cfg!(feature = "python") {
+        // the library is built for the tests next to them, in target/<profile>/deps
+        let deps_dir = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+        return deps_dir.join("librolling_in_the_diff.a");
+    }
+    let target_dir = scratch.join("without-python");
+    let built = Command::new(env!("CARGO"))
+        .args(["build", "--lib", "--manifest-path"])
+        .arg(manifest_dir.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .status()
+        .unwrap();
+    assert!(built.success());
+    target_dir.join("debug/librolling_in_the_diff.a")
+}
+
+#[test]
+fn test_c_api() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let scratch = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let program = scratch.join("c_api_test");
+
+    let compiled = Command::new(env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .args([
+            "-std=c99",
+            "-Wall",
+            "-Wextra",
+            "-Werror",
+            "-D_POSIX_C_SOURCE=200809L",
+        ])
+        .arg("-I")
+        .arg(manifest_dir.join("include"))
+        .arg(manifest_dir.join("tests/c/c_api_test.c"))
+        .arg(static_library(manifest_dir, &scratch))
+        .args(native_libs(&scratch))
+        .arg("-o")
+        .arg(&program)
+        .status()
+        .unwrap();
+    assert!(compiled.success());
+
+    let ran = Command::new(&program).status().unwrap();
+    assert!(ran.success());
+}